@@ -0,0 +1,506 @@
+//! Large binary data split into content-defined chunks.
+//!
+//! # Usage
+//! A `Blob` is a manifest which lists the hashes and sizes of its chunks.
+//! Each chunk is stored as its own object.  Data is written through a
+//! `BlobWriter` and read through a `BlobReader`, so only one chunk is held
+//! in memory at a time.
+//!
+//! The chunk boundaries are found with a rolling gear hash over the content
+//! instead of fixed offsets.  If bytes are inserted or removed, only the
+//! chunks around the edit change and all other chunks are deduplicated.
+//!
+//! Loading a `Blob` only loads the manifest.  The blob keeps the
+//! `raw_source` of the store it was loaded from, so `put` into another store
+//! can copy the chunks which are missing there, and `childs` can load the
+//! chunks for tools which walk the graph.  This holds all data in memory,
+//! so use the reader for the data itself.
+
+use hash::*;
+use io::*;
+use hashio::*;
+use std::io::{Read, Write};
+use std::result;
+use std::io;
+use std::collections::BTreeMap;
+use std::cmp;
+use std::fmt;
+use std::rc::Rc;
+
+/// Chunks are never smaller than this, except for the last one.
+pub const MIN_CHUNK_SIZE: usize = 2 * 1024;
+/// Chunks are cut when this size is reached.
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024;
+/// Mask of the rolling hash which gives an average chunk size of 8 KiB.
+///
+/// The gear hash shifts to the left, so the low bits only depend on the last
+/// few bytes.  The mask uses the high bits which depend on the last 64 bytes.
+const CHUNK_MASK: u64 = ((1 << 13) - 1) << (64 - 13);
+
+/// Generates the pseudo random table of the gear hash.
+fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x9e3779b97f4a7c15;
+    for entry in table.iter_mut() {
+        // splitmix64
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        *entry = z ^ (z >> 31);
+    }
+    table
+}
+
+/// Reads a chunk and checks its size against the manifest.
+fn read_chunk<H>(hash_io: &H, len: usize, hash: &Hash) -> Result<Rc<BlobChunk>>
+        where H: HashIO {
+    let chunk: Rc<BlobChunk> = try!(hash_io.get(hash));
+    try!(check_chunk_len(&chunk, len, hash));
+    Ok(chunk)
+}
+
+/// Loads a chunk from the raw source of a blob.
+fn load_chunk(source: &RawSource, len: usize, hash: &Hash) -> Result<Rc<BlobChunk>> {
+    let object = try!(source.load_raw(hash));
+    let mut read: &[u8] = &object.payload;
+    let chunk_len = try!(read_u32(&mut read)) as usize;
+    let chunk = Rc::new(BlobChunk { data: try!(read_bytes_max(&mut read, chunk_len, len)) });
+    try!(check_chunk_len(&chunk, len, hash));
+    Ok(chunk)
+}
+
+fn check_chunk_len(chunk: &BlobChunk, len: usize, hash: &Hash) -> Result<()> {
+    if chunk.data.len() == len {
+        Ok(())
+    } else {
+        Err(HashIOError::Undefined(format!("Chunk {} has {} bytes instead of {}",
+                                           hash.as_string(), chunk.data.len(), len)))
+    }
+}
+
+fn to_io_error(err: HashIOError) -> io::Error {
+    match err {
+        HashIOError::IOError(err) => err,
+        err => io::Error::new(io::ErrorKind::Other, format!("{}", err))
+    }
+}
+
+
+/// Part of the data of a blob.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlobChunk {
+    pub data: Vec<u8>
+}
+
+impl Writable for BlobChunk {
+    fn write_to<W: Write>(&self, write: &mut W) -> result::Result<usize, io::Error> {
+        try!(write_u32(self.data.len() as u32, write));
+        try!(write.write_all(&self.data));
+        Ok(4 + self.data.len())
+    }
+}
+
+impl Hashable for BlobChunk {
+    fn as_hash(&self) -> Hash {
+        self.writable_to_hash()
+    }
+}
+
+impl Typeable for BlobChunk {
+    fn type_hash() -> Hash {
+        Hash::hash_string(BlobChunk::type_name())
+    }
+
+    fn type_name() -> String {
+        "BlobChunk".to_string()
+    }
+}
+
+impl HashIOType for BlobChunk {
+    fn type_hash_obj(&self) -> Hash {
+        BlobChunk::type_hash()
+    }
+
+    fn type_name_obj(&self) -> String {
+        BlobChunk::type_name()
+    }
+}
+
+impl HashIOParse for BlobChunk {
+    fn parse<H, R>(hash_io: &H, read: &mut R, _: &Option<Hash>)
+                -> Result<Rc<Self>> where H: HashIO, R: Read {
+        let len = try!(read_u32(read)) as usize;
+        try!(hash_io.limits().check_string_len(len));
+        let data = try!(read_bytes_max(read, len, len));
+        Ok(Rc::new(BlobChunk { data: data }))
+    }
+
+    fn store<H, W>(&self, _: &H, write: &mut W) -> Result<()> where H: HashIO, W: Write {
+        try!(self.write_to(write));
+        Ok(())
+    }
+
+    fn type_hash_valid(hash: &Hash) -> bool {
+        *hash == BlobChunk::type_hash()
+    }
+
+    fn payload_references(_: &[u8], _: &Option<Hash>) -> Result<Option<Vec<Hash>>> {
+        Ok(Some(Vec::new()))
+    }
+}
+
+
+/// Manifest of binary data which references its chunks.
+#[derive(Clone)]
+pub struct Blob {
+    chunks: Vec<(usize, Hash)>,
+    /// Store which contains the chunks.
+    source: Option<Rc<RawSource>>
+}
+
+impl fmt::Debug for Blob {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Blob").field("chunks", &self.chunks).finish()
+    }
+}
+
+impl PartialEq for Blob {
+    fn eq(&self, other: &Blob) -> bool {
+        self.chunks == other.chunks
+    }
+}
+
+impl Blob {
+    /// Stores the data and returns the manifest.
+    pub fn from_bytes<H>(hash_io: &H, data: &[u8]) -> Result<Rc<Blob>> where H: HashIO {
+        let mut writer = BlobWriter::new(hash_io);
+        try!(writer.write_all(data));
+        writer.finish()
+    }
+
+    /// Size of the data in bytes.
+    pub fn len(&self) -> usize {
+        self.chunks.iter().map(|&(len, _)| len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Hashes of all chunks in order.
+    pub fn chunk_hashes(&self) -> Vec<Hash> {
+        self.chunks.iter().map(|&(_, hash)| hash).collect()
+    }
+
+    /// Returns a reader which loads the chunks on demand.
+    pub fn reader<'a, H>(&self, hash_io: &'a H) -> BlobReader<'a, H> where H: HashIO {
+        BlobReader {
+            hash_io: hash_io,
+            chunks: self.chunks.clone(),
+            next_chunk: 0,
+            current: None,
+            pos: 0
+        }
+    }
+
+    /// Loads all data into memory.
+    pub fn read_all<H>(&self, hash_io: &H) -> Result<Vec<u8>> where H: HashIO {
+        let mut res: Vec<u8> = Vec::with_capacity(self.len());
+        try!(self.reader(hash_io).read_to_end(&mut res));
+        Ok(res)
+    }
+}
+
+impl Writable for Blob {
+    fn write_to<W: Write>(&self, write: &mut W) -> result::Result<usize, io::Error> {
+        let mut size = try!(write_u32(self.chunks.len() as u32, write));
+        for &(len, ref hash) in self.chunks.iter() {
+            size += try!(write_u32(len as u32, write));
+            size += try!(write_hash(hash, write));
+        }
+        Ok(size)
+    }
+}
+
+impl Hashable for Blob {
+    fn as_hash(&self) -> Hash {
+        self.writable_to_hash()
+    }
+}
+
+impl Typeable for Blob {
+    fn type_hash() -> Hash {
+        Hash::hash_string(Blob::type_name())
+    }
+
+    fn type_name() -> String {
+        "Blob".to_string()
+    }
+}
+
+impl HashIOType for Blob {
+    /// Loads the chunks from the store the blob was loaded from.  Chunks
+    /// which can't be loaded are only listed by `child_hashes`.
+    fn childs(&self) -> BTreeMap<String, Rc<HashIOType>> {
+        let mut res: BTreeMap<String, Rc<HashIOType>> = BTreeMap::new();
+        let source = match self.source {
+            Some(ref source) => source,
+            None => return res
+        };
+        for (i, &(len, ref hash)) in self.chunks.iter().enumerate() {
+            if let Ok(chunk) = load_chunk(&**source, len, hash) {
+                res.insert(format!("{}", i), chunk as Rc<HashIOType>);
+            }
+        }
+        res
+    }
+
+    fn child_hashes(&self) -> BTreeMap<String, Hash> {
+        self.chunks.iter().enumerate()
+            .map(|(i, &(_, hash))| (format!("{}", i), hash))
+            .collect()
+    }
+
+    fn type_hash_obj(&self) -> Hash {
+        Blob::type_hash()
+    }
+
+    fn type_name_obj(&self) -> String {
+        Blob::type_name()
+    }
+}
+
+impl HashIOParse for Blob {
+    fn parse<H, R>(hash_io: &H, read: &mut R, _: &Option<Hash>)
+                -> Result<Rc<Self>> where H: HashIO, R: Read {
+        let count = try!(read_u32(read));
+        try!(hash_io.limits().check_collection_len(count as usize));
+        let mut chunks: Vec<(usize, Hash)> = Vec::new();
+        for _ in 0..count {
+            let len = try!(read_u32(read)) as usize;
+            chunks.push((len, try!(read_hash(read))));
+        }
+        Ok(Rc::new(Blob {
+            chunks: chunks,
+            source: hash_io.raw_source()
+        }))
+    }
+
+    fn store<H, W>(&self, _: &H, write: &mut W) -> Result<()> where H: HashIO, W: Write {
+        try!(self.write_to(write));
+        Ok(())
+    }
+
+    /// Copies the missing chunks from the store the blob was loaded from.
+    fn store_childs<H>(&self, hash_io: &H) -> Result<()> where H: HashIO {
+        let missing = try!(hash_io.missing(&self.chunk_hashes()));
+        for hash in missing {
+            let object = match self.source {
+                Some(ref source) => try!(source.load_raw(&hash)),
+                None => return Err(HashIOError::NotFound(hash))
+            };
+            try!(hash_io.put_raw(&object));
+        }
+        Ok(())
+    }
+
+    fn type_hash_valid(hash: &Hash) -> bool {
+        *hash == Blob::type_hash()
+    }
+
+    fn payload_references(payload: &[u8], _: &Option<Hash>) -> Result<Option<Vec<Hash>>> {
+        let mut read: &[u8] = payload;
+        let count = try!(read_u32(&mut read));
+        let mut res: Vec<Hash> = Vec::new();
+        for _ in 0..count {
+            try!(read_u32(&mut read));
+            res.push(try!(read_hash(&mut read)));
+        }
+        Ok(Some(res))
+    }
+}
+
+
+/// Splits written data into chunks and stores them.
+///
+/// Call `finish` to store the last chunk and get the `Blob`.
+pub struct BlobWriter<'a, H> where H: HashIO + 'a {
+    hash_io: &'a H,
+    gear: [u64; 256],
+    rolling_hash: u64,
+    buffer: Vec<u8>,
+    chunks: Vec<(usize, Hash)>
+}
+
+impl<'a, H> BlobWriter<'a, H> where H: HashIO {
+    pub fn new(hash_io: &'a H) -> BlobWriter<'a, H> {
+        BlobWriter {
+            hash_io: hash_io,
+            gear: gear_table(),
+            rolling_hash: 0,
+            buffer: Vec::with_capacity(MAX_CHUNK_SIZE),
+            chunks: Vec::new()
+        }
+    }
+
+    fn store_chunk(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(())
+        }
+        let chunk = BlobChunk {
+            data: self.buffer.clone()
+        };
+        self.chunks.push((chunk.data.len(), chunk.as_hash()));
+        try!(self.hash_io.put(Rc::new(chunk)));
+        self.buffer.clear();
+        self.rolling_hash = 0;
+        Ok(())
+    }
+
+    /// Stores the remaining data and the manifest.
+    pub fn finish(mut self) -> Result<Rc<Blob>> {
+        try!(self.store_chunk());
+        let blob = Rc::new(Blob {
+            chunks: self.chunks,
+            source: self.hash_io.raw_source()
+        });
+        try!(self.hash_io.put(blob.clone()));
+        Ok(blob)
+    }
+}
+
+impl<'a, H> Write for BlobWriter<'a, H> where H: HashIO {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.buffer.push(byte);
+            self.rolling_hash = (self.rolling_hash << 1).wrapping_add(self.gear[byte as usize]);
+            let len = self.buffer.len();
+            if (len >= MIN_CHUNK_SIZE && self.rolling_hash & CHUNK_MASK == 0)
+                    || len >= MAX_CHUNK_SIZE {
+                try!(self.store_chunk().map_err(to_io_error));
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+/// Reads the data of a blob and loads one chunk at a time.
+pub struct BlobReader<'a, H> where H: HashIO + 'a {
+    hash_io: &'a H,
+    chunks: Vec<(usize, Hash)>,
+    next_chunk: usize,
+    current: Option<Rc<BlobChunk>>,
+    pos: usize
+}
+
+impl<'a, H> Read for BlobReader<'a, H> where H: HashIO {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(ref chunk) = self.current {
+                if self.pos < chunk.data.len() {
+                    let len = cmp::min(buf.len(), chunk.data.len() - self.pos);
+                    buf[..len].copy_from_slice(&chunk.data[self.pos..self.pos + len]);
+                    self.pos += len;
+                    return Ok(len)
+                }
+            }
+            if self.next_chunk >= self.chunks.len() {
+                return Ok(0)
+            }
+            let (len, hash) = self.chunks[self.next_chunk];
+            let chunk = try!(read_chunk(self.hash_io, len, &hash).map_err(to_io_error));
+            self.current = Some(chunk);
+            self.next_chunk += 1;
+            self.pos = 0;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hashiofile::HashIOFile;
+    use std::collections::BTreeSet;
+    use std::fs::remove_dir_all;
+    use std::io::Read;
+
+    fn test_data(len: usize) -> Vec<u8> {
+        let mut state: u32 = 12345;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn test_blob() {
+        remove_dir_all("./unittest/blobtest/").ok();
+        let hash_io = HashIOFile::new("unittest/blobtest".to_string());
+        let data = test_data(300 * 1024);
+        let blob = Blob::from_bytes(&hash_io, &data).unwrap();
+        assert_eq!(data.len(), blob.len());
+        assert!(blob.chunk_hashes().len() > 4);
+
+        let loaded: Rc<Blob> = hash_io.get(&blob.as_hash()).unwrap();
+        assert_eq!(data, loaded.read_all(&hash_io).unwrap());
+        let mut reader = loaded.reader(&hash_io);
+        let mut start = [0u8; 10];
+        reader.read_exact(&mut start).unwrap();
+        assert_eq!(&data[0..10], &start);
+
+        // Inserting data in the middle only changes the chunks around it
+        let mut edited = data.clone();
+        for (i, byte) in b"inserted".iter().enumerate() {
+            edited.insert(150 * 1024 + i, *byte);
+        }
+        let edited_blob = Blob::from_bytes(&hash_io, &edited).unwrap();
+        assert_eq!(edited, edited_blob.read_all(&hash_io).unwrap());
+        let old_chunks: BTreeSet<Hash> = blob.chunk_hashes().into_iter().collect();
+        let new_chunks = edited_blob.chunk_hashes();
+        let changed = new_chunks.iter().filter(|hash| !old_chunks.contains(hash)).count();
+        assert!(changed <= 2);
+        assert_eq!(new_chunks.len(), edited_blob.child_hashes().len());
+        assert_eq!(new_chunks.len(), edited_blob.childs().len());
+        assert_eq!(new_chunks[0], edited_blob.childs()["0"].as_hash());
+
+        let empty = Blob::from_bytes(&hash_io, &[]).unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(Vec::<u8>::new(), empty.read_all(&hash_io).unwrap());
+    }
+
+    #[test]
+    fn test_copy_blob() {
+        remove_dir_all("./unittest/blobcopytest/").ok();
+        let source = HashIOFile::new("unittest/blobcopytest/source".to_string());
+        let target = HashIOFile::new("unittest/blobcopytest/target".to_string());
+        let data = test_data(100 * 1024);
+        let blob = Blob::from_bytes(&source, &data).unwrap();
+
+        let loaded: Rc<Blob> = source.get(&blob.as_hash()).unwrap();
+        target.put(loaded).unwrap();
+        assert_eq!(source.hashes().unwrap(), target.hashes().unwrap());
+        let copied: Rc<Blob> = target.get(&blob.as_hash()).unwrap();
+        assert_eq!(data, copied.read_all(&target).unwrap());
+    }
+
+    #[test]
+    fn test_chunk_len() {
+        remove_dir_all("./unittest/blobchunklentest/").ok();
+        let hash_io = HashIOFile::new("unittest/blobchunklentest".to_string());
+        let data = test_data(1000);
+        let blob = Blob::from_bytes(&hash_io, &data).unwrap();
+        let manifest = Blob {
+            chunks: vec![(999, blob.chunk_hashes()[0])],
+            source: hash_io.raw_source()
+        };
+        assert!(manifest.read_all(&hash_io).is_err());
+        assert!(manifest.childs().is_empty());
+    }
+}
@@ -51,6 +51,11 @@ impl<T, U> HashIOType for BTreeMap<Rc<T>, Rc<U>>
         res
     }
 
+    /// Collections store nothing beside their entries.
+    fn attributes_hash(&self) -> Option<Hash> {
+        Some(Hash::hash_bytes(&[]))
+    }
+
     fn type_hash_obj(&self) -> Hash {
         BTreeMap::<Rc<T>, Rc<U>>::type_hash()
     }
@@ -67,12 +72,15 @@ impl<T, U> HashIOParse for BTreeMap<Rc<T>, Rc<U>>
         // read and ignore version
         try!(read_u32(read));
         let len = try!(read_u32(read));
+        try!(hash_io.limits().check_collection_len(len as usize));
         let mut res: BTreeMap<Rc<T>, Rc<U>> = BTreeMap::new();
-        for _ in 0..len {
+        for i in 0..len {
             let key_hash = try!(read_hash(read));
-            let key: Rc<T> = try!(hash_io.get(&key_hash));
+            let key: Rc<T> = try!(hash_io.get(&key_hash)
+                                  .map_err(|err| err.in_item(format!("key {}", i))));
             let val_hash = try!(read_hash(read));
-            let val: Rc<U> = try!(hash_io.get(&val_hash));
+            let val: Rc<U> = try!(hash_io.get(&val_hash)
+                                  .map_err(|err| err.in_item(i)));
             res.insert(key, val);
         }
         Ok(Rc::new(res))
@@ -146,4 +154,26 @@ mod test {
         assert_eq!(&Rc::new("2".to_string()), my_btree.get(&Rc::new("b".to_string())).unwrap());
         assert_eq!(&Rc::new("3".to_string()), my_btree.get(&Rc::new("c".to_string())).unwrap());
     }
+
+    #[test]
+    fn test_error_path() {
+        remove_dir_all("./unittest/btreemaperrortest/").ok();
+        let hash_io = HashIOFile::new("unittest/btreemaperrortest".to_string());
+        let mut btreemap: BTreeMap<Rc<String>, Rc<String>> = BTreeMap::new();
+        btreemap.insert(Rc::new("a".to_string()), Rc::new("1".to_string()));
+        btreemap.insert(Rc::new("b".to_string()), Rc::new("2".to_string()));
+        let my_obj = TestType {
+            a: Rc::new(btreemap)
+        };
+        let my_hash = my_obj.as_hash();
+        hash_io.put(Rc::new(my_obj)).unwrap();
+        ::std::fs::remove_file(hash_io.filename_for_hash(&"2".to_string().as_hash())).unwrap();
+
+        // Values are named by their entry index instead of the key
+        match hash_io.get::<TestType>(&my_hash) {
+            Err(HashIOError::Object(object_error)) =>
+                assert_eq!("TestType.a[1]", object_error.path()),
+            _ => panic!("Expected an error with context")
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,135 @@
+use hash::*;
+use io::*;
+use hashio::*;
+use std::io::{Read, Write};
+use std::result;
+use std::io;
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
+
+impl<T> Writable for BTreeSet<Rc<T>> where T: HashIOParse {
+    fn write_to<W: Write>(&self, write: &mut W) -> result::Result<usize, io::Error> {
+        try!(write_u32(0, write));
+        try!(write_u32(self.len() as u32, write));
+        for item in self {
+            try!(write_hash(&item.as_hash(), write));
+        }
+        return Ok(8 + self.len() * 32)
+    }
+}
+
+impl<T> Hashable for BTreeSet<Rc<T>> where T: HashIOParse {
+    fn as_hash(&self) -> Hash {
+        self.writable_to_hash()
+    }
+}
+
+impl<T> Typeable for BTreeSet<Rc<T>> where T: HashIOParse {
+    fn type_hash() -> Hash {
+        Hash::hash_string(BTreeSet::<Rc<T>>::type_name())
+    }
+
+    fn type_name() -> String {
+        "BTreeSet<".to_string() + &T::type_name() + ">"
+    }
+}
+
+impl<T> HashIOType for BTreeSet<Rc<T>> where T: HashIOParse + 'static {
+    fn childs(&self) -> BTreeMap<String, Rc<HashIOType>> {
+        let mut res: BTreeMap<String, Rc<HashIOType>> = BTreeMap::new();
+        for (i, item) in self.iter().enumerate() {
+            let i_str = format!("{}", i);
+            let boxed_item_object: Rc<HashIOType> = item.clone() as Rc<HashIOType>;
+            res.insert(i_str, boxed_item_object);
+        }
+        res
+    }
+
+    /// Collections store nothing beside their entries.
+    fn attributes_hash(&self) -> Option<Hash> {
+        Some(Hash::hash_bytes(&[]))
+    }
+
+    fn type_hash_obj(&self) -> Hash {
+        BTreeSet::<Rc<T>>::type_hash()
+    }
+
+    fn type_name_obj(&self) -> String {
+        BTreeSet::<Rc<T>>::type_name()
+    }
+}
+
+impl<T> HashIOParse for BTreeSet<Rc<T>> where T: HashIOParse + Ord + 'static {
+    fn parse<H, R>(hash_io: &H, read: &mut R, _: &Option<Hash>)
+                -> Result<Rc<Self>> where H: HashIO, R: Read {
+        // read and ignore version
+        try!(read_u32(read));
+        let len = try!(read_u32(read));
+        try!(hash_io.limits().check_collection_len(len as usize));
+        let mut res: BTreeSet<Rc<T>> = BTreeSet::new();
+        for i in 0..len {
+            let item_hash = try!(read_hash(read));
+            let item: Rc<T> = try!(hash_io.get(&item_hash).map_err(|err| err.in_item(i)));
+            res.insert(item);
+        }
+        Ok(Rc::new(res))
+    }
+
+    fn store<H, W>(&self, _: &H, write: &mut W) -> Result<()> where H: HashIO, W: Write {
+        try!(self.write_to(write));
+        Ok(())
+    }
+
+    fn store_childs<H>(&self, hash_io: &H) -> Result<()>
+            where H: HashIO {
+        for item in self {
+            try!(hash_io.put(item.clone()));
+        }
+        Ok(())
+    }
+    fn unsafe_loader() -> bool {
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::io::*;
+    use super::super::hashio::*;
+    use std::io::{Read, Write};
+    use std::io;
+    use hash::*;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::result;
+    use std::rc::Rc;
+    use hashiofile::HashIOFile;
+    use std::fs::remove_dir_all;
+
+    hashio_type!{ TestType {
+    } {
+        a: BTreeSet< Rc<String> >
+    }}
+
+    #[test]
+    fn test() {
+        remove_dir_all("./unittest/btreesettest/").ok();
+        let hash_io = HashIOFile::new("unittest/btreesettest".to_string());
+        let mut btreeset: BTreeSet<Rc<String>> = BTreeSet::new();
+        btreeset.insert(Rc::new("b".to_string()));
+        btreeset.insert(Rc::new("a".to_string()));
+        btreeset.insert(Rc::new("c".to_string()));
+
+        let my_obj = TestType {
+            a: Rc::new(btreeset)
+        };
+        let my_hash = my_obj.as_hash();
+        hash_io.put(Rc::new(my_obj)).unwrap();
+
+        let my_obj: Rc<TestType> = hash_io.get(&my_hash).unwrap();
+        assert_eq!("BTreeSet<String>", my_obj.a.type_name_obj());
+        assert_eq!(3, my_obj.a.len());
+        assert!(my_obj.a.contains(&Rc::new("a".to_string())));
+        assert_eq!(Rc::new("a".to_string()).as_hash(), my_obj.a.childs()["0"].as_hash());
+    }
+}
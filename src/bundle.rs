@@ -0,0 +1,375 @@
+//! Single file bundles for offline transfer.
+//!
+//! # Usage
+//! `create_bundle` packs all objects which are reachable from the roots into
+//! one file.  Objects which are reachable from the bases are left out, the
+//! receiver must have them already.  `import_bundle` writes the objects into
+//! another store.  Both walk the raw objects with a `ReferenceDecoder`, so
+//! the types don't have to be known.
+//!
+//! The file starts with the magic `HASHIOBUNDLE` and the version, followed
+//! by the manifest: the roots, the bases and the number of objects.  Then
+//! every object follows with its hash, its length and its file content
+//! including the header.  Children are written before their parents.  The
+//! last 33 bytes contain the checksum of everything before, written with
+//! `write_hash`.
+//!
+//! `BundleReader` reads the objects one by one and verifies the checksum
+//! after the last one.  `import_bundle` verifies every object and only
+//! writes it once its children exist in the store, so a damaged bundle may
+//! be imported partially, but never leaves an incomplete graph behind.
+
+use hash::*;
+use hashio::*;
+use io::*;
+use references::ReferenceDecoder;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::io::{Read, Write};
+
+const MAGIC: &[u8] = b"HASHIOBUNDLE";
+const VERSION: u32 = 1;
+
+/// Roots and bases of a bundle.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BundleManifest {
+    pub roots: Vec<Hash>,
+    pub bases: Vec<Hash>,
+    pub object_count: usize
+}
+
+/// Content of a bundle file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bundle {
+    pub manifest: BundleManifest,
+    objects: BTreeMap<Hash, Vec<u8>>
+}
+
+impl Bundle {
+    /// Reads the bundle and verifies the checksum and all objects.
+    pub fn read_from<R: Read>(read: &mut R) -> Result<Bundle> {
+        let mut reader = try!(BundleReader::new(read));
+        let mut objects: BTreeMap<Hash, Vec<u8>> = BTreeMap::new();
+        while let Some((hash, object)) = try!(reader.next_file()) {
+            objects.insert(hash, object);
+        }
+        for (hash, object) in objects.iter() {
+            try!(RawObject::from_file(hash, object));
+        }
+        Ok(Bundle {
+            manifest: reader.manifest,
+            objects: objects
+        })
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.objects.contains_key(hash)
+    }
+}
+
+/// Reads a bundle object by object.
+pub struct BundleReader<'a, R: Read + 'a> {
+    pub manifest: BundleManifest,
+    read: ChecksumRead<'a, R>,
+    remaining: usize,
+    verified: bool
+}
+
+impl<'a, R: Read + 'a> BundleReader<'a, R> {
+    /// Reads the manifest.
+    pub fn new(read: &'a mut R) -> Result<BundleReader<'a, R>> {
+        let mut read = ChecksumRead {
+            read: read,
+            hasher: Hasher::new()
+        };
+        match read_bytes(&mut read, MAGIC.len()) {
+            Ok(ref magic) if &magic[..] == MAGIC => (),
+            _ => return Err(invalid("Not a bundle"))
+        }
+        let version = try!(read_u32(&mut read));
+        if version != VERSION {
+            return Err(HashIOError::VersionError(version))
+        }
+        let roots = try!(read_hashes(&mut read));
+        let bases = try!(read_hashes(&mut read));
+        let object_count = try!(read_u32(&mut read)) as usize;
+        Ok(BundleReader {
+            manifest: BundleManifest {
+                roots: roots,
+                bases: bases,
+                object_count: object_count
+            },
+            read: read,
+            remaining: object_count,
+            verified: false
+        })
+    }
+
+    /// Returns the hash and the file content of the next object.
+    ///
+    /// The content isn't verified.  After the last object, the checksum is
+    /// verified and None is returned.
+    pub fn next_file(&mut self) -> Result<Option<(Hash, Vec<u8>)>> {
+        if self.remaining == 0 {
+            if !self.verified {
+                try!(self.verify_checksum());
+                self.verified = true;
+            }
+            return Ok(None)
+        }
+        let hash = try!(read_hash(&mut self.read));
+        let len = try!(read_u32(&mut self.read)) as usize;
+        let object = try!(read_bytes(&mut self.read, len));
+        self.remaining -= 1;
+        Ok(Some((hash, object)))
+    }
+
+    /// Returns the next object after verifying it against its hash.
+    pub fn next_object(&mut self) -> Result<Option<RawObject>> {
+        match try!(self.next_file()) {
+            Some((hash, object)) => Ok(Some(try!(RawObject::from_file(&hash, &object)))),
+            None => Ok(None)
+        }
+    }
+
+    fn verify_checksum(&mut self) -> Result<()> {
+        let checksum = self.read.hasher.result();
+        if try!(read_hash(&mut self.read.read)) != checksum {
+            return Err(invalid("Checksum mismatch"))
+        }
+        let mut rest = [0u8; 1];
+        if try!(self.read.read.read(&mut rest)) != 0 {
+            return Err(invalid("Unexpected data after the objects"))
+        }
+        Ok(())
+    }
+}
+
+/// Writes the objects below the roots which are not below the bases.
+pub fn create_bundle<H, W>(hash_io: &H, decoder: &ReferenceDecoder, roots: &[Hash],
+                           bases: &[Hash], write: &mut W)
+        -> Result<BundleManifest> where H: HashIO, W: Write {
+    let excluded = try!(reachable(hash_io, decoder, bases));
+    let hashes = try!(objects_below(hash_io, decoder, roots, excluded));
+    let mut write = ChecksumWrite {
+        write: write,
+        hasher: Hasher::new()
+    };
+    try!(write.write_all(MAGIC));
+    try!(write_u32(VERSION, &mut write));
+    try!(write_hashes(roots, &mut write));
+    try!(write_hashes(bases, &mut write));
+    try!(write_u32(hashes.len() as u32, &mut write));
+    for hash in hashes.iter() {
+        let object = try!(hash_io.get_raw(hash)).to_file();
+        try!(write_hash(hash, &mut write));
+        try!(write_u32(object.len() as u32, &mut write));
+        try!(write.write_all(&object));
+    }
+    let checksum = write.hasher.result();
+    try!(write_hash(&checksum, write.write));
+    Ok(BundleManifest {
+        roots: roots.to_vec(),
+        bases: bases.to_vec(),
+        object_count: hashes.len()
+    })
+}
+
+/// Reads a bundle and stores its objects in hash_io.
+///
+/// The objects below the bases of the bundle must already exist in hash_io.
+pub fn import_bundle<H, R>(hash_io: &H, decoder: &ReferenceDecoder, read: &mut R)
+        -> Result<BundleManifest> where H: HashIO, R: Read {
+    let mut reader = try!(BundleReader::new(read));
+    if let Some(base) = try!(hash_io.missing(&reader.manifest.bases)).first() {
+        return Err(HashIOError::NotFound(*base))
+    }
+    while let Some(object) = try!(reader.next_object()) {
+        let childs = try!(decoder.stored_references(hash_io, &object));
+        if let Some(child) = try!(hash_io.missing(&childs)).first() {
+            return Err(HashIOError::NotFound(*child))
+        }
+        try!(hash_io.put_raw(&object));
+    }
+    if let Some(root) = try!(hash_io.missing(&reader.manifest.roots)).first() {
+        return Err(HashIOError::NotFound(*root))
+    }
+    Ok(reader.manifest)
+}
+
+
+/// Forwards the written data and calculates its checksum.
+struct ChecksumWrite<'a, W: Write + 'a> {
+    write: &'a mut W,
+    hasher: Hasher
+}
+
+impl<'a, W: Write + 'a> Write for ChecksumWrite<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = try!(self.write.write(buf));
+        self.hasher.input(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write.flush()
+    }
+}
+
+/// Forwards the read data and calculates its checksum.
+struct ChecksumRead<'a, R: Read + 'a> {
+    read: &'a mut R,
+    hasher: Hasher
+}
+
+impl<'a, R: Read + 'a> Read for ChecksumRead<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = try!(self.read.read(buf));
+        self.hasher.input(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Returns the roots and all objects below them.
+fn reachable<H>(hash_io: &H, decoder: &ReferenceDecoder, roots: &[Hash])
+        -> Result<BTreeSet<Hash>> where H: HashIO {
+    let mut res: BTreeSet<Hash> = BTreeSet::new();
+    let mut pending: Vec<Hash> = roots.to_vec();
+    while let Some(hash) = pending.pop() {
+        if res.insert(hash) {
+            let object = try!(hash_io.get_raw(&hash));
+            pending.extend(try!(decoder.stored_references(hash_io, &object)));
+        }
+    }
+    Ok(res)
+}
+
+/// Lists the objects below the roots except the excluded ones, children first.
+fn objects_below<H>(hash_io: &H, decoder: &ReferenceDecoder, roots: &[Hash],
+                    excluded: BTreeSet<Hash>) -> Result<Vec<Hash>> where H: HashIO {
+    let mut res: Vec<Hash> = Vec::new();
+    let mut visited = excluded;
+    // Objects whose children are still visited, with these children.
+    let mut stack: Vec<(Hash, Vec<Hash>)> = Vec::new();
+    let mut pending: Vec<Hash> = roots.iter().rev().cloned().collect();
+    loop {
+        let next = match stack.last_mut() {
+            Some(&mut (_, ref mut childs)) => childs.pop(),
+            None => pending.pop()
+        };
+        match next {
+            Some(hash) => {
+                if !visited.insert(hash) {
+                    continue
+                }
+                let object = try!(hash_io.get_raw(&hash));
+                let mut childs = try!(decoder.stored_references(hash_io, &object));
+                childs.reverse();
+                stack.push((hash, childs));
+            },
+            None => match stack.pop() {
+                Some((hash, _)) => res.push(hash),
+                None => break
+            }
+        }
+    }
+    Ok(res)
+}
+
+fn write_hashes<W: Write>(hashes: &[Hash], write: &mut W) -> Result<()> {
+    try!(write_u32(hashes.len() as u32, write));
+    for hash in hashes {
+        try!(write_hash(hash, write));
+    }
+    Ok(())
+}
+
+fn read_hashes<R: Read>(read: &mut R) -> Result<Vec<Hash>> {
+    let len = try!(read_u32(read));
+    let mut res: Vec<Hash> = Vec::new();
+    for _ in 0..len {
+        res.push(try!(read_hash(read)));
+    }
+    Ok(res)
+}
+
+fn invalid(msg: &str) -> HashIOError {
+    HashIOError::Undefined(format!("Invalid bundle: {}", msg))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fixtures::*;
+    use blob::Blob;
+    use hamt::Hamt;
+    use hashiofile::HashIOFile;
+    use std::rc::Rc;
+    use std::fs::remove_dir_all;
+
+    #[test]
+    fn test_bundle() {
+        remove_dir_all("./unittest/bundletest/").ok();
+        let source = HashIOFile::new("unittest/bundletest/source".to_string());
+        let target = HashIOFile::new("unittest/bundletest/target".to_string());
+        let old = storage(&["a", "b"]);
+        let new = storage(&["a", "c"]);
+        source.put(Rc::new(old.clone())).unwrap();
+        source.put(Rc::new(new.clone())).unwrap();
+
+        let decoder = ReferenceDecoder::new().with::<FixtureStorage>().with::<FixtureTask>();
+        let mut full: Vec<u8> = Vec::new();
+        let manifest = create_bundle(
+            &source, &decoder, &[old.as_hash()], &[], &mut full).unwrap();
+        assert_eq!(6, manifest.object_count);
+        let mut delta: Vec<u8> = Vec::new();
+        let manifest = create_bundle(
+            &source, &decoder, &[new.as_hash()], &[old.as_hash()], &mut delta).unwrap();
+        // The objects below the old root are left out.
+        assert_eq!(4, manifest.object_count);
+
+        // The delta needs the objects of the base.
+        assert!(import_bundle(&target, &decoder, &mut &delta[..]).is_err());
+        import_bundle(&target, &decoder, &mut &full[..]).unwrap();
+        let manifest = import_bundle(&target, &decoder, &mut &delta[..]).unwrap();
+        assert_eq!(vec![new.as_hash()], manifest.roots);
+        assert_eq!(source.hashes().unwrap(), target.hashes().unwrap());
+        let loaded: Rc<FixtureStorage> = target.get(&new.as_hash()).unwrap();
+        assert_eq!(new.as_hash(), loaded.as_hash());
+
+        let len = delta.len();
+        delta[len / 2] ^= 1;
+        let bundle = Bundle::read_from(&mut &delta[..]);
+        assert_eq!("Undefined error: Invalid bundle: Checksum mismatch",
+                   format!("{}", bundle.unwrap_err()));
+    }
+
+    #[test]
+    fn test_bundle_keys_and_chunks() {
+        remove_dir_all("./unittest/bundlerawtest/").ok();
+        let source = HashIOFile::new("unittest/bundlerawtest/source".to_string());
+        let target = HashIOFile::new("unittest/bundlerawtest/target".to_string());
+        let mut map: Hamt<String, String> = Hamt::new();
+        for i in 0..20 {
+            map = map.insert(Rc::new(format!("key{}", i)), Rc::new(format!("value{}", i)));
+        }
+        source.put(Rc::new(map.clone())).unwrap();
+        let data: Vec<u8> = (0..100 * 1024).map(|i| (i * 7 % 251) as u8).collect();
+        let blob = Blob::from_bytes(&source, &data).unwrap();
+        source.put(blob.clone()).unwrap();
+
+        let decoder = ReferenceDecoder::new().with::<Hamt<String, String>>().with::<Blob>();
+        let mut bundle: Vec<u8> = Vec::new();
+        let manifest = create_bundle(&source, &decoder, &[map.as_hash(), blob.as_hash()], &[],
+                                     &mut bundle).unwrap();
+        assert_eq!(source.hashes().unwrap().len(), manifest.object_count);
+        import_bundle(&target, &decoder, &mut &bundle[..]).unwrap();
+        assert_eq!(source.hashes().unwrap(), target.hashes().unwrap());
+        let value = Hamt::<String, String>::lookup(&target, &map.as_hash(),
+                                                   &"key3".to_string()).unwrap();
+        assert_eq!(Some(Rc::new("value3".to_string())), value);
+        let copied: Rc<Blob> = target.get(&blob.as_hash()).unwrap();
+        assert_eq!(data, copied.read_all(&target).unwrap());
+    }
+}
@@ -0,0 +1,207 @@
+//! Structural diff between two object graphs.
+//!
+//! # Usage
+//! `diff` loads two roots of the same type and compares them by walking
+//! `HashIOType::childs()`.  Subtrees with identical hashes are skipped, so
+//! only the parts which differ are visited.
+//!
+//! Paths are built from the child names joined by `/`, like `tasks/3/title`.
+//! The root itself has the empty path.  A node is reported as changed if it
+//! has no children, if its type changed or if its own attributes differ,
+//! see `HashIOType::attributes_hash`.  Types which don't provide their
+//! attributes are reported whenever they changed.  The changes below a node
+//! follow the node itself.
+
+use hash::*;
+use hashio::*;
+use std::rc::Rc;
+
+/// Difference at one path.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Added { path: String, hash: Hash },
+    Removed { path: String, hash: Hash },
+    Changed { path: String, old: Hash, new: Hash }
+}
+
+impl Change {
+    pub fn path(&self) -> &str {
+        match *self {
+            Change::Added { ref path, .. } => path,
+            Change::Removed { ref path, .. } => path,
+            Change::Changed { ref path, .. } => path
+        }
+    }
+}
+
+/// Loads both roots as T and compares them.
+pub fn diff<T, H>(hash_io: &H, old: &Hash, new: &Hash) -> Result<Vec<Change>>
+        where T: HashIOParse + 'static, H: HashIO {
+    if old == new {
+        return Ok(Vec::new())
+    }
+    let old_obj: Rc<T> = try!(hash_io.get(old));
+    let new_obj: Rc<T> = try!(hash_io.get(new));
+    Ok(diff_objects(&*old_obj, &*new_obj))
+}
+
+/// Compares two loaded objects.
+pub fn diff_objects(old: &HashIOType, new: &HashIOType) -> Vec<Change> {
+    let mut res: Vec<Change> = Vec::new();
+    diff_at("", old, new, &mut res);
+    res
+}
+
+/// Appends the child name to a `/` separated path.
+pub fn join_path(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", path, name)
+    }
+}
+
+fn diff_at(path: &str, old: &HashIOType, new: &HashIOType, res: &mut Vec<Change>) {
+    let old_hash = old.as_hash();
+    let new_hash = new.as_hash();
+    if old_hash == new_hash {
+        return
+    }
+    let changed = Change::Changed {
+        path: path.to_string(),
+        old: old_hash,
+        new: new_hash
+    };
+    let old_childs = old.childs();
+    let new_childs = new.childs();
+    if old.type_hash_obj() != new.type_hash_obj()
+            || (old_childs.is_empty() && new_childs.is_empty()) {
+        res.push(changed);
+        return
+    }
+    let changes_before = res.len();
+    for (name, old_child) in old_childs.iter() {
+        let child_path = join_path(path, name);
+        match new_childs.get(name) {
+            Some(new_child) => diff_at(&child_path, &**old_child, &**new_child, res),
+            None => res.push(Change::Removed {
+                path: child_path,
+                hash: old_child.as_hash()
+            })
+        }
+    }
+    for (name, new_child) in new_childs.iter() {
+        if !old_childs.contains_key(name) {
+            res.push(Change::Added {
+                path: join_path(path, name),
+                hash: new_child.as_hash()
+            });
+        }
+    }
+    let attributes_changed = match (old.attributes_hash(), new.attributes_hash()) {
+        (Some(old_attributes), Some(new_attributes)) => old_attributes != new_attributes,
+        _ => true
+    };
+    if attributes_changed || res.len() == changes_before {
+        res.insert(changes_before, changed);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use io::*;
+    use hashiofile::HashIOFile;
+    use std::io::{Read, Write};
+    use std::io;
+    use std::collections::BTreeMap;
+    use std::result;
+    use std::rc::Rc;
+    use std::fs::remove_dir_all;
+
+    hashio_type! {
+        DiffTask {
+            factor: f32, read_f32, write_f32
+        } {
+            title: String
+        }
+    }
+    hashio_type! {
+        DiffStorage {
+        } {
+            tasks: Vec<Rc<DiffTask>>,
+            tags: BTreeMap<Rc<String>, Rc<String>>
+        }
+    }
+
+    fn task(title: &str, factor: f32) -> Rc<DiffTask> {
+        Rc::new(DiffTask {
+            factor: factor,
+            title: Rc::new(title.to_string())
+        })
+    }
+
+    fn tags(entries: &[(&str, &str)]) -> Rc<BTreeMap<Rc<String>, Rc<String>>> {
+        Rc::new(entries.iter()
+                .map(|&(key, value)| (Rc::new(key.to_string()), Rc::new(value.to_string())))
+                .collect())
+    }
+
+    #[test]
+    fn test_diff() {
+        remove_dir_all("./unittest/difftest/").ok();
+        let hash_io = HashIOFile::new("unittest/difftest".to_string());
+        let old = DiffStorage {
+            tasks: Rc::new(vec![task("a", 1.0), task("b", 1.0)]),
+            tags: tags(&[("color", "red"), ("size", "big")])
+        };
+        let new = DiffStorage {
+            tasks: Rc::new(vec![task("a", 2.0), task("c", 1.0), task("d", 1.0)]),
+            tags: tags(&[("color", "blue"), ("owner", "me")])
+        };
+        let old_hash = old.as_hash();
+        let new_hash = new.as_hash();
+        hash_io.put(Rc::new(old)).unwrap();
+        hash_io.put(Rc::new(new)).unwrap();
+
+        let changes = diff::<DiffStorage, _>(&hash_io, &old_hash, &new_hash).unwrap();
+        let paths: Vec<&str> = changes.iter().map(|change| change.path()).collect();
+        assert_eq!(vec!["tags/\"color\"", "tags/\"size\"", "tags/\"owner\"",
+                        "tasks/0", "tasks/1/title", "tasks/2"], paths);
+        assert_eq!(Change::Changed {
+            path: "tasks/1/title".to_string(),
+            old: Rc::new("b".to_string()).as_hash(),
+            new: Rc::new("c".to_string()).as_hash()
+        }, changes[4]);
+        assert_eq!(Change::Removed {
+            path: "tags/\"size\"".to_string(),
+            hash: Rc::new("big".to_string()).as_hash()
+        }, changes[1]);
+        match changes[5] {
+            Change::Added { .. } => (),
+            ref change => panic!("Unexpected change {:?}", change)
+        }
+
+        assert!(diff::<DiffStorage, _>(&hash_io, &old_hash, &old_hash).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_diff_field_and_child() {
+        let old = task("a", 1.0);
+        let new = task("b", 2.0);
+        let changes = diff_objects(&*old, &*new);
+        let paths: Vec<&str> = changes.iter().map(|change| change.path()).collect();
+        assert_eq!(vec!["", "title"], paths);
+        assert_eq!(Change::Changed {
+            path: "".to_string(),
+            old: old.as_hash(),
+            new: new.as_hash()
+        }, changes[0]);
+
+        // Only the child changed.
+        let changes = diff_objects(&*old, &*task("b", 1.0));
+        let paths: Vec<&str> = changes.iter().map(|change| change.path()).collect();
+        assert_eq!(vec!["title"], paths);
+    }
+}
@@ -0,0 +1,173 @@
+//! Graphviz rendering of object graphs.
+//!
+//! # Usage
+//! `to_dot` renders a loaded object and `stored_to_dot` loads the object
+//! from a store first.  Every object becomes one node which is labelled with
+//! its type name and the first characters of its hash, the edges are
+//! labelled with the child names.  Objects which are shared by several
+//! parents appear only once, so the graph shows which parts are deduplicated.
+//!
+//! The output can be rendered with `dot -Tsvg graph.dot > graph.svg`.
+
+use hash::*;
+use hashio::*;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+/// Nodes and edges of a graph which can be written in the DOT language.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DotGraph {
+    nodes: BTreeMap<Hash, String>,
+    edges: Vec<(Hash, Hash, String)>
+}
+
+impl DotGraph {
+    pub fn new() -> DotGraph {
+        DotGraph {
+            nodes: BTreeMap::new(),
+            edges: Vec::new()
+        }
+    }
+
+    /// Adds a node labelled with the type name and the short hash.
+    ///
+    /// Returns false if the node already exists.
+    pub fn add_node(&mut self, hash: &Hash, type_name: &str) -> bool {
+        if self.nodes.contains_key(hash) {
+            return false
+        }
+        self.nodes.insert(*hash, format!("{}\n{}", type_name, short_hash(hash)));
+        true
+    }
+
+    pub fn add_edge(&mut self, from: &Hash, to: &Hash, name: &str) {
+        self.edges.push((*from, *to, name.to_string()));
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.edges.len()
+    }
+
+    /// Writes the graph in the DOT language.
+    pub fn to_dot(&self) -> String {
+        let mut res = "digraph hashio {\n    node [shape=box];\n".to_string();
+        for (hash, label) in self.nodes.iter() {
+            res.push_str(&format!("    \"{}\" [label=\"{}\"];\n",
+                                  hash.as_string(), escape(label)));
+        }
+        for (from, to, name) in self.edges.iter() {
+            res.push_str(&format!("    \"{}\" -> \"{}\" [label=\"{}\"];\n",
+                                  from.as_string(), to.as_string(), escape(name)));
+        }
+        res.push_str("}\n");
+        res
+    }
+}
+
+impl Default for DotGraph {
+    fn default() -> DotGraph {
+        DotGraph::new()
+    }
+}
+
+/// Builds the graph of the object and all its children.
+pub fn to_graph(root: &HashIOType) -> DotGraph {
+    let mut graph = DotGraph::new();
+    add_object(&mut graph, root, &root.as_hash());
+    graph
+}
+
+/// Renders the object and all its children.
+pub fn to_dot(root: &HashIOType) -> String {
+    to_graph(root).to_dot()
+}
+
+/// Loads the object as T and renders it.
+pub fn stored_to_dot<T, H>(hash_io: &H, root: &Hash) -> Result<String>
+        where T: HashIOParse + 'static, H: HashIO {
+    let item: Rc<T> = try!(hash_io.get(root));
+    Ok(to_dot(&*item))
+}
+
+/// The first 8 characters of the hash.
+pub fn short_hash(hash: &Hash) -> String {
+    hash.as_string().chars().take(8).collect()
+}
+
+fn add_object(graph: &mut DotGraph, object: &HashIOType, hash: &Hash) {
+    if !graph.add_node(hash, &object.type_name_obj()) {
+        return
+    }
+    for (name, child) in object.childs() {
+        let child_hash = child.as_hash();
+        graph.add_edge(hash, &child_hash, &name);
+        add_object(graph, &*child, &child_hash);
+    }
+}
+
+fn escape(string: &str) -> String {
+    string.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use io::*;
+    use hashiofile::HashIOFile;
+    use std::io::{Read, Write};
+    use std::io;
+    use std::collections::BTreeMap;
+    use std::result;
+    use std::rc::Rc;
+    use std::fs::remove_dir_all;
+
+    hashio_type! {
+        DotTask {
+        } {
+            title: String
+        }
+    }
+    hashio_type! {
+        DotStorage {
+        } {
+            tasks: Vec<Rc<DotTask>>,
+            tags: BTreeMap<Rc<String>, Rc<String>>
+        }
+    }
+
+    #[test]
+    fn test_dot() {
+        remove_dir_all("./unittest/dottest/").ok();
+        let hash_io = HashIOFile::new("unittest/dottest".to_string());
+        let task = Rc::new(DotTask { title: Rc::new("a".to_string()) });
+        let mut tags: BTreeMap<Rc<String>, Rc<String>> = BTreeMap::new();
+        tags.insert(Rc::new("owner".to_string()), Rc::new("a".to_string()));
+        let storage = DotStorage {
+            tasks: Rc::new(vec![task.clone(), task.clone()]),
+            tags: Rc::new(tags)
+        };
+        let root = storage.as_hash();
+
+        let graph = to_graph(&storage);
+        // storage, list, task, map and the shared string "a"
+        assert_eq!(5, graph.node_count());
+        // tasks, tags, two list items, the title and the map value
+        assert_eq!(6, graph.edge_count());
+
+        let dot = to_dot(&storage);
+        assert!(dot.starts_with("digraph hashio {\n"));
+        assert!(dot.contains(&format!("\"{}\" [label=\"DotStorage\\n{}\"];",
+                                      root.as_string(), short_hash(&root))));
+        assert!(dot.contains(&format!("\"{}\" -> \"{}\" [label=\"\\\"owner\\\"\"];",
+                                      storage.tags.as_hash().as_string(),
+                                      task.title.as_hash().as_string())));
+
+        hash_io.put(Rc::new(storage)).unwrap();
+        assert_eq!(dot, stored_to_dot::<DotStorage, _>(&hash_io, &root).unwrap());
+    }
+}
@@ -0,0 +1,545 @@
+//! JSON export and import of stored object graphs.
+//!
+//! # Usage
+//! `JsonExporter` walks the graph which is reachable from a root hash and
+//! describes every object as JSON node.  Models are decoded with their
+//! schemas which must be registered first, `String` and the builtin
+//! collections are recognized by their type names.  `import_json` writes
+//! the objects back into a store with `put_raw`.  The content of every
+//! object is verified against its hash, so the import reproduces identical
+//! hashes.
+//!
+//! The document has the form `{"root": <hash>, "objects": {<hash>: <node>}}`.
+//! Every node contains its `kind` and `type_name`, models also the
+//! `type_hash` of their header.  Depending on the kind, a node contains:
+//!
+//! * `model`: the `fields` with name, codec and value and the `childs` with
+//!   name and hash.
+//! * `string`: the `value`.
+//! * `list`: the `items` of a Vec, VecDeque, BTreeSet or HashSet.
+//! * `map`: the `entries` of a BTreeMap or HashMap as key and value pairs.
+//! * `raw`: the hex encoded file `data` of objects which cannot be decoded,
+//!   like types without registered schema or data of an old version.  Their
+//!   children are found by the `decoder` of the store and exported with an
+//!   empty type name.
+//!
+//! Finite `f32` values are written as numbers.  NaN and the infinities are
+//! written as hex string of their bits like `"0x7fc00000"`, so the payload
+//! of a NaN survives the round trip.
+
+use hash::*;
+use hashio::*;
+use format::split_header;
+use hashiofile::*;
+use io::*;
+use json::*;
+use schema::*;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// Exports object graphs with the help of the registered schemas.
+#[derive(Debug, Clone)]
+pub struct JsonExporter {
+    schemas: BTreeMap<Hash, TypeSchema>
+}
+
+impl JsonExporter {
+    pub fn new() -> JsonExporter {
+        JsonExporter {
+            schemas: BTreeMap::new()
+        }
+    }
+
+    pub fn add_schema(&mut self, schema: TypeSchema) {
+        self.schemas.insert(schema.type_hash, schema);
+    }
+
+    pub fn register<T: HashIOSchema>(&mut self) {
+        self.add_schema(T::schema());
+    }
+
+    /// Builder style version of `register`.
+    pub fn with<T: HashIOSchema>(mut self) -> JsonExporter {
+        self.register::<T>();
+        self
+    }
+
+    /// Exports the graph below root which is stored as T.
+    pub fn export<T: Typeable>(&self, hash_io: &HashIOFile, root: &Hash) -> Result<Json> {
+        self.export_as(hash_io, root, &T::type_name())
+    }
+
+    /// Exports the graph below root where the type is given by its name.
+    pub fn export_as(&self, hash_io: &HashIOFile, root: &Hash, type_name: &str)
+            -> Result<Json> {
+        let mut objects: BTreeMap<String, Json> = BTreeMap::new();
+        let mut pending: Vec<(Hash, String)> = vec![(*root, type_name.to_string())];
+        while let Some((hash, type_name)) = pending.pop() {
+            if objects.contains_key(&hash.as_string()) {
+                continue
+            }
+            let data = try!(hash_io.read_file(&hash)
+                            .map_err(|err| err.in_object(&hash, type_name.clone())));
+            let (header, payload) = match split_header(&hash, &data) {
+                Some(split) => split,
+                None => return Err(HashIOError::HashMismatch(hash))
+            };
+            let mut childs: Vec<(Hash, String)> = Vec::new();
+            let node = match self.decode(&type_name, header, payload, &mut childs) {
+                Some(node) => {
+                    pending.extend(childs);
+                    node
+                },
+                None => {
+                    let object = try!(RawObject::from_file(&hash, &data));
+                    for child in try!(hash_io.decoder.stored_references(hash_io, &object)) {
+                        pending.push((child, String::new()));
+                    }
+                    json_object(vec![
+                        ("kind", json_str("raw")),
+                        ("type_name", json_str(&type_name)),
+                        ("data", json_str(&to_hex(&data)))
+                    ])
+                }
+            };
+            objects.insert(hash.as_string(), node);
+        }
+        Ok(json_object(vec![
+            ("root", json_str(&root.as_string())),
+            ("objects", Json::Object(objects))
+        ]))
+    }
+
+    /// Returns None if the object cannot be decoded.
+    fn decode(&self, type_name: &str, header: Option<(u32, Hash)>, payload: &[u8],
+              childs: &mut Vec<(Hash, String)>) -> Option<Json> {
+        match header {
+            Some((1, type_hash)) => match self.schemas.get(&type_hash) {
+                Some(schema) => decode_model(schema, payload, childs),
+                None => None
+            },
+            Some(_) => None,
+            None => decode_builtin(type_name, payload, childs)
+        }
+    }
+}
+
+impl Default for JsonExporter {
+    fn default() -> JsonExporter {
+        JsonExporter::new()
+    }
+}
+
+/// Writes all objects of an exported document into the store.
+///
+/// Returns the root hash.  Objects which already exist are kept.  If the
+/// import fails in between, only a part of the objects is written.
+pub fn import_json<H>(hash_io: &H, json: &Json) -> Result<Hash> where H: HashIO {
+    let root = try!(get_hash(json, "root"));
+    let objects = try!(json.get("objects").and_then(|objects| objects.as_object())
+                       .ok_or(invalid("Missing object 'objects'".to_string())));
+    for (hash_string, node) in objects {
+        let hash = try!(parse_hash(hash_string));
+        let object = try!(RawObject::from_file(&hash, &try!(encode(node))));
+        try!(hash_io.put_raw(&object));
+    }
+    Ok(root)
+}
+
+
+fn decode_model(schema: &TypeSchema, payload: &[u8], childs: &mut Vec<(Hash, String)>)
+        -> Option<Json> {
+    let mut read: &[u8] = payload;
+    let mut fields: Vec<Json> = Vec::new();
+    for field in schema.fields.iter() {
+        let codec = field.read_fn.trim_start_matches("read_");
+        let value = match read_field(codec, &mut read) {
+            Some(value) => value,
+            None => return None
+        };
+        fields.push(json_object(vec![
+            ("name", json_str(&field.name)),
+            ("codec", json_str(codec)),
+            ("value", value)
+        ]));
+    }
+    let mut child_nodes: Vec<Json> = Vec::new();
+    for child in schema.childs.iter() {
+        let hash = match read_hash(&mut read) {
+            Ok(hash) => hash,
+            Err(_) => return None
+        };
+        childs.push((hash, child.type_name.clone()));
+        child_nodes.push(json_object(vec![
+            ("name", json_str(&child.name)),
+            ("hash", json_str(&hash.as_string()))
+        ]));
+    }
+    if !read.is_empty() {
+        return None
+    }
+    Some(json_object(vec![
+        ("kind", json_str("model")),
+        ("type_name", json_str(&schema.name)),
+        ("type_hash", json_str(&schema.type_hash.as_string())),
+        ("fields", Json::Array(fields)),
+        ("childs", Json::Array(child_nodes))
+    ]))
+}
+
+fn decode_builtin(type_name: &str, payload: &[u8], childs: &mut Vec<(Hash, String)>)
+        -> Option<Json> {
+    if type_name == "String" {
+        return match read_str(payload) {
+            Ok((value, [])) => Some(json_object(vec![
+                ("kind", json_str("string")),
+                ("type_name", json_str(type_name)),
+                ("value", json_str(value))
+            ])),
+            _ => None
+        }
+    }
+    let (base, args) = match split_type_name(type_name) {
+        Some(split) => split,
+        None => return None
+    };
+    let hashes_per_item = match (base, args.len()) {
+        ("Vec", 1) | ("VecDeque", 1) | ("BTreeSet", 1) | ("HashSet", 1) => 1,
+        ("BTreeMap", 2) | ("HashMap", 2) => 2,
+        _ => return None
+    };
+    let mut read: &[u8] = payload;
+    let version = read_u32(&mut read).ok();
+    let len = read_u32(&mut read).ok();
+    let len = match (version, len) {
+        (Some(0), Some(len)) if read.len() == len as usize * hashes_per_item * 33 => len,
+        _ => return None
+    };
+    let mut items: Vec<Json> = Vec::new();
+    for _ in 0..len {
+        let mut item: Vec<Json> = Vec::new();
+        for arg in args.iter() {
+            let hash = read_hash(&mut read).unwrap_or(Hash::None);
+            if hash == Hash::None {
+                return None
+            }
+            childs.push((hash, arg.clone()));
+            item.push(json_str(&hash.as_string()));
+        }
+        items.push(if hashes_per_item == 1 { item.remove(0) } else { Json::Array(item) });
+    }
+    let (kind, key) = if hashes_per_item == 1 { ("list", "items") } else { ("map", "entries") };
+    Some(json_object(vec![
+        ("kind", json_str(kind)),
+        ("type_name", json_str(type_name)),
+        (key, Json::Array(items))
+    ]))
+}
+
+/// Splits a type name like `BTreeMap<String,Vec<String>>` into its base
+/// name and the names of its type arguments.
+fn split_type_name(type_name: &str) -> Option<(&str, Vec<String>)> {
+    let start = match type_name.find('<') {
+        Some(start) => start,
+        None => return None
+    };
+    if !type_name.ends_with('>') {
+        return None
+    }
+    let mut args: Vec<String> = Vec::new();
+    let mut depth = 0;
+    let mut arg = String::new();
+    for c in type_name[start + 1..type_name.len() - 1].chars() {
+        match c {
+            '<' => depth += 1,
+            '>' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(arg.trim().to_string());
+                arg = String::new();
+                continue
+            },
+            _ => ()
+        }
+        arg.push(c);
+    }
+    args.push(arg.trim().to_string());
+    Some((&type_name[..start], args))
+}
+
+fn read_field(codec: &str, read: &mut &[u8]) -> Option<Json> {
+    let value = match codec {
+        "u8" => read_u8(read).map(|i| Json::Number(i as f64)),
+        "u32" => read_u32(read).map(|i| Json::Number(i as f64)),
+        "i32" => read_i32(read).map(|i| Json::Number(i as f64)),
+        "i16" => read_i16(read).map(|i| Json::Number(i as f64)),
+        "f32" => read_f32(read).map(|f| if f.is_finite() {
+            Json::Number(f as f64)
+        } else {
+            Json::String(format!("{:#010x}", f.to_bits()))
+        }),
+        "tm" => {
+            let mut values: Vec<Json> = Vec::new();
+            for _ in 0..11 {
+                match read_i32(read) {
+                    Ok(i) => values.push(Json::Number(i as f64)),
+                    Err(_) => return None
+                }
+            }
+            return Some(Json::Array(values))
+        },
+        _ => return None
+    };
+    value.ok()
+}
+
+fn write_field(codec: &str, value: &Json, write: &mut Vec<u8>) -> Result<()> {
+    let number = |value: &Json| value.as_f64()
+        .ok_or(invalid(format!("Expected a number for codec '{}'", codec)));
+    match codec {
+        "u8" => try!(write_u8(try!(number(value)) as u8, write)),
+        "u32" => try!(write_u32(try!(number(value)) as u32, write)),
+        "i32" => try!(write_i32(try!(number(value)) as i32, write)),
+        "i16" => try!(write_i16(try!(number(value)) as i16, write)),
+        "f32" => {
+            let f = match value.as_str() {
+                Some(string) => try!(parse_float_bits(string)
+                                     .ok_or(invalid(format!("Invalid float '{}'", string)))),
+                None => try!(number(value)) as f32
+            };
+            try!(write_f32(f, write))
+        },
+        "tm" => {
+            let values = try!(value.as_array()
+                              .ok_or(invalid("Expected an array for codec 'tm'".to_string())));
+            if values.len() != 11 {
+                return Err(invalid("Expected 11 values for codec 'tm'".to_string()))
+            }
+            for value in values {
+                try!(write_i32(try!(number(value)) as i32, write));
+            }
+            0
+        },
+        _ => return Err(invalid(format!("Unknown codec '{}'", codec)))
+    };
+    Ok(())
+}
+
+/// Builds the file content of a node.
+fn encode(node: &Json) -> Result<Vec<u8>> {
+    let mut data: Vec<u8> = Vec::new();
+    match &*try!(get_string(node, "kind")) {
+        "model" => {
+            try!(write_u32(1, &mut data));
+            try!(write_hash(&try!(get_hash(node, "type_hash")), &mut data));
+            for field in try!(get_array(node, "fields")) {
+                let value = try!(field.get("value")
+                                 .ok_or(invalid("Missing field value".to_string())));
+                try!(write_field(&try!(get_string(field, "codec")), value, &mut data));
+            }
+            for child in try!(get_array(node, "childs")) {
+                try!(write_hash(&try!(get_hash(child, "hash")), &mut data));
+            }
+        },
+        "string" => {
+            let value = try!(get_string(node, "value"));
+            try!(data.write_all(&usize_to_u32_bytes(value.len())));
+            try!(data.write_all(value.as_bytes()));
+        },
+        "list" => {
+            let items = try!(get_array(node, "items"));
+            try!(write_u32(0, &mut data));
+            try!(write_u32(items.len() as u32, &mut data));
+            for item in items {
+                try!(write_hash(&try!(to_hash(item)), &mut data));
+            }
+        },
+        "map" => {
+            let entries = try!(get_array(node, "entries"));
+            try!(write_u32(0, &mut data));
+            try!(write_u32(entries.len() as u32, &mut data));
+            for entry in entries {
+                match entry.as_array() {
+                    Some(pair) if pair.len() == 2 => {
+                        try!(write_hash(&try!(to_hash(&pair[0])), &mut data));
+                        try!(write_hash(&try!(to_hash(&pair[1])), &mut data));
+                    },
+                    _ => return Err(invalid("Expected a key value pair".to_string()))
+                }
+            }
+        },
+        "raw" => data = try!(from_hex(&try!(get_string(node, "data")))),
+        kind => return Err(invalid(format!("Unknown kind '{}'", kind)))
+    }
+    Ok(data)
+}
+
+
+fn invalid(msg: String) -> HashIOError {
+    HashIOError::Undefined(format!("Invalid export: {}", msg))
+}
+
+impl From<FieldError> for HashIOError {
+    fn from(err: FieldError) -> HashIOError {
+        invalid(err.message)
+    }
+}
+
+fn to_hash(json: &Json) -> Result<Hash> {
+    let string = try!(json.as_str().ok_or(invalid("Expected a hash".to_string())));
+    Ok(try!(parse_hash(string)))
+}
+
+/// Reads the bits of a float like `0x7fc00000`, older exports wrote names
+/// like `NaN` or `inf`.
+fn parse_float_bits(string: &str) -> Option<f32> {
+    match string.strip_prefix("0x") {
+        Some(bits) => u32::from_str_radix(bits, 16).ok().map(f32::from_bits),
+        None => string.parse::<f32>().ok()
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(string: &str) -> Result<Vec<u8>> {
+    if string.len() % 2 != 0 || !string.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid("Invalid hex data".to_string()))
+    }
+    Ok((0..string.len() / 2)
+       .map(|i| u8::from_str_radix(&string[2 * i..2 * i + 2], 16).unwrap())
+       .collect())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hashiofile::HashIOFile;
+    use json::Json;
+    use std::io::{Read, Write};
+    use std::io;
+    use std::collections::BTreeMap;
+    use std::result;
+    use std::rc::Rc;
+    use std::fs::remove_dir_all;
+
+    hashio_type! {
+        ExportTask {
+            priority: u32, read_u32, write_u32,
+            factor: f32, read_f32, write_f32
+        } {
+            title: String
+        }
+    }
+    hashio_type! {
+        ExportStorage {
+        } {
+            name: String,
+            tasks: Vec<Rc<ExportTask>>,
+            tags: BTreeMap<Rc<String>, Rc<String>>
+        }
+    }
+
+    fn storage() -> ExportStorage {
+        let mut tags: BTreeMap<Rc<String>, Rc<String>> = BTreeMap::new();
+        tags.insert(Rc::new("color".to_string()), Rc::new("red".to_string()));
+        ExportStorage {
+            name: Rc::new("export".to_string()),
+            tasks: Rc::new(vec![
+                Rc::new(ExportTask { priority: 3, factor: 0.1, title: Rc::new("a".to_string()) }),
+                Rc::new(ExportTask { priority: 1, factor: -2.5, title: Rc::new("b".to_string()) })
+            ]),
+            tags: Rc::new(tags)
+        }
+    }
+
+    #[test]
+    fn test_export_import() {
+        remove_dir_all("./unittest/exporttest/").ok();
+        let hash_io = HashIOFile::new("unittest/exporttest/src".to_string());
+        let target = HashIOFile::new("unittest/exporttest/target".to_string());
+        let root = storage().as_hash();
+        hash_io.put(Rc::new(storage())).unwrap();
+
+        let exporter = JsonExporter::new().with::<ExportStorage>().with::<ExportTask>();
+        let json = exporter.export::<ExportStorage>(&hash_io, &root).unwrap();
+        let objects = json.get("objects").unwrap();
+        let node = objects.get(&root.as_string()).unwrap();
+        assert_eq!(Some("model"), node.get("kind").and_then(|kind| kind.as_str()));
+        let task_node = objects.as_object().unwrap().values()
+            .find(|node| node.get("type_name").and_then(|name| name.as_str()) == Some("ExportTask"))
+            .unwrap();
+        assert_eq!(Some("f32"), task_node.get("fields").unwrap().as_array().unwrap()[1]
+                   .get("codec").and_then(|codec| codec.as_str()));
+
+        let json = Json::parse(&json.to_pretty_string()).unwrap();
+        assert_eq!(root, import_json(&target, &json).unwrap());
+        assert_eq!(hash_io.hashes().unwrap(), target.hashes().unwrap());
+        let imported: Rc<ExportStorage> = target.get(&root).unwrap();
+        assert_eq!(root, imported.as_hash());
+        assert_eq!(0.1, imported.tasks[0].factor);
+    }
+
+    #[test]
+    fn test_export_raw() {
+        remove_dir_all("./unittest/exportrawtest/").ok();
+        let hash_io = HashIOFile::new("unittest/exportrawtest/src".to_string());
+        let target = HashIOFile::new("unittest/exportrawtest/target".to_string());
+        let root = storage().as_hash();
+        hash_io.put(Rc::new(storage())).unwrap();
+
+        // Without schema, the tasks are exported as raw data and their
+        // titles are found by the decoder.
+        let exporter = JsonExporter::new().with::<ExportStorage>();
+        let json = exporter.export::<ExportStorage>(&hash_io, &root).unwrap();
+        let task_hash = storage().tasks[0].as_hash();
+        let task_node = json.get("objects").unwrap().get(&task_hash.as_string()).unwrap();
+        assert_eq!(Some("raw"), task_node.get("kind").and_then(|kind| kind.as_str()));
+        let title_hash = storage().tasks[0].title.as_hash();
+        let title_node = json.get("objects").unwrap().get(&title_hash.as_string()).unwrap();
+        assert_eq!(Some(""), title_node.get("type_name").and_then(|name| name.as_str()));
+
+        import_json(&target, &json).unwrap();
+        assert_eq!(hash_io.read_file(&task_hash).unwrap(), target.read_file(&task_hash).unwrap());
+        assert_eq!(hash_io.hashes().unwrap(), target.hashes().unwrap());
+
+        let mut json = json;
+        if let Json::Object(ref mut document) = json {
+            document.insert("root".to_string(), Json::String("00".to_string()));
+        }
+        assert!(import_json(&target, &json).is_err());
+    }
+
+    #[test]
+    fn test_export_floats() {
+        remove_dir_all("./unittest/exportfloattest/").ok();
+        let hash_io = HashIOFile::new("unittest/exportfloattest/src".to_string());
+        let target = HashIOFile::new("unittest/exportfloattest/target".to_string());
+        let nan = f32::from_bits(0x7fc0_1234);
+        let factors = [-0.0, nan, f32::NEG_INFINITY];
+        let storage = ExportStorage {
+            name: Rc::new("floats".to_string()),
+            tasks: Rc::new(factors.iter()
+                           .map(|factor| Rc::new(ExportTask {
+                               priority: 1,
+                               factor: *factor,
+                               title: Rc::new("a".to_string())
+                           }))
+                           .collect()),
+            tags: Rc::new(BTreeMap::new())
+        };
+        let root = storage.as_hash();
+        hash_io.put(Rc::new(storage)).unwrap();
+
+        let exporter = JsonExporter::new().with::<ExportStorage>().with::<ExportTask>();
+        let json = exporter.export::<ExportStorage>(&hash_io, &root).unwrap();
+        let json = Json::parse(&json.to_string()).unwrap();
+        import_json(&target, &json).unwrap();
+        assert_eq!(hash_io.hashes().unwrap(), target.hashes().unwrap());
+        let imported: Rc<ExportStorage> = target.get(&root).unwrap();
+        let bits: Vec<u32> = imported.tasks.iter().map(|task| task.factor.to_bits()).collect();
+        let expected: Vec<u32> = factors.iter().map(|factor| factor.to_bits()).collect();
+        assert_eq!(expected, bits);
+    }
+}
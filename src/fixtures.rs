@@ -0,0 +1,39 @@
+//! Models shared by the unit tests of the store tools.
+//!
+//! # Usage
+//! `storage` builds a list of tasks from their titles.  Stored, it consists
+//! of the root, the list, one object per distinct task and one per distinct
+//! title, so `storage(&["a", "b"])` writes six objects.
+
+use hash::*;
+use hashio::*;
+use io::*;
+use std::io::{Read, Write};
+use std::io;
+use std::collections::BTreeMap;
+use std::result;
+use std::rc::Rc;
+
+hashio_type! {
+    FixtureTask {
+        done: u8, read_u8, write_u8
+    } {
+        title: String
+    }
+}
+hashio_type! {
+    FixtureStorage {
+    } {
+        tasks: Vec<Rc<FixtureTask>>
+    }
+}
+
+/// Storage with one open task per title.
+pub fn storage(titles: &[&str]) -> FixtureStorage {
+    FixtureStorage {
+        tasks: Rc::new(titles.iter().map(|title| Rc::new(FixtureTask {
+            done: 0,
+            title: Rc::new(title.to_string())
+        })).collect())
+    }
+}
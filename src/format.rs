@@ -0,0 +1,66 @@
+//! Layout of a stored object.
+//!
+//! # Usage
+//! Every backend stores an object as its header followed by the payload.
+//! The header contains the version as u32 and the type hash.  Types which
+//! use the unsafe loader, like strings and collections, write no header.
+//! The hash of an object is only calculated from its payload.
+//!
+//! `write_object` and `parse_object` convert between an object and this
+//! layout, `split_header` separates the header without knowing the type.
+
+use hash::*;
+use hashio::*;
+use io::*;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+
+/// Size of the version and type hash in front of an object.
+pub const HEADER_LEN: usize = 37;
+
+/// Splits the content of an object file into its header and payload.
+///
+/// A file has a header if the hash of the data behind it matches, otherwise
+/// the whole file must match.  Returns None if neither matches, so the file
+/// is damaged or stored under a wrong hash.
+pub fn split_header<'a>(hash: &Hash, data: &'a [u8]) -> Option<(Option<(u32, Hash)>, &'a [u8])> {
+    if data.len() >= HEADER_LEN && Hash::hash_bytes(&data[HEADER_LEN..]) == *hash {
+        let mut read: &[u8] = data;
+        let version = read_u32(&mut read).unwrap();
+        let type_hash = read_hash(&mut read).unwrap();
+        return Some((Some((version, type_hash)), &data[HEADER_LEN..]))
+    }
+    if Hash::hash_bytes(data) == *hash {
+        return Some((None, data))
+    }
+    None
+}
+
+/// Parses an object with its header.
+pub fn parse_object<T, H, R>(hash_io: &H, read: &mut R) -> Result<Rc<T>>
+            where T: HashIOParse, H: HashIO, R: Read {
+    let mut type_hash: Option<Hash> = None;
+    if !T::unsafe_loader() {
+        let version = try!(read_u32(read));
+        if !T::version_valid(version) {
+            // try fallback
+            return T::fallback_parse(hash_io, read)
+        }
+        type_hash = Some(try!(read_hash(read)));
+        if !T::type_hash_valid(&type_hash.unwrap()) {
+            return Err(HashIOError::TypeError(type_hash.unwrap()))
+        }
+    }
+    T::parse(hash_io, read, &type_hash)
+}
+
+/// Writes the object with its header.
+pub fn write_object<T, H, W>(hash_io: &H, item: &T, write: &mut W) -> Result<()>
+            where T: HashIOParse, H: HashIO, W: Write {
+    if !T::unsafe_loader() {
+        try!(write_u32(1, write));
+        try!(write_hash(&T::type_hash(), write));
+    }
+    item.store(hash_io, write)
+}
@@ -0,0 +1,755 @@
+//! Persistent hash array mapped trie.
+//!
+//! # Usage
+//! A `BTreeMap` is stored as one object listing all key and value hashes, so
+//! changing a single entry rewrites the whole list.  `Hamt` splits the map
+//! into nodes which are stored as separate HashIO objects.  Each node has up
+//! to 32 slots which are selected by five bits of the key hash per level.
+//!
+//! The map is persistent:  `insert` and `remove` return a new map which shares
+//! all unchanged nodes with the old one.  When the new map is stored, only the
+//! O(log n) changed nodes are written since all others already exist.
+//!
+//! Loading a `Hamt` with `HashIO::get` loads all nodes.  If only some entries
+//! are required, `Hamt::lookup` loads only the nodes on the path to the key
+//! and `Hamt::insert_into` and `Hamt::remove_from` update a stored map
+//! without loading it.
+//!
+//! Keys are identified by their content hash.  The structure only depends on
+//! the entries and not on the order of the operations, so equal maps have
+//! equal hashes.
+//!
+//! Unlike `BTreeMap`, `childs` lists the keys as well, named like the value
+//! with a `#key` suffix, because keys are stored as separate objects.
+
+use hash::*;
+use io::*;
+use hashio::*;
+use std::io::{Read, Write};
+use std::result;
+use std::io;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+const BITS_PER_LEVEL: usize = 5;
+const ENTRY_LEAF: u8 = 0;
+const ENTRY_NODE: u8 = 1;
+
+/// Returns the slot of the hash on the given level.
+fn fragment(hash: &Hash, level: usize) -> u32 {
+    let bytes = hash.get_bytes();
+    let mut res = 0u32;
+    for i in 0..BITS_PER_LEVEL {
+        let bit = level * BITS_PER_LEVEL + i;
+        res <<= 1;
+        if bit < bytes.len() * 8 {
+            res |= ((bytes[bit / 8] >> (7 - bit % 8)) & 1) as u32;
+        }
+    }
+    res
+}
+
+/// Position of the slot in the entry list.
+fn position(bitmap: u32, slot: u32) -> usize {
+    (bitmap & ((1u32 << slot) - 1)).count_ones() as usize
+}
+
+
+enum HamtEntry<K, V> {
+    Leaf {
+        key_hash: Hash,
+        key: Rc<K>,
+        value_hash: Hash,
+        value: Rc<V>
+    },
+    Node(Hash, Rc<Hamt<K, V>>)
+}
+
+impl<K, V> Clone for HamtEntry<K, V> {
+    fn clone(&self) -> HamtEntry<K, V> {
+        match *self {
+            HamtEntry::Leaf { key_hash, ref key, value_hash, ref value } => HamtEntry::Leaf {
+                key_hash: key_hash,
+                key: key.clone(),
+                value_hash: value_hash,
+                value: value.clone()
+            },
+            HamtEntry::Node(hash, ref node) => HamtEntry::Node(hash, node.clone())
+        }
+    }
+}
+
+impl<K, V> HamtEntry<K, V> where K: HashIOParse, V: HashIOParse {
+    fn leaf(key: Rc<K>, value: Rc<V>) -> HamtEntry<K, V> {
+        HamtEntry::Leaf {
+            key_hash: key.as_hash(),
+            key: key,
+            value_hash: value.as_hash(),
+            value: value
+        }
+    }
+
+    fn node(node: Hamt<K, V>) -> HamtEntry<K, V> {
+        HamtEntry::Node(node.as_hash(), Rc::new(node))
+    }
+}
+
+
+/// Node of a persistent hash array mapped trie and the map itself.
+pub struct Hamt<K, V> {
+    bitmap: u32,
+    entries: Vec<HamtEntry<K, V>>
+}
+
+impl<K, V> Clone for Hamt<K, V> {
+    fn clone(&self) -> Hamt<K, V> {
+        Hamt {
+            bitmap: self.bitmap,
+            entries: self.entries.clone()
+        }
+    }
+}
+
+impl<K, V> fmt::Debug for Hamt<K, V> where K: HashIOParse, V: HashIOParse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut map = f.debug_map();
+        for (key, value) in self.entries() {
+            map.entry(&key, &value);
+        }
+        map.finish()
+    }
+}
+
+impl<K, V> Hamt<K, V> where K: HashIOParse, V: HashIOParse {
+    /// Creates an empty map.
+    pub fn new() -> Hamt<K, V> {
+        Hamt {
+            bitmap: 0,
+            entries: Vec::new()
+        }
+    }
+
+    /// Number of entries in the map.
+    pub fn len(&self) -> usize {
+        self.entries.iter().map(|entry| match *entry {
+            HamtEntry::Leaf { .. } => 1,
+            HamtEntry::Node(_, ref node) => node.len()
+        }).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns all entries ordered by the hashes of the keys.
+    pub fn entries(&self) -> Vec<(Rc<K>, Rc<V>)> {
+        let mut res: Vec<(Rc<K>, Rc<V>)> = Vec::new();
+        self.collect_entries(&mut res);
+        res
+    }
+
+    fn collect_entries(&self, res: &mut Vec<(Rc<K>, Rc<V>)>) {
+        for entry in self.entries.iter() {
+            match *entry {
+                HamtEntry::Leaf { ref key, ref value, .. } => res.push((key.clone(), value.clone())),
+                HamtEntry::Node(_, ref node) => node.collect_entries(res)
+            }
+        }
+    }
+
+    /// Looks up the value of the key.
+    pub fn get(&self, key: &K) -> Option<Rc<V>> {
+        let key_hash = key.as_hash();
+        let mut node = self;
+        let mut level = 0;
+        loop {
+            let slot = fragment(&key_hash, level);
+            if node.bitmap & (1 << slot) == 0 {
+                return None
+            }
+            match node.entries[position(node.bitmap, slot)] {
+                HamtEntry::Leaf { key_hash: leaf_key_hash, ref value, .. } => {
+                    return if leaf_key_hash == key_hash { Some(value.clone()) } else { None }
+                },
+                HamtEntry::Node(_, ref child) => {
+                    node = child;
+                    level += 1;
+                }
+            }
+        }
+    }
+
+    /// Returns a new map which contains the entry.
+    ///
+    /// All nodes which are not on the path to the key are shared.
+    pub fn insert(&self, key: Rc<K>, value: Rc<V>) -> Hamt<K, V> {
+        self.insert_entry(HamtEntry::leaf(key, value), 0)
+    }
+
+    fn insert_entry(&self, leaf: HamtEntry<K, V>, level: usize) -> Hamt<K, V> {
+        let key_hash = match leaf {
+            HamtEntry::Leaf { key_hash, .. } => key_hash,
+            HamtEntry::Node(..) => unreachable!()
+        };
+        let slot = fragment(&key_hash, level);
+        let pos = position(self.bitmap, slot);
+        let mut res = self.clone();
+        if self.bitmap & (1 << slot) == 0 {
+            res.bitmap |= 1 << slot;
+            res.entries.insert(pos, leaf);
+            return res
+        }
+        let new_entry = match self.entries[pos] {
+            HamtEntry::Leaf { key_hash: existing_hash, .. } if existing_hash == key_hash => leaf,
+            HamtEntry::Leaf { .. } => {
+                let node = Hamt::new()
+                    .insert_entry(self.entries[pos].clone(), level + 1)
+                    .insert_entry(leaf, level + 1);
+                HamtEntry::node(node)
+            },
+            HamtEntry::Node(_, ref child) => HamtEntry::node(child.insert_entry(leaf, level + 1))
+        };
+        res.entries[pos] = new_entry;
+        res
+    }
+
+    /// Returns a new map without the key.
+    pub fn remove(&self, key: &K) -> Hamt<K, V> {
+        self.remove_hash(&key.as_hash(), 0)
+    }
+
+    fn remove_hash(&self, key_hash: &Hash, level: usize) -> Hamt<K, V> {
+        let slot = fragment(key_hash, level);
+        if self.bitmap & (1 << slot) == 0 {
+            return self.clone()
+        }
+        let pos = position(self.bitmap, slot);
+        let mut res = self.clone();
+        let replacement = match self.entries[pos] {
+            HamtEntry::Leaf { key_hash: existing_hash, .. } => {
+                if existing_hash != *key_hash {
+                    return res
+                }
+                None
+            },
+            HamtEntry::Node(_, ref child) => {
+                let new_child = child.remove_hash(key_hash, level + 1);
+                match new_child.single_leaf() {
+                    Some(leaf) => Some(leaf),
+                    None if new_child.is_empty() => None,
+                    None => Some(HamtEntry::node(new_child))
+                }
+            }
+        };
+        match replacement {
+            Some(entry) => res.entries[pos] = entry,
+            None => {
+                res.bitmap &= !(1 << slot);
+                res.entries.remove(pos);
+            }
+        }
+        res
+    }
+
+    /// Returns the entry if the node only contains one leaf.
+    ///
+    /// Such nodes are collapsed into their parent so the structure only
+    /// depends on the entries.
+    fn single_leaf(&self) -> Option<HamtEntry<K, V>> {
+        if self.entries.len() == 1 {
+            if let HamtEntry::Leaf { .. } = self.entries[0] {
+                return Some(self.entries[0].clone())
+            }
+        }
+        None
+    }
+}
+
+impl<K, V> Default for Hamt<K, V> where K: HashIOParse, V: HashIOParse {
+    fn default() -> Hamt<K, V> {
+        Hamt::new()
+    }
+}
+
+impl<K, V> Writable for Hamt<K, V> where K: HashIOParse, V: HashIOParse {
+    fn write_to<W: Write>(&self, write: &mut W) -> result::Result<usize, io::Error> {
+        let mut size = try!(write_u32(self.bitmap, write));
+        for entry in self.entries.iter() {
+            match *entry {
+                HamtEntry::Leaf { ref key_hash, ref value_hash, .. } => {
+                    size += try!(write_u8(ENTRY_LEAF, write));
+                    size += try!(write_hash(key_hash, write));
+                    size += try!(write_hash(value_hash, write));
+                },
+                HamtEntry::Node(ref hash, _) => {
+                    size += try!(write_u8(ENTRY_NODE, write));
+                    size += try!(write_hash(hash, write));
+                }
+            }
+        }
+        Ok(size)
+    }
+}
+
+impl<K, V> Hashable for Hamt<K, V> where K: HashIOParse, V: HashIOParse {
+    fn as_hash(&self) -> Hash {
+        self.writable_to_hash()
+    }
+}
+
+impl<K, V> Typeable for Hamt<K, V> where K: HashIOParse, V: HashIOParse {
+    fn type_hash() -> Hash {
+        Hash::hash_string(Hamt::<K, V>::type_name())
+    }
+
+    fn type_name() -> String {
+        "Hamt<".to_string() + &K::type_name() + "," + &V::type_name() + ">"
+    }
+}
+
+impl<K, V> HashIOType for Hamt<K, V> where K: HashIOParse + 'static, V: HashIOParse + 'static {
+    fn childs(&self) -> BTreeMap<String, Rc<HashIOType>> {
+        let mut res: BTreeMap<String, Rc<HashIOType>> = BTreeMap::new();
+        let mut slots = (0..32).filter(|slot| self.bitmap & (1 << slot) != 0);
+        for entry in self.entries.iter() {
+            let slot = slots.next().unwrap_or(0);
+            match *entry {
+                HamtEntry::Leaf { ref key, ref value, .. } => {
+                    let key_str = format!("{:?}", key);
+                    res.insert(format!("{}#key", key_str), key.clone() as Rc<HashIOType>);
+                    res.insert(key_str, value.clone() as Rc<HashIOType>);
+                },
+                HamtEntry::Node(_, ref node) => {
+                    res.insert(format!("#{}", slot), node.clone() as Rc<HashIOType>);
+                }
+            }
+        }
+        res
+    }
+
+    fn type_hash_obj(&self) -> Hash {
+        Hamt::<K, V>::type_hash()
+    }
+
+    fn type_name_obj(&self) -> String {
+        Hamt::<K, V>::type_name()
+    }
+}
+
+impl<K, V> HashIOParse for Hamt<K, V> where K: HashIOParse + 'static, V: HashIOParse + 'static {
+    fn parse<H, R>(hash_io: &H, read: &mut R, _: &Option<Hash>)
+                -> Result<Rc<Self>> where H: HashIO, R: Read {
+        let shallow = try!(ShallowNode::<K, V>::read(read));
+        let mut entries: Vec<HamtEntry<K, V>> = Vec::new();
+        for entry in shallow.entries {
+            entries.push(match entry {
+                ShallowEntry::Leaf(key_hash, value_hash) => HamtEntry::Leaf {
+                    key_hash: key_hash,
+                    key: try!(hash_io.get(&key_hash)),
+                    value_hash: value_hash,
+                    value: try!(hash_io.get(&value_hash))
+                },
+                ShallowEntry::Node(hash) => HamtEntry::Node(hash, try!(hash_io.get(&hash)))
+            });
+        }
+        Ok(Rc::new(Hamt {
+            bitmap: shallow.bitmap,
+            entries: entries
+        }))
+    }
+
+    fn store<H, W>(&self, _: &H, write: &mut W) -> Result<()> where H: HashIO, W: Write {
+        try!(self.write_to(write));
+        Ok(())
+    }
+
+    fn store_childs<H>(&self, hash_io: &H) -> Result<()>
+            where H: HashIO {
+        for entry in self.entries.iter() {
+            match *entry {
+                HamtEntry::Leaf { ref key, ref value, .. } => {
+                    try!(hash_io.put(key.clone()));
+                    try!(hash_io.put(value.clone()));
+                },
+                HamtEntry::Node(_, ref node) => try!(hash_io.put(node.clone()))
+            }
+        }
+        Ok(())
+    }
+
+    fn type_hash_valid(hash: &Hash) -> bool {
+        *hash == Hamt::<K, V>::type_hash()
+    }
+
+    fn payload_references(payload: &[u8], _: &Option<Hash>) -> Result<Option<Vec<Hash>>> {
+        let mut read: &[u8] = payload;
+        let shallow = try!(ShallowNode::<K, V>::read(&mut read));
+        let mut res: Vec<Hash> = Vec::new();
+        for entry in shallow.entries {
+            match entry {
+                ShallowEntry::Leaf(key_hash, value_hash) => {
+                    res.push(key_hash);
+                    res.push(value_hash);
+                },
+                ShallowEntry::Node(hash) => res.push(hash)
+            }
+        }
+        Ok(Some(res))
+    }
+}
+
+
+impl<K, V> Hamt<K, V> where K: HashIOParse + 'static, V: HashIOParse + 'static {
+    /// Looks up a key in a stored map and only loads the nodes on the path.
+    pub fn lookup<H>(hash_io: &H, root: &Hash, key: &K) -> Result<Option<Rc<V>>>
+            where H: HashIO {
+        let key_hash = key.as_hash();
+        let mut node_hash = *root;
+        let mut level = 0;
+        loop {
+            let node: Rc<ShallowNode<K, V>> = try!(hash_io.get(&node_hash));
+            let slot = fragment(&key_hash, level);
+            if node.bitmap & (1 << slot) == 0 {
+                return Ok(None)
+            }
+            match node.entries[position(node.bitmap, slot)] {
+                ShallowEntry::Leaf(leaf_key_hash, value_hash) => {
+                    return if leaf_key_hash == key_hash {
+                        Ok(Some(try!(hash_io.get(&value_hash))))
+                    } else {
+                        Ok(None)
+                    }
+                },
+                ShallowEntry::Node(hash) => {
+                    node_hash = hash;
+                    level += 1;
+                }
+            }
+        }
+    }
+
+    /// Inserts an entry into a stored map and returns the hash of the new root.
+    ///
+    /// Only the nodes on the path to the key are loaded and written.
+    pub fn insert_into<H>(hash_io: &H, root: &Hash, key: Rc<K>, value: Rc<V>) -> Result<Hash>
+            where H: HashIO {
+        let leaf = ShallowEntry::Leaf(key.as_hash(), value.as_hash());
+        try!(hash_io.put(key));
+        try!(hash_io.put(value));
+        let root_node: Rc<ShallowNode<K, V>> = try!(hash_io.get(root));
+        let new_root = try!(root_node.insert_entry(hash_io, leaf, 0));
+        let new_root_hash = new_root.as_hash();
+        try!(hash_io.put(Rc::new(new_root)));
+        Ok(new_root_hash)
+    }
+
+    /// Removes a key from a stored map and returns the hash of the new root.
+    ///
+    /// Only the nodes on the path to the key are loaded and written.
+    pub fn remove_from<H>(hash_io: &H, root: &Hash, key: &K) -> Result<Hash>
+            where H: HashIO {
+        let root_node: Rc<ShallowNode<K, V>> = try!(hash_io.get(root));
+        let new_root = try!(root_node.remove_hash(hash_io, &key.as_hash(), 0));
+        let new_root_hash = new_root.as_hash();
+        try!(hash_io.put(Rc::new(new_root)));
+        Ok(new_root_hash)
+    }
+}
+
+
+/// Entry of a node which only references its children by their hashes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ShallowEntry {
+    Leaf(Hash, Hash),
+    Node(Hash)
+}
+
+/// View of a stored `Hamt` node which doesn't load its children.
+///
+/// It uses the same type hash and binary representation as `Hamt`, so
+/// it can read and write the nodes of a `Hamt` directly.
+#[derive(Debug)]
+struct ShallowNode<K, V> {
+    bitmap: u32,
+    entries: Vec<ShallowEntry>,
+    phantom: PhantomData<(K, V)>
+}
+
+impl<K, V> Clone for ShallowNode<K, V> {
+    fn clone(&self) -> ShallowNode<K, V> {
+        ShallowNode {
+            bitmap: self.bitmap,
+            entries: self.entries.clone(),
+            phantom: PhantomData
+        }
+    }
+}
+
+impl<K, V> ShallowNode<K, V> where K: HashIOParse + 'static, V: HashIOParse + 'static {
+    fn read<R>(read: &mut R) -> Result<ShallowNode<K, V>> where R: Read {
+        let bitmap = try!(read_u32(read));
+        let mut entries: Vec<ShallowEntry> = Vec::new();
+        for _ in 0..bitmap.count_ones() {
+            let entry = match try!(read_u8(read)) {
+                ENTRY_LEAF => {
+                    let key_hash = try!(read_hash(read));
+                    let value_hash = try!(read_hash(read));
+                    ShallowEntry::Leaf(key_hash, value_hash)
+                },
+                ENTRY_NODE => ShallowEntry::Node(try!(read_hash(read))),
+                tag => return Err(HashIOError::Undefined(format!("Invalid Hamt entry {}", tag)))
+            };
+            entries.push(entry);
+        }
+        Ok(ShallowNode {
+            bitmap: bitmap,
+            entries: entries,
+            phantom: PhantomData
+        })
+    }
+
+    fn empty() -> ShallowNode<K, V> {
+        ShallowNode {
+            bitmap: 0,
+            entries: Vec::new(),
+            phantom: PhantomData
+        }
+    }
+
+    fn insert_entry<H>(&self, hash_io: &H, leaf: ShallowEntry, level: usize)
+            -> Result<ShallowNode<K, V>> where H: HashIO {
+        let key_hash = match leaf {
+            ShallowEntry::Leaf(key_hash, _) => key_hash,
+            ShallowEntry::Node(_) => unreachable!()
+        };
+        let slot = fragment(&key_hash, level);
+        let pos = position(self.bitmap, slot);
+        let mut res = self.clone();
+        if self.bitmap & (1 << slot) == 0 {
+            res.bitmap |= 1 << slot;
+            res.entries.insert(pos, leaf);
+            return Ok(res)
+        }
+        let new_node = match self.entries[pos] {
+            ShallowEntry::Leaf(existing_hash, _) if existing_hash == key_hash => {
+                res.entries[pos] = leaf;
+                return Ok(res)
+            },
+            ShallowEntry::Leaf(..) => {
+                let node = try!(ShallowNode::empty()
+                    .insert_entry(hash_io, self.entries[pos], level + 1));
+                try!(node.insert_entry(hash_io, leaf, level + 1))
+            },
+            ShallowEntry::Node(hash) => {
+                let child: Rc<ShallowNode<K, V>> = try!(hash_io.get(&hash));
+                try!(child.insert_entry(hash_io, leaf, level + 1))
+            }
+        };
+        res.entries[pos] = ShallowEntry::Node(new_node.as_hash());
+        try!(hash_io.put(Rc::new(new_node)));
+        Ok(res)
+    }
+
+    fn remove_hash<H>(&self, hash_io: &H, key_hash: &Hash, level: usize)
+            -> Result<ShallowNode<K, V>> where H: HashIO {
+        let slot = fragment(key_hash, level);
+        if self.bitmap & (1 << slot) == 0 {
+            return Ok(self.clone())
+        }
+        let pos = position(self.bitmap, slot);
+        let mut res = self.clone();
+        let replacement = match self.entries[pos] {
+            ShallowEntry::Leaf(existing_hash, _) => {
+                if existing_hash != *key_hash {
+                    return Ok(res)
+                }
+                None
+            },
+            ShallowEntry::Node(hash) => {
+                let child: Rc<ShallowNode<K, V>> = try!(hash_io.get(&hash));
+                let new_child = try!(child.remove_hash(hash_io, key_hash, level + 1));
+                match new_child.entries.first() {
+                    None => None,
+                    Some(&ShallowEntry::Leaf(..)) if new_child.entries.len() == 1 =>
+                        Some(new_child.entries[0]),
+                    _ => {
+                        let new_hash = new_child.as_hash();
+                        try!(hash_io.put(Rc::new(new_child)));
+                        Some(ShallowEntry::Node(new_hash))
+                    }
+                }
+            }
+        };
+        match replacement {
+            Some(entry) => res.entries[pos] = entry,
+            None => {
+                res.bitmap &= !(1 << slot);
+                res.entries.remove(pos);
+            }
+        }
+        Ok(res)
+    }
+}
+
+impl<K, V> Writable for ShallowNode<K, V> {
+    fn write_to<W: Write>(&self, write: &mut W) -> result::Result<usize, io::Error> {
+        let mut size = try!(write_u32(self.bitmap, write));
+        for entry in self.entries.iter() {
+            match *entry {
+                ShallowEntry::Leaf(ref key_hash, ref value_hash) => {
+                    size += try!(write_u8(ENTRY_LEAF, write));
+                    size += try!(write_hash(key_hash, write));
+                    size += try!(write_hash(value_hash, write));
+                },
+                ShallowEntry::Node(ref hash) => {
+                    size += try!(write_u8(ENTRY_NODE, write));
+                    size += try!(write_hash(hash, write));
+                }
+            }
+        }
+        Ok(size)
+    }
+}
+
+impl<K, V> Hashable for ShallowNode<K, V> {
+    fn as_hash(&self) -> Hash {
+        self.writable_to_hash()
+    }
+}
+
+impl<K, V> Typeable for ShallowNode<K, V> where K: HashIOParse, V: HashIOParse {
+    fn type_hash() -> Hash {
+        Hamt::<K, V>::type_hash()
+    }
+
+    fn type_name() -> String {
+        Hamt::<K, V>::type_name()
+    }
+}
+
+impl<K, V> HashIOType for ShallowNode<K, V> where K: HashIOParse, V: HashIOParse {
+    fn type_hash_obj(&self) -> Hash {
+        ShallowNode::<K, V>::type_hash()
+    }
+
+    fn type_name_obj(&self) -> String {
+        ShallowNode::<K, V>::type_name()
+    }
+}
+
+impl<K, V> HashIOParse for ShallowNode<K, V>
+        where K: HashIOParse + 'static, V: HashIOParse + 'static {
+    fn parse<H, R>(_: &H, read: &mut R, _: &Option<Hash>)
+                -> Result<Rc<Self>> where H: HashIO, R: Read {
+        Ok(Rc::new(try!(ShallowNode::read(read))))
+    }
+
+    fn store<H, W>(&self, _: &H, write: &mut W) -> Result<()> where H: HashIO, W: Write {
+        try!(self.write_to(write));
+        Ok(())
+    }
+
+    fn type_hash_valid(hash: &Hash) -> bool {
+        *hash == ShallowNode::<K, V>::type_hash()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::rc::Rc;
+    use hashiofile::HashIOFile;
+    use std::fs::remove_dir_all;
+
+    fn string(value: &str) -> Rc<String> {
+        Rc::new(value.to_string())
+    }
+
+    #[test]
+    fn test_in_memory() {
+        let mut map: Hamt<String, String> = Hamt::new();
+        for i in 0..200 {
+            map = map.insert(string(&format!("key{}", i)), string(&format!("value{}", i)));
+        }
+        assert_eq!(200, map.len());
+        assert_eq!(Some(string("value42")), map.get(&"key42".to_string()));
+        assert_eq!(None, map.get(&"key200".to_string()));
+
+        let updated = map.insert(string("key42"), string("changed"));
+        assert_eq!(200, updated.len());
+        assert_eq!(Some(string("changed")), updated.get(&"key42".to_string()));
+        assert_eq!(Some(string("value42")), map.get(&"key42".to_string()));
+
+        // The structure doesn't depend on the insert order
+        let mut reversed: Hamt<String, String> = Hamt::new();
+        for i in (0..200).rev() {
+            reversed = reversed.insert(string(&format!("key{}", i)), string(&format!("value{}", i)));
+        }
+        assert_eq!(map.as_hash(), reversed.as_hash());
+
+        let mut removed = map.clone();
+        for i in 10..200 {
+            removed = removed.remove(&format!("key{}", i));
+        }
+        let mut small: Hamt<String, String> = Hamt::new();
+        for i in 0..10 {
+            small = small.insert(string(&format!("key{}", i)), string(&format!("value{}", i)));
+        }
+        assert_eq!(10, removed.len());
+        assert_eq!(small.as_hash(), removed.as_hash());
+    }
+
+    #[test]
+    fn test_stored() {
+        remove_dir_all("./unittest/hamttest/").ok();
+        let hash_io = HashIOFile::new("unittest/hamttest".to_string());
+        let mut map: Hamt<String, String> = Hamt::new();
+        for i in 0..100 {
+            map = map.insert(string(&format!("key{}", i)), string(&format!("value{}", i)));
+        }
+        let root = map.as_hash();
+        hash_io.put(Rc::new(map.clone())).unwrap();
+
+        let loaded: Rc<Hamt<String, String>> = hash_io.get(&root).unwrap();
+        assert_eq!(100, loaded.len());
+        assert_eq!(root, loaded.as_hash());
+        let mut pending: Vec<Rc<HashIOType>> = vec![loaded.clone()];
+        let mut keys = 0;
+        while let Some(node) = pending.pop() {
+            for (name, child) in node.childs() {
+                if name.ends_with("#key") {
+                    assert!(hash_io.get::<String>(&child.as_hash()).is_ok());
+                    keys += 1;
+                } else if name.starts_with('#') {
+                    pending.push(child);
+                }
+            }
+        }
+        assert_eq!(100, keys);
+        assert_eq!(Some(string("value7")), loaded.get(&"key7".to_string()));
+
+        assert_eq!(Some(string("value7")),
+                   Hamt::<String, String>::lookup(&hash_io, &root, &"key7".to_string()).unwrap());
+        assert_eq!(None,
+                   Hamt::<String, String>::lookup(&hash_io, &root, &"nope".to_string()).unwrap());
+
+        // Updating the stored map gives the same result as the in-memory map
+        let new_root = Hamt::insert_into(&hash_io, &root, string("key7"), string("seven")).unwrap();
+        assert_eq!(map.insert(string("key7"), string("seven")).as_hash(), new_root);
+        let new_root = Hamt::insert_into(&hash_io, &new_root, string("new"), string("entry")).unwrap();
+        let new_root = Hamt::<String, String>::remove_from(&hash_io, &new_root,
+                                                           &"key8".to_string()).unwrap();
+        let expected = map.insert(string("key7"), string("seven"))
+            .insert(string("new"), string("entry"))
+            .remove(&"key8".to_string());
+        assert_eq!(expected.as_hash(), new_root);
+
+        let loaded: Rc<Hamt<String, String>> = hash_io.get(&new_root).unwrap();
+        assert_eq!(100, loaded.len());
+        assert_eq!(Some(string("seven")), loaded.get(&"key7".to_string()));
+        assert_eq!(None, loaded.get(&"key8".to_string()));
+    }
+}
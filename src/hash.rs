@@ -66,6 +66,12 @@ fn hex_str_to_u8(byte: u8) -> u8 {
 
 
 
+/// Returns true if the string only contains lower case hex digits like
+/// the strings of `Hash::as_string`.
+pub fn is_hex(string: &str) -> bool {
+    string.chars().all(|c| c.is_digit(16) && !c.is_uppercase())
+}
+
 fn byte_to_string(byte: u8) -> String {
     let mut res = String::new();
     res.push_str(&half_byte_to_string(byte / 16));
@@ -143,6 +149,37 @@ impl Hash {
     }
 }
 
+/// Calculates the hash of data which arrives in parts.
+///
+/// The result is the same as `Hash::hash_bytes` of all parts together.
+pub struct Hasher {
+    sha3: Sha3
+}
+
+impl Default for Hasher {
+    fn default() -> Hasher {
+        Hasher::new()
+    }
+}
+
+impl Hasher {
+    pub fn new() -> Hasher {
+        Hasher { sha3: Sha3::sha3_256() }
+    }
+
+    pub fn input(&mut self, bytes: &[u8]) {
+        self.sha3.input(bytes);
+    }
+
+    /// Returns the hash of the input so far and starts over.
+    pub fn result(&mut self) -> Hash {
+        let mut res = [0u8; 32];
+        self.sha3.result(&mut res);
+        self.sha3.reset();
+        Hash::Sha3(res)
+    }
+}
+
 /// Can generate a hash type which represents the current type.
 pub trait Hashable {
     fn as_hash(&self) -> Hash;
@@ -20,7 +20,9 @@ extern crate byteorder;
 use std::io::{Read, Write};
 use std::{io, error, fmt};
 use hash::*;
-use std::collections::BTreeMap;
+use io::{DEFAULT_MAX_BYTES_LEN, write_u32, write_hash};
+use format::{HEADER_LEN, split_header};
+use std::collections::{BTreeMap, BTreeSet};
 use std::result;
 use std::rc::Rc;
 use std::fmt::Debug;
@@ -33,10 +35,109 @@ pub enum HashIOError {
     TypeError(Hash),
     IOError(io::Error),
     ParseError(Box<error::Error>),
-    FallbackNotSupported
+    FallbackNotSupported,
+    /// A limit was exceeded.  Contains the name of the limit and the value.
+    LimitExceeded(String, usize),
+    /// The object references itself directly or indirectly.
+    CycleDetected(Hash),
+    /// No object is stored for the hash.
+    NotFound(Hash),
+    /// The content doesn't match the hash it is stored with.
+    HashMismatch(Hash),
+    /// The object is pinned and must not be removed.
+    Pinned(Hash),
+    /// The object is still referenced by the listed objects.
+    Referenced(Hash, Vec<Hash>),
+    /// Error while loading an object with information where it occurred.
+    Object(Box<ObjectError>)
 }
 pub type Result<T> = result::Result<T, HashIOError>;
 
+/// Context of an error which occurred while loading an object.
+#[derive(Debug)]
+pub struct ObjectError {
+    /// Hash of the object which failed to load.
+    pub hash: Hash,
+    /// Type of the object which failed to load.
+    pub type_name: String,
+    /// Type of the object which was requested in the first place.
+    pub root_type: String,
+    /// Fields and items from the requested object to the failed one.
+    pub field_path: Vec<String>,
+    pub cause: HashIOError
+}
+
+impl ObjectError {
+    /// Path to the failed object like `TaskStrage.tasks[3].title`.
+    pub fn path(&self) -> String {
+        let mut res = self.root_type.clone();
+        for segment in self.field_path.iter() {
+            res.push_str(segment);
+        }
+        res
+    }
+}
+
+impl HashIOError {
+    /// Adds the loaded object to the error.
+    ///
+    /// The first call sets the object which failed, further calls while
+    /// the error is passed up set the requested type the path starts from.
+    pub fn in_object(self, hash: &Hash, type_name: String) -> HashIOError {
+        match self {
+            HashIOError::Object(mut object_error) => {
+                if object_error.hash == Hash::None {
+                    object_error.hash = *hash;
+                    object_error.type_name = type_name.clone();
+                }
+                object_error.root_type = type_name;
+                HashIOError::Object(object_error)
+            },
+            err => HashIOError::Object(Box::new(ObjectError {
+                hash: *hash,
+                type_name: type_name.clone(),
+                root_type: type_name,
+                field_path: Vec::new(),
+                cause: err
+            }))
+        }
+    }
+
+    /// Adds the field in which the error occurred to the path.
+    pub fn in_field(self, field: &str) -> HashIOError {
+        self.in_segment(format!(".{}", field))
+    }
+
+    /// Adds the collection item in which the error occurred to the path.
+    pub fn in_item<D>(self, key: D) -> HashIOError where D: fmt::Display {
+        self.in_segment(format!("[{}]", key))
+    }
+
+    fn in_segment(self, segment: String) -> HashIOError {
+        match self {
+            HashIOError::Object(mut object_error) => {
+                object_error.field_path.insert(0, segment);
+                HashIOError::Object(object_error)
+            },
+            err => HashIOError::Object(Box::new(ObjectError {
+                hash: Hash::None,
+                type_name: String::new(),
+                root_type: String::new(),
+                field_path: vec![segment],
+                cause: err
+            }))
+        }
+    }
+
+    /// Returns the error without the object context.
+    pub fn root_cause(&self) -> &HashIOError {
+        match *self {
+            HashIOError::Object(ref object_error) => object_error.cause.root_cause(),
+            ref err => err
+        }
+    }
+}
+
 impl fmt::Display for HashIOError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -45,7 +146,20 @@ impl fmt::Display for HashIOError {
             HashIOError::TypeError(ref hash) => write!(f, "Unexpected type: {}", hash.as_string()),
             HashIOError::IOError(ref err) => write!(f, "IOError: {}", err),
             HashIOError::ParseError(ref err) => write!(f, "Parse error: {}", err),
-            HashIOError::FallbackNotSupported => write!(f, "Fallback is not supported")
+            HashIOError::FallbackNotSupported => write!(f, "Fallback is not supported"),
+            HashIOError::LimitExceeded(ref limit, value) =>
+                write!(f, "Limit {} exceeded: {}", limit, value),
+            HashIOError::CycleDetected(ref hash) => write!(f, "Cycle detected: {}", hash.as_string()),
+            HashIOError::NotFound(ref hash) => write!(f, "Object not found: {}", hash.as_string()),
+            HashIOError::HashMismatch(ref hash) =>
+                write!(f, "Content doesn't match the hash: {}", hash.as_string()),
+            HashIOError::Pinned(ref hash) =>
+                write!(f, "Object is pinned: {}", hash.as_string()),
+            HashIOError::Referenced(ref hash, ref parents) =>
+                write!(f, "Object {} is referenced by {} objects", hash.as_string(), parents.len()),
+            HashIOError::Object(ref object_error) =>
+                write!(f, "Failed to load {} {} at {}: {}", object_error.type_name,
+                       object_error.hash.as_string(), object_error.path(), object_error.cause)
         }
     }
 }
@@ -57,7 +171,14 @@ impl error::Error for HashIOError {
             HashIOError::TypeError(_) => "Unexpected type",
             HashIOError::IOError(ref err) => err.description(),
             HashIOError::ParseError(ref err) => err.description(),
-            HashIOError::FallbackNotSupported => "Fallback is not supported"
+            HashIOError::FallbackNotSupported => "Fallback is not supported",
+            HashIOError::LimitExceeded(_, _) => "Limit exceeded",
+            HashIOError::CycleDetected(_) => "Cycle detected",
+            HashIOError::NotFound(_) => "Object not found",
+            HashIOError::HashMismatch(_) => "Content doesn't match the hash",
+            HashIOError::Pinned(_) => "Object is pinned",
+            HashIOError::Referenced(_, _) => "Object is referenced",
+            HashIOError::Object(ref object_error) => object_error.cause.description()
         }
     }
 }
@@ -92,6 +213,22 @@ pub trait HashIOType: Hashable + Debug {
         BTreeMap::new()
     }
 
+    /// Hashes of the children, including children which are not loaded.
+    ///
+    /// Types which only load their children on demand override this, so
+    /// analytics can follow all references.
+    fn child_hashes(&self) -> BTreeMap<String, Hash> {
+        self.childs().iter().map(|(name, child)| (name.clone(), child.as_hash())).collect()
+    }
+
+    /// Hash of the attributes which are stored beside the children, None
+    /// if the type doesn't provide it.
+    ///
+    /// `diff` uses it to tell if a node changed itself or only below.
+    fn attributes_hash(&self) -> Option<Hash> {
+        None
+    }
+
     fn type_hash_obj(&self) -> Hash;
     fn type_name_obj(&self) -> String;
 }
@@ -123,6 +260,152 @@ pub trait HashIOParse: HashIOType + Typeable {
     fn type_hash_valid(_: &Hash) -> bool {
         false
     }
+
+    /// Type hashes of older type hash schemes of this type.
+    ///
+    /// Data stored with them has the same layout as the current type.
+    fn legacy_type_hashes() -> Vec<Hash> {
+        Vec::new()
+    }
+
+    /// Lists the names and type hashes of all older types this type
+    /// can be migrated from, including the fallbacks of the fallbacks.
+    fn fallback_types() -> Vec<(String, Hash)> {
+        Vec::new()
+    }
+
+    /// Reads the hashes of the children from the payload of a stored
+    /// object without loading them.
+    ///
+    /// Returns None if the type doesn't know its layout.
+    fn payload_references(_: &[u8], _: &Option<Hash>) -> Result<Option<Vec<Hash>>> {
+        Ok(None)
+    }
+}
+
+
+/// Limits which are enforced while parsing untrusted data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// Maximum number of entries in a collection.
+    pub max_collection_len: usize,
+    /// Maximum size of a string or binary data in bytes.
+    pub max_string_len: usize,
+    /// Maximum number of nested `get` calls.
+    pub max_depth: usize
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_collection_len: 1024 * 1024,
+            max_string_len: DEFAULT_MAX_BYTES_LEN,
+            max_depth: 512
+        }
+    }
+}
+
+impl Limits {
+    pub fn check_collection_len(&self, len: usize) -> Result<()> {
+        if len > self.max_collection_len {
+            return Err(HashIOError::LimitExceeded("max_collection_len".to_string(), len))
+        }
+        Ok(())
+    }
+
+    pub fn check_string_len(&self, len: usize) -> Result<()> {
+        if len > self.max_string_len {
+            return Err(HashIOError::LimitExceeded("max_string_len".to_string(), len))
+        }
+        Ok(())
+    }
+
+    pub fn check_depth(&self, depth: usize) -> Result<()> {
+        if depth > self.max_depth {
+            return Err(HashIOError::LimitExceeded("max_depth".to_string(), depth))
+        }
+        Ok(())
+    }
+}
+
+
+/// Stored object without knowing its type.
+///
+/// The payload is the data the hash is calculated from, the header is
+/// written in front of it unless the type uses the unsafe loader.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawObject {
+    pub hash: Hash,
+    /// Version and type hash.
+    pub header: Option<(u32, Hash)>,
+    pub payload: Vec<u8>
+}
+
+impl RawObject {
+    /// Creates the object and calculates its hash.
+    pub fn new(header: Option<(u32, Hash)>, payload: Vec<u8>) -> RawObject {
+        RawObject {
+            hash: Hash::hash_bytes(&payload),
+            header: header,
+            payload: payload
+        }
+    }
+
+    /// Splits the file content of an object into header and payload.
+    pub fn from_file(hash: &Hash, data: &[u8]) -> Result<RawObject> {
+        match split_header(hash, data) {
+            Some((header, payload)) => Ok(RawObject {
+                hash: *hash,
+                header: header,
+                payload: payload.to_vec()
+            }),
+            None => Err(HashIOError::HashMismatch(*hash))
+        }
+    }
+
+    /// The file content with the header in front of the payload.
+    pub fn to_file(&self) -> Vec<u8> {
+        let mut res: Vec<u8> = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        if let Some((version, ref type_hash)) = self.header {
+            write_u32(version, &mut res).unwrap();
+            write_hash(type_hash, &mut res).unwrap();
+        }
+        res.extend_from_slice(&self.payload);
+        res
+    }
+
+    /// Size of the file content.
+    pub fn file_len(&self) -> usize {
+        match self.header {
+            Some(_) => HEADER_LEN + self.payload.len(),
+            None => self.payload.len()
+        }
+    }
+
+    pub fn version(&self) -> Option<u32> {
+        self.header.map(|(version, _)| version)
+    }
+
+    pub fn type_hash(&self) -> Option<Hash> {
+        self.header.map(|(_, type_hash)| type_hash)
+    }
+
+    /// Returns an error if the payload doesn't match the hash.
+    pub fn verify(&self) -> Result<()> {
+        if Hash::hash_bytes(&self.payload) != self.hash {
+            return Err(HashIOError::HashMismatch(self.hash))
+        }
+        Ok(())
+    }
+}
+
+
+/// Object safe read access to the stored objects of a backend.
+///
+/// Types which load their children on demand keep it, so they can copy
+/// their children into another store.
+pub trait RawSource {
+    fn load_raw(&self, hash: &Hash) -> Result<RawObject>;
 }
 
 
@@ -132,6 +415,76 @@ pub trait HashIO {
                 where T: HashIOParse;
     fn put<T>(&self, item: Rc<T>) -> Result<()>
                 where T: HashIOParse;
+
+    /// Loads the object without parsing it.
+    fn get_raw(&self, hash: &Hash) -> Result<RawObject>;
+
+    /// Stores the object byte for byte after its hash was verified.
+    ///
+    /// Unlike `put`, the children are not stored.
+    fn put_raw(&self, object: &RawObject) -> Result<()>;
+
+    /// Stores the object like `put_raw`, but overwrites a stored object
+    /// with the same hash, so its header can be updated.
+    fn replace_raw(&self, object: &RawObject) -> Result<()> {
+        Err(HashIOError::Undefined(
+            format!("Cannot replace the stored object {}", object.hash.as_string())))
+    }
+
+    /// Returns true if the object is stored, without loading it.
+    fn contains(&self, hash: &Hash) -> Result<bool>;
+
+    /// Returns the hashes which are not stored, in the given order.
+    ///
+    /// Backends where every check is expensive should answer all hashes at
+    /// once.
+    fn missing(&self, hashes: &[Hash]) -> Result<Vec<Hash>> {
+        let mut res: Vec<Hash> = Vec::new();
+        for hash in hashes {
+            if !try!(self.contains(hash)) {
+                res.push(*hash);
+            }
+        }
+        Ok(res)
+    }
+
+    /// Limits which parse functions have to respect.
+    fn limits(&self) -> Limits {
+        Limits::default()
+    }
+
+    /// Handle to load objects from this store later on.
+    ///
+    /// Returns None if the backend cannot provide one.
+    fn raw_source(&self) -> Option<Rc<RawSource>> {
+        None
+    }
+}
+
+/// Stores which are able to remove objects.
+///
+/// Pinned objects and all objects below them are protected, neither
+/// `remove` nor a garbage collection deletes them.
+pub trait HashIORemove: HashIO {
+    /// Returns the pinned objects.
+    fn pins(&self) -> Result<BTreeSet<Hash>>;
+
+    /// Pins a stored object.  Returns false if it was already pinned.
+    fn pin(&self, hash: &Hash) -> Result<bool>;
+
+    /// Unpins an object.  Returns false if it wasn't pinned.
+    fn unpin(&self, hash: &Hash) -> Result<bool>;
+
+    fn is_pinned(&self, hash: &Hash) -> Result<bool> {
+        Ok(try!(self.pins()).contains(hash))
+    }
+
+    /// Deletes an object.  Returns false if it didn't exist.
+    ///
+    /// Fails with `Pinned` for pinned objects and their children.  Objects
+    /// which are referenced by other stored objects fail with `Referenced`,
+    /// unless force is set, which leaves the parents incomplete.
+    fn remove(&self, hash: &Hash, force: bool) -> Result<bool>;
 }
 
 
@@ -49,6 +49,126 @@ macro_rules! hashio_gen_writable {
 }
 
 
+/// Calculates the type hash of a model.
+///
+/// Without a scheme, only the primitive types and the child type hashes
+/// are used, which is the original (v1) behaviour.  Field names and the
+/// field order are not part of the v1 hash, so renaming or swapping two
+/// fields of the same type keeps the hash.
+///
+/// The v2 scheme additionally includes the field names and their order
+/// and can be selected with `type_hash => v2` in `hashio_type!`.
+#[macro_export]
+macro_rules! hashio_gen_type_hash {
+    ($model_name:ident {
+        $($attr_type:ty),*
+    } {
+        $($hash_type:ty),*
+    }) => {{
+        trace!(target: "Typeable", "{}::type_hash()", stringify!($model_name));
+        let mut byte_gen: Vec<u8> = Vec::new();
+        $(
+            {
+                let type_string = stringify!($attr_type);
+                let type_bytes = type_string.as_bytes();
+                let type_hash = Hash::hash_bytes(type_bytes);
+                byte_gen.extend_from_slice(&*type_hash.get_bytes());
+            };
+        )*
+        $(
+            {
+                let type_hash: Hash = <$hash_type>::type_hash();
+                byte_gen.extend_from_slice(&*type_hash.get_bytes());
+            };
+        )*
+        let hash = Hash::hash_bytes(byte_gen.as_slice());
+        trace!(target: "Typeable", "{}::type_hash => {}",
+            stringify!($model_name), hash.as_string());
+        hash
+    }};
+    ($model_name:ident [] {
+        $($attr_name:ident : $attr_type:ty),*
+    } {
+        $($hash_name:ident : $hash_type:ty),*
+    }) => {
+        hashio_gen_type_hash!($model_name {
+            $($attr_type),*
+        } {
+            $($hash_type),*
+        })
+    };
+    ($model_name:ident [v1] {
+        $($attr_name:ident : $attr_type:ty),*
+    } {
+        $($hash_name:ident : $hash_type:ty),*
+    }) => {
+        hashio_gen_type_hash!($model_name {
+            $($attr_type),*
+        } {
+            $($hash_type),*
+        })
+    };
+    ($model_name:ident [v2] {
+        $($attr_name:ident : $attr_type:ty),*
+    } {
+        $($hash_name:ident : $hash_type:ty),*
+    }) => {{
+        trace!(target: "Typeable", "{}::type_hash() (v2)", stringify!($model_name));
+        let mut byte_gen: Vec<u8> = Vec::new();
+        byte_gen.extend_from_slice(&*Hash::hash_string("hashio-type-v2".to_string()).get_bytes());
+        $(
+            {
+                let field_string = format!("field {}: {}",
+                    stringify!($attr_name), stringify!($attr_type));
+                byte_gen.extend_from_slice(&*Hash::hash_string(field_string).get_bytes());
+            };
+        )*
+        $(
+            {
+                let child_string = format!("child {}", stringify!($hash_name));
+                byte_gen.extend_from_slice(&*Hash::hash_string(child_string).get_bytes());
+                let type_hash: Hash = <$hash_type>::type_hash();
+                byte_gen.extend_from_slice(&*type_hash.get_bytes());
+            };
+        )*
+        let hash = Hash::hash_bytes(byte_gen.as_slice());
+        trace!(target: "Typeable", "{}::type_hash => {} (v2)",
+            stringify!($model_name), hash.as_string());
+        hash
+    }};
+}
+
+
+/// Collects the type hashes of the given legacy schemes of a model.
+#[macro_export]
+macro_rules! hashio_gen_legacy_hashes {
+    ($model_name:ident [] {
+        $($attr_name:ident : $attr_type:ty),*
+    } {
+        $($hash_name:ident : $hash_type:ty),*
+    }) => {
+        Vec::<Hash>::new()
+    };
+    ($model_name:ident [$scheme:ident $($rest:ident)*] {
+        $($attr_name:ident : $attr_type:ty),*
+    } {
+        $($hash_name:ident : $hash_type:ty),*
+    }) => {{
+        let mut hashes: Vec<Hash> = hashio_gen_legacy_hashes!($model_name [$($rest)*] {
+            $($attr_name : $attr_type),*
+        } {
+            $($hash_name : $hash_type),*
+        });
+        hashes.push(hashio_gen_type_hash!($model_name [$scheme] {
+            $($attr_name : $attr_type),*
+        } {
+            $($hash_name : $hash_type),*
+        }));
+        hashes
+    }};
+}
+
+
 #[macro_export]
 macro_rules! hashio_gen_typeable {
     ($model_name:ident {
@@ -59,26 +179,32 @@ macro_rules! hashio_gen_typeable {
         // Make the type able to represent itself 
         impl Typeable for $model_name {
             fn type_hash() -> Hash {
-                trace!(target: "Typeable", "{}::type_hash()", stringify!($model_name));
-                let mut byte_gen: Vec<u8> = Vec::new();
-                $(
-                    {
-                        let type_string = stringify!($attr_type);
-                        let type_bytes = type_string.as_bytes();
-                        let type_hash = Hash::hash_bytes(type_bytes);
-                        byte_gen.extend_from_slice(&*type_hash.get_bytes());
-                    };
-                )*
-                $(
-                    {
-                        let type_hash: Hash = <$hash_type>::type_hash();
-                        byte_gen.extend_from_slice(&*type_hash.get_bytes());
-                    };
-                )*
-                let hash = Hash::hash_bytes(byte_gen.as_slice());
-                trace!(target: "Typeable", "{}::type_hash => {}",
-                    stringify!($model_name), hash.as_string());
-                hash
+                hashio_gen_type_hash!($model_name {
+                    $($attr_type),*
+                } {
+                    $($hash_type),*
+                })
+            }
+
+            fn type_name() -> String {
+                stringify!($model_name).to_string()
+            }
+        }
+    };
+    ($model_name:ident [$($scheme:ident)*] {
+        $($attr_name:ident : $attr_type:ty),*
+    } {
+        $($hash_name:ident : $hash_type:ty),*
+    }) => {
+        // Make the type able to represent itself using the given
+        // type hash scheme
+        impl Typeable for $model_name {
+            fn type_hash() -> Hash {
+                hashio_gen_type_hash!($model_name [$($scheme)*] {
+                    $($attr_name : $attr_type),*
+                } {
+                    $($hash_name : $hash_type),*
+                })
             }
 
             fn type_name() -> String {
@@ -93,6 +219,30 @@ macro_rules! hashio_gen_typeable {
 macro_rules! hashio_gen_hashiotype {
     ($model_name:ident {
         $($hash_name:ident),*
+    }) => {
+        hashio_gen_hashiotype!(@impl $model_name { $($hash_name),* } {});
+    };
+    ($model_name:ident {
+        $($attr_name:ident : $attr_write_fn:ident),*
+    } {
+        $($hash_name:ident),*
+    }) => {
+        hashio_gen_hashiotype!(@impl $model_name { $($hash_name),* } {
+            fn attributes_hash(&self) -> Option<Hash> {
+                let data: &mut Vec<u8> = &mut Vec::new();
+                $(
+                    if $attr_write_fn(self.$attr_name, data).is_err() {
+                        return None
+                    }
+                )*
+                Some(Hash::hash_bytes(data))
+            }
+        });
+    };
+    (@impl $model_name:ident {
+        $($hash_name:ident),*
+    } {
+        $($attributes_hash:tt)*
     }) => {
         impl HashIOType for $model_name {
             fn childs(&self) -> BTreeMap<String, Rc<HashIOType>> {
@@ -106,6 +256,8 @@ macro_rules! hashio_gen_hashiotype {
                 res
             }
 
+            $($attributes_hash)*
+
             fn type_hash_obj(&self) -> Hash {
                 $model_name::type_hash()
             }
@@ -125,6 +277,7 @@ macro_rules! hashio_gen_hashioparse {
         } {
             $($hash_name:ident : $hash_type:ty),*
         }
+        $(legacy_fallback => $legacy_scheme:ident)*
         $(fallback => $fallback_type:ident)*
         $(plain_fallback => $plain_fallback_fn:ident)*
 
@@ -136,21 +289,31 @@ macro_rules! hashio_gen_hashioparse {
                     Err(HashIOError::Undefined("None type received".to_string()))
                 } else {
                     let unwrappled_type_hash = type_hash.unwrap();
-                    if unwrappled_type_hash == $model_name::type_hash() {
+                    // Data stored with a legacy type hash scheme of this model has
+                    // the same layout and can be read directly.
+                    if unwrappled_type_hash == $model_name::type_hash() ||
+                            $model_name::legacy_type_hashes().contains(&unwrappled_type_hash) {
                         $(
-                            let $attr_name: $attr_type = try!($attr_read_fn(read));
+                            let $attr_name: $attr_type = try!($attr_read_fn(read)
+                                .map_err(|err| HashIOError::from(err)
+                                         .in_field(stringify!($attr_name))));
                         )*
                         $(
                             let $hash_name: Rc<$hash_type> = {
-                                let hash = try!(read_hash(read));
-                                try!(hash_io.get(&hash))
+                                let hash = try!(read_hash(read)
+                                    .map_err(|err| HashIOError::from(err)
+                                             .in_field(stringify!($hash_name))));
+                                try!(hash_io.get(&hash)
+                                    .map_err(|err| err.in_field(stringify!($hash_name))))
                             };
                         )*
                         Ok(Rc::new($model_name {
                             $($attr_name: $attr_name,)*
                             $($hash_name: $hash_name),*
                         }))
-                    } $( else if unwrappled_type_hash == $fallback_type::type_hash() {
+                    } $( else if $fallback_type::type_hash_valid(&unwrappled_type_hash) {
+                        // The fallback type is able to read the data, maybe through its own
+                        // fallbacks, so the data is migrated step by step.
                         let fallback_obj = try!($fallback_type::parse(hash_io, read, type_hash));
                         Ok(Rc::new($model_name::from(fallback_obj)))
                     })* else {
@@ -181,17 +344,126 @@ macro_rules! hashio_gen_hashioparse {
             fn type_hash_valid(hash: &Hash) -> bool {
                 if *hash == $model_name::type_hash() {
                     true
-                } $(else if *hash == $fallback_type::type_hash() {
+                } else if $model_name::legacy_type_hashes().contains(hash) {
+                    true
+                } $(else if $fallback_type::type_hash_valid(hash) {
                     true
                 })* else {
                     false
                 }
-            }            
+            }
+
+            /// Computed once per thread, since parsing checks them for every
+            /// object with an outdated header.
+            fn legacy_type_hashes() -> Vec<Hash> {
+                thread_local! {
+                    static LEGACY_HASHES: Vec<Hash> = hashio_gen_legacy_hashes!($model_name
+                        [$($legacy_scheme)*] {
+                            $($attr_name : $attr_type),*
+                        } {
+                            $($hash_name : $hash_type),*
+                        });
+                }
+                LEGACY_HASHES.with(|hashes| hashes.clone())
+            }
+
+            fn fallback_types() -> Vec<(String, Hash)> {
+                let mut res: Vec<(String, Hash)> = Vec::new();
+                let legacy_schemes: Vec<&str> = vec![$(stringify!($legacy_scheme)),*];
+                let legacy_hashes = $model_name::legacy_type_hashes();
+                for (scheme, hash) in legacy_schemes.iter().rev().zip(legacy_hashes) {
+                    res.push((format!("{} ({})", $model_name::type_name(), scheme), hash));
+                }
+                $(
+                    res.push(($fallback_type::type_name(), $fallback_type::type_hash()));
+                    res.extend($fallback_type::fallback_types());
+                )*
+                res
+            }
+
+            fn payload_references(payload: &[u8], type_hash: &Option<Hash>)
+                    -> Result<Option<Vec<Hash>>> {
+                let unwrappled_type_hash = match *type_hash {
+                    Some(hash) => hash,
+                    None => return Ok(None)
+                };
+                if unwrappled_type_hash == $model_name::type_hash() ||
+                        $model_name::legacy_type_hashes().contains(&unwrappled_type_hash) {
+                    let mut read: &[u8] = payload;
+                    $(
+                        let _: $attr_type = try!($attr_read_fn(&mut read));
+                    )*
+                    $(
+                        let $hash_name = try!(read_hash(&mut read));
+                    )*
+                    Ok(Some(vec![$($hash_name),*]))
+                } $( else if $fallback_type::type_hash_valid(&unwrappled_type_hash) {
+                    $fallback_type::payload_references(payload, type_hash)
+                })* else {
+                    Err(HashIOError::TypeError(unwrappled_type_hash))
+                }
+            }
+        }
+    }
+}
+
+
+/// Lets the model describe itself, see the schema module.
+#[macro_export]
+macro_rules! hashio_gen_schema {
+    ($model_name:ident [$($scheme:ident)*] {
+        $($attr_name:ident : $attr_type:ty, $attr_read_fn:ident, $attr_write_fn:ident),*
+    } {
+        $($hash_name:ident : $hash_type:ty),*
+    }) => {
+        impl $crate::schema::HashIOSchema for $model_name {
+            fn schema() -> $crate::schema::TypeSchema {
+                let schemes: Vec<&str> = vec![$(stringify!($scheme)),*];
+                $crate::schema::TypeSchema {
+                    name: $model_name::type_name(),
+                    type_hash: $model_name::type_hash(),
+                    scheme: schemes.first().unwrap_or(&"v1").to_string(),
+                    fields: vec![$(
+                        $crate::schema::FieldSchema {
+                            name: stringify!($attr_name).to_string(),
+                            type_name: stringify!($attr_type).to_string(),
+                            read_fn: stringify!($attr_read_fn).to_string(),
+                            write_fn: stringify!($attr_write_fn).to_string(),
+                        }
+                    ),*],
+                    childs: vec![$(
+                        $crate::schema::ChildSchema {
+                            name: stringify!($hash_name).to_string(),
+                            type_name: <$hash_type>::type_name(),
+                            type_hash: <$hash_type>::type_hash(),
+                        }
+                    ),*],
+                    fallbacks: $model_name::fallback_types().into_iter()
+                        .map(|(type_name, type_hash)| $crate::schema::FallbackSchema {
+                            type_name: type_name,
+                            type_hash: type_hash,
+                        })
+                        .collect(),
+                }
+            }
         }
     }
 }
 
 
+/// Generates a complete HashIO model.
+///
+/// The first block contains the primitive attributes with their read and
+/// write functions, the second block the HashIO children.  The following
+/// optional settings can be appended in this order:
+///
+/// * `type_hash => v2`:  Use the type hash scheme which includes field names
+///   and their order.
+/// * `legacy_fallback => v1`:  Also accept data stored with the given type hash
+///   scheme of this model, which is useful after switching to `v2`.
+/// * `fallback => OldType`:  Accept data of an older type and convert it using
+///   `From<Rc<OldType>>`.
+/// * `plain_fallback => function`:  Parse data with an unknown version.
 #[macro_export]
 macro_rules! hashio_type {
         ($model_name:ident {
@@ -199,6 +471,8 @@ macro_rules! hashio_type {
         } {
             $($hash_name:ident : $hash_type:ty),*
         }
+        $(type_hash => $scheme:ident)*
+        $(legacy_fallback => $legacy_scheme:ident)*
         $(fallback => $fallback_type:ident)*
         $(plain_fallback => $plain_fallback_fn:ident)*
 
@@ -221,15 +495,17 @@ macro_rules! hashio_type {
         hashable_for_writable!($model_name);
 
         hashio_gen_typeable! {
-            $model_name {
-                $($attr_type),*
+            $model_name [$($scheme)*] {
+                $($attr_name : $attr_type),*
             } {
-                $($hash_type),*
+                $($hash_name : $hash_type),*
             }
         }
 
         hashio_gen_hashiotype! {
             $model_name {
+                $($attr_name : $attr_write_fn),*
+            } {
                 $($hash_name),*
             }
         }
@@ -241,9 +517,18 @@ macro_rules! hashio_type {
             } {
                 $($hash_name : $hash_type),*
             }
+            $(legacy_fallback => $legacy_scheme)*
             $(fallback => $fallback_type)*
             $(plain_fallback => $plain_fallback_fn)*
         }
+
+        hashio_gen_schema! {
+            $model_name [$($scheme)*] {
+                $($attr_name : $attr_type, $attr_read_fn, $attr_write_fn),*
+            } {
+                $($hash_name : $hash_type),*
+            }
+        }
     }
 }
 
@@ -308,6 +593,101 @@ mod test {
 }
 
 
+#[cfg(test)]
+mod test_type_hash_v2 {
+    use super::super::io::*;
+    use super::super::hashio::*;
+    use std::io::{Read, Write};
+    use std::io;
+    use hash::*;
+    use std::collections::BTreeMap;
+    use std::result;
+    use std::rc::Rc;
+    use hashiofile::HashIOFile;
+    use std::fs::remove_dir_all;
+
+    hashio_type! {
+        PointV1 {
+            x: u32, read_u32, write_u32,
+            y: u32, read_u32, write_u32
+        } {
+            label: String
+        }
+    }
+    hashio_type! {
+        Point {
+            x: u32, read_u32, write_u32,
+            y: u32, read_u32, write_u32
+        } {
+            label: String
+        }
+        type_hash => v2
+        legacy_fallback => v1
+    }
+    hashio_type! {
+        PointSwapped {
+            y: u32, read_u32, write_u32,
+            x: u32, read_u32, write_u32
+        } {
+            label: String
+        }
+        type_hash => v2
+    }
+    hashio_type! {
+        PointRenamed {
+            x: u32, read_u32, write_u32,
+            z: u32, read_u32, write_u32
+        } {
+            label: String
+        }
+        type_hash => v2
+    }
+    hashio_type! {
+        PointSwappedV1 {
+            y: u32, read_u32, write_u32,
+            x: u32, read_u32, write_u32
+        } {
+            label: String
+        }
+    }
+
+    #[test]
+    fn field_names_change_type_hash() {
+        // v1 ignores the names so swapping fields keeps the hash
+        assert_eq!(PointV1::type_hash(), PointSwappedV1::type_hash());
+
+        assert!(Point::type_hash() != PointV1::type_hash());
+        assert!(Point::type_hash() != PointSwapped::type_hash());
+        assert!(Point::type_hash() != PointRenamed::type_hash());
+        assert!(PointSwapped::type_hash() != PointRenamed::type_hash());
+    }
+
+    #[test]
+    fn legacy_fallback() {
+        remove_dir_all("./unittest/typehashv2test/").ok();
+        let hash_io = HashIOFile::new("unittest/typehashv2test".to_string());
+        let old = PointV1 {
+            x: 1,
+            y: 2,
+            label: Rc::new("a".to_string())
+        };
+        let hash = old.as_hash();
+        hash_io.put(Rc::new(old)).unwrap();
+
+        assert!(Point::type_hash_valid(&PointV1::type_hash()));
+        assert_eq!(vec![("Point (v1)".to_string(), PointV1::type_hash())],
+                   Point::fallback_types());
+        let point: Rc<Point> = hash_io.get(&hash).unwrap();
+        assert_eq!(1, point.x);
+        assert_eq!(2, point.y);
+        assert_eq!(Rc::new("a".to_string()), point.label);
+
+        // Without a legacy fallback, the old data is rejected
+        assert!(hash_io.get::<PointSwapped>(&hash).is_err());
+    }
+}
+
+
 #[cfg(test)]
 mod test2 {
     use super::super::io::*;
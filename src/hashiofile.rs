@@ -1,20 +1,78 @@
 extern crate crypto;
 extern crate byteorder;
+extern crate memmap;
 
+use format::*;
 use hash::*;
 use hashio::*;
+use inspect::{reachable, referrers};
 use io::*;
-use std::fs::{File, create_dir_all};
+use references::ReferenceDecoder;
+use std::cell::RefCell;
+use std::fmt;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions, create_dir_all, read_dir, remove_dir_all, remove_file};
+use std::io;
+use std::io::{Read, Write};
 use std::path::Path;
 use std::fs::rename;
 use std::rc::Rc;
 use log::{LogLevelFilter, max_log_level};
+use self::memmap::{Mmap, Protection};
 
 
 /// Structure to store and lead HashIO-able values
-#[derive(Clone, Debug, PartialEq)]
 pub struct HashIOFile {
     pub base_path: String,
+    /// Map the files into memory instead of reading them with system calls.
+    pub use_mmap: bool,
+    pub limits: Limits,
+    /// Record the parents of every stored child in the reverse index.
+    pub parent_index: bool,
+    /// Finds the references of raw objects for the index, `remove` and
+    /// the garbage collection.
+    pub decoder: ReferenceDecoder,
+    /// Objects which are currently loaded by nested `get` calls.
+    loading: RefCell<Vec<Hash>>,
+    /// Objects whose children are currently stored by nested `put` calls.
+    storing: RefCell<Vec<Hash>>,
+}
+
+
+// The traversal stacks only belong to the running calls, so they are
+// neither copied nor compared.
+impl Clone for HashIOFile {
+    fn clone(&self) -> HashIOFile {
+        HashIOFile {
+            base_path: self.base_path.clone(),
+            use_mmap: self.use_mmap,
+            limits: self.limits,
+            parent_index: self.parent_index,
+            decoder: self.decoder.clone(),
+            loading: RefCell::new(Vec::new()),
+            storing: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+impl PartialEq for HashIOFile {
+    fn eq(&self, other: &HashIOFile) -> bool {
+        self.base_path == other.base_path && self.use_mmap == other.use_mmap
+            && self.limits == other.limits && self.parent_index == other.parent_index
+            && self.decoder == other.decoder
+    }
+}
+
+impl fmt::Debug for HashIOFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HashIOFile")
+            .field("base_path", &self.base_path)
+            .field("use_mmap", &self.use_mmap)
+            .field("limits", &self.limits)
+            .field("parent_index", &self.parent_index)
+            .field("decoder", &self.decoder)
+            .finish()
+    }
 }
 
 
@@ -22,9 +80,40 @@ impl HashIOFile {
     pub fn new(path: String) -> HashIOFile {
         HashIOFile {
             base_path: path.clone(),
+            use_mmap: false,
+            limits: Limits::default(),
+            parent_index: false,
+            decoder: ReferenceDecoder::new(),
+            loading: RefCell::new(Vec::new()),
+            storing: RefCell::new(Vec::new()),
         }
     }
 
+    /// Builder style setter for the parsing limits.
+    pub fn with_limits(mut self, limits: Limits) -> HashIOFile {
+        self.limits = limits;
+        self
+    }
+
+    /// Builder style setter to read the objects through memory maps.
+    pub fn with_mmap(mut self, use_mmap: bool) -> HashIOFile {
+        self.use_mmap = use_mmap;
+        self
+    }
+
+    /// Builder style setter to maintain the reverse index in `put`.
+    pub fn with_parent_index(mut self, parent_index: bool) -> HashIOFile {
+        self.parent_index = parent_index;
+        self
+    }
+
+    /// Builder style setter for the decoder which finds the references of
+    /// raw objects.  Register the model types to avoid scanning the payloads.
+    pub fn with_decoder(mut self, decoder: ReferenceDecoder) -> HashIOFile {
+        self.decoder = decoder;
+        self
+    }
+
     pub fn directory_for_hash(&self, hash: &Hash) -> String {
         let hash_str = hash.as_string();
         let mut result = String::new();
@@ -41,6 +130,275 @@ impl HashIOFile {
         result.push_str(&hash_str[2..]);
         result
     }
+
+    /// Lists the hashes of all objects in the store.
+    ///
+    /// Unfinished files which are still written are skipped.
+    pub fn hashes(&self) -> Result<Vec<Hash>> {
+        let mut res: Vec<Hash> = Vec::new();
+        if !Path::new(&self.base_path).exists() {
+            return Ok(res)
+        }
+        for dir_entry in try!(read_dir(&self.base_path)) {
+            let dir_entry = try!(dir_entry);
+            let dir_name = dir_entry.file_name().to_string_lossy().into_owned();
+            if dir_name.len() != 2 || !is_hex(&dir_name) || !try!(dir_entry.file_type()).is_dir() {
+                continue
+            }
+            for file_entry in try!(read_dir(dir_entry.path())) {
+                let file_entry = try!(file_entry);
+                let file_name = file_entry.file_name().to_string_lossy().into_owned();
+                if file_name.len() != 62 || !is_hex(&file_name) {
+                    continue
+                }
+                res.push(Hash::from_string(dir_name.clone() + &file_name));
+            }
+        }
+        res.sort();
+        Ok(res)
+    }
+
+    /// Reads the version and the type hash which is stored in front of an object.
+    ///
+    /// Types which use the unsafe loader don't store this header, in this case
+    /// the returned values are meaningless.
+    pub fn read_header(&self, hash: &Hash) -> Result<(u32, Hash)> {
+        let mut read = try!(File::open(self.filename_for_hash(hash)));
+        let version = try!(read_u32(&mut read));
+        let type_hash = try!(read_hash(&mut read));
+        Ok((version, type_hash))
+    }
+
+    /// Maps the file of the object into memory.
+    ///
+    /// The data contains the header if the type writes one.  Together with
+    /// `io::read_str`, strings can be used directly from the mapped file
+    /// without copying them.
+    pub fn map(&self, hash: &Hash) -> Result<MappedObject> {
+        let mmap = try!(Mmap::open_path(self.filename_for_hash(hash), Protection::Read)
+                        .map_err(|err| not_found_error(err, hash)));
+        Ok(MappedObject { mmap: mmap })
+    }
+
+    /// Reads the whole file content of an object including the header.
+    pub fn read_file(&self, hash: &Hash) -> Result<Vec<u8>> {
+        let mut read = try!(File::open(self.filename_for_hash(hash))
+                            .map_err(|err| not_found_error(err, hash)));
+        let mut data: Vec<u8> = Vec::new();
+        try!(read.read_to_end(&mut data));
+        Ok(data)
+    }
+
+    /// Stores the file content of an object as it is.
+    ///
+    /// The caller is responsible that the content matches the hash.  Like
+    /// `put`, existing files are not overwritten.
+    pub fn put_file(&self, hash: &Hash, data: &[u8]) -> Result<()> {
+        if !try!(self.contains(hash)) {
+            try!(self.write_file(hash, data));
+        }
+        Ok(())
+    }
+
+    /// Writes the file content of an object, even if it already exists.
+    fn write_file(&self, hash: &Hash, data: &[u8]) -> Result<()> {
+        let filename = self.filename_for_hash(hash);
+        let tmp_filename = filename.clone() + "_";
+        try!(create_dir_all(self.directory_for_hash(hash)));
+        {
+            let mut write = try!(File::create(tmp_filename.clone()));
+            try!(write.write_all(data));
+        }
+        try!(rename(tmp_filename, filename));
+        Ok(())
+    }
+
+    /// File which contains the pinned hashes, one per line.
+    pub fn pins_filename(&self) -> String {
+        let mut result = self.base_path.clone();
+        result.push_str("/pins");
+        result
+    }
+
+    /// Returns true if the object is pinned or below a pinned object.
+    ///
+    /// With the parent index, only the ancestors of the object are visited,
+    /// otherwise everything below the pins.
+    fn is_protected(&self, hash: &Hash) -> Result<bool> {
+        let pins = try!(self.pins());
+        if !self.parent_index {
+            let pins: Vec<Hash> = pins.into_iter().collect();
+            return Ok(try!(reachable(self, &pins)).contains(hash))
+        }
+        let mut visited: BTreeSet<Hash> = BTreeSet::new();
+        let mut pending: Vec<Hash> = vec![*hash];
+        while let Some(hash) = pending.pop() {
+            if pins.contains(&hash) {
+                return Ok(true)
+            }
+            if visited.insert(hash) {
+                pending.extend(try!(self.parents_of(&hash)));
+            }
+        }
+        Ok(false)
+    }
+
+    /// Returns the stored objects which reference the object, from the
+    /// parent index if it is enabled or by scanning the whole store.
+    fn referrers_of(&self, hash: &Hash) -> Result<Vec<Hash>> {
+        if self.parent_index {
+            self.parents_of(hash)
+        } else {
+            referrers(self, hash)
+        }
+    }
+
+    /// Directory of the reverse index.
+    ///
+    /// It contains one file per child which lists its parents, one per line.
+    pub fn parents_directory(&self) -> String {
+        let mut result = self.base_path.clone();
+        result.push_str("/parents/");
+        result
+    }
+
+    /// Returns the stored objects which reference the object.
+    ///
+    /// Only the parents which were recorded by `put` or `put_raw` with the
+    /// parent index enabled or by `rebuild_parent_index` are found.
+    pub fn parents_of(&self, hash: &Hash) -> Result<Vec<Hash>> {
+        let mut res: Vec<Hash> = Vec::new();
+        for parent in try!(self.read_parents(hash)) {
+            if try!(self.contains(&parent)) {
+                res.push(parent);
+            }
+        }
+        Ok(res)
+    }
+
+    /// Replaces the reverse index by scanning all objects of the store.
+    pub fn rebuild_parent_index(&self) -> Result<()> {
+        let directory = self.parents_directory();
+        if Path::new(&directory).exists() {
+            try!(remove_dir_all(&directory));
+        }
+        let hashes = try!(self.hashes());
+        let known: BTreeSet<Hash> = hashes.iter().cloned().collect();
+        let mut index: BTreeMap<Hash, BTreeSet<Hash>> = BTreeMap::new();
+        for hash in hashes {
+            let object = try!(self.get_raw(&hash));
+            for child in try!(self.decoder.references(&object, &known)) {
+                index.entry(child).or_default().insert(hash);
+            }
+        }
+        for (child, parents) in index.iter() {
+            try!(self.write_parents(child, parents));
+        }
+        Ok(())
+    }
+
+    /// Deletes the file of an object and its entries in the reverse index.
+    ///
+    /// Unlike `remove`, neither the pins nor the parents are checked.
+    pub fn delete_object(&self, hash: &Hash) -> Result<()> {
+        if Path::new(&self.parents_directory()).exists() {
+            // Damaged objects have no known children.  Their entries are
+            // left behind and skipped by `parents_of`.
+            if let Ok(object) = self.get_raw(hash) {
+                for child in try!(self.decoder.stored_references(self, &object)) {
+                    let mut parents = try!(self.read_parents(&child));
+                    if parents.remove(hash) {
+                        try!(self.write_parents(&child, &parents));
+                    }
+                }
+            }
+            try!(self.write_parents(hash, &BTreeSet::new()));
+        }
+        try!(remove_file(self.filename_for_hash(hash)));
+        Ok(())
+    }
+
+    /// Reads the recorded parents, entries may appear multiple times in the file.
+    fn read_parents(&self, hash: &Hash) -> Result<BTreeSet<Hash>> {
+        let mut content = String::new();
+        match File::open(self.parents_directory() + &hash.as_string()) {
+            Ok(mut read) => { try!(read.read_to_string(&mut content)); },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(HashIOError::IOError(err))
+        }
+        Ok(content.lines()
+           .filter(|line| line.len() == 64 && is_hex(line))
+           .map(|line| Hash::from_string(line.to_string()))
+           .collect())
+    }
+
+    /// Appends the parent without reading the file, `read_parents` drops
+    /// the duplicates.
+    fn add_parent(&self, hash: &Hash, parent: &Hash) -> Result<()> {
+        try!(create_dir_all(self.parents_directory()));
+        let mut write = try!(OpenOptions::new().create(true).append(true)
+                             .open(self.parents_directory() + &hash.as_string()));
+        try!(writeln!(write, "{}", parent.as_string()));
+        Ok(())
+    }
+
+    /// Replaces the recorded parents, the file is removed if there are none.
+    fn write_parents(&self, hash: &Hash, parents: &BTreeSet<Hash>) -> Result<()> {
+        let filename = self.parents_directory() + &hash.as_string();
+        if parents.is_empty() {
+            return match remove_file(filename) {
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+                Err(err) => Err(err.into()),
+                Ok(()) => Ok(())
+            }
+        }
+        let tmp_filename = filename.clone() + "_";
+        try!(create_dir_all(self.parents_directory()));
+        {
+            let mut write = try!(File::create(tmp_filename.clone()));
+            for parent in parents.iter() {
+                try!(writeln!(write, "{}", parent.as_string()));
+            }
+        }
+        try!(rename(tmp_filename, filename));
+        Ok(())
+    }
+
+    fn write_pins(&self, pins: &BTreeSet<Hash>) -> Result<()> {
+        let filename = self.pins_filename();
+        let tmp_filename = filename.clone() + "_";
+        try!(create_dir_all(&self.base_path));
+        {
+            let mut write = try!(File::create(tmp_filename.clone()));
+            for hash in pins.iter() {
+                try!(writeln!(write, "{}", hash.as_string()));
+            }
+        }
+        try!(rename(tmp_filename, filename));
+        Ok(())
+    }
+}
+
+/// Stored object which is mapped into memory.
+pub struct MappedObject {
+    mmap: Mmap
+}
+
+impl MappedObject {
+    pub fn as_slice(&self) -> &[u8] {
+        // Objects are never modified after they were written, since a
+        // different content would have a different hash.
+        unsafe { self.mmap.as_slice() }
+    }
+}
+
+/// Turns the error of a missing file into `NotFound`.
+fn not_found_error(err: io::Error, hash: &Hash) -> HashIOError {
+    if err.kind() == io::ErrorKind::NotFound {
+        HashIOError::NotFound(*hash)
+    } else {
+        HashIOError::IOError(err)
+    }
 }
 
 impl HashIO for HashIOFile {
@@ -50,21 +408,27 @@ impl HashIO for HashIOFile {
             trace!("HashIO::get<{}> type_hash: {} for {}",
                 T::type_name(), T::type_hash().as_string(), hash.as_string());
         }
-        let filename = self.filename_for_hash(hash);
-        let mut read = try!(File::open(filename.clone()));
-        let mut type_hash: Option<Hash> = None;
-        if !T::unsafe_loader() {
-            let version = try!(read_u32(&mut read));
-            if !T::version_valid(version) {
-                // try fallback
-                return T::fallback_parse(self, &mut read)
-            }
-            type_hash = Some(try!(read_hash(&mut read)));
-            if !T::type_hash_valid(&type_hash.unwrap()) {
-                return Err(HashIOError::TypeError(type_hash.unwrap()))
+        {
+            let mut loading = self.loading.borrow_mut();
+            if loading.contains(hash) {
+                return Err(HashIOError::CycleDetected(*hash).in_object(hash, T::type_name()))
             }
+            try!(self.limits.check_depth(loading.len() + 1)
+                 .map_err(|err| err.in_object(hash, T::type_name())));
+            loading.push(*hash);
         }
-        let res = try!(T::parse(self, &mut read, &type_hash));
+        let res = if self.use_mmap {
+            self.map(hash).and_then(|mapped| {
+                let mut read: &[u8] = mapped.as_slice();
+                parse_object(self, &mut read)
+            })
+        } else {
+            File::open(self.filename_for_hash(hash))
+                .map_err(|err| not_found_error(err, hash))
+                .and_then(|mut read| parse_object(self, &mut read))
+        };
+        self.loading.borrow_mut().pop();
+        let res = try!(res.map_err(|err| err.in_object(hash, T::type_name())));
         trace!("HashIO::get<{}> completed for {}", T::type_name(), hash.as_string());
         Ok(res)
     }
@@ -78,13 +442,21 @@ impl HashIO for HashIOFile {
         }
         let hash = item.as_hash();
         let filename = self.filename_for_hash(&hash);
+        if self.parent_index {
+            if let Some(parent) = self.storing.borrow().last() {
+                try!(self.add_parent(&hash, parent));
+            }
+        }
 
         // First, if the entry already exists, skip the insert because it's already saved.
-        if !Path::new(&filename).exists() {
+        if !try!(self.contains(&hash)) {
             // First store all childs and their childs.
             // So we make sure that all dependencies are available when the current object has
             // finished writing.
-            try!(item.store_childs(self));
+            self.storing.borrow_mut().push(hash);
+            let res = item.store_childs(self);
+            self.storing.borrow_mut().pop();
+            try!(res);
 
             // First write in a slightly modified file which will be renamed when writing was
             // finished.  So we only have valid files or nothing on the expected position but
@@ -98,16 +470,108 @@ impl HashIO for HashIOFile {
             // and the file is closed and completed before we rename the file.
             {
                 let mut write = try!(File::create(tmp_filename.clone()));
-                if !T::unsafe_loader() {
-                    try!(write_u32(1, &mut write));
-                    try!(write_hash(&T::type_hash(), &mut write));
-                }
-                try!(item.store(self, &mut write));
+                try!(write_object(self, &*item, &mut write));
             }
             try!(rename(tmp_filename, filename));
         }
         Ok(())
     }
+
+    fn get_raw(&self, hash: &Hash) -> Result<RawObject> {
+        RawObject::from_file(hash, &try!(self.read_file(hash)))
+    }
+
+    fn put_raw(&self, object: &RawObject) -> Result<()> {
+        try!(object.verify());
+        if self.parent_index && !try!(self.contains(&object.hash)) {
+            for child in try!(self.decoder.stored_references(self, object)) {
+                try!(self.add_parent(&child, &object.hash));
+            }
+        }
+        self.put_file(&object.hash, &object.to_file())
+    }
+
+    /// The payload is unchanged, so the parent index stays valid.
+    fn replace_raw(&self, object: &RawObject) -> Result<()> {
+        try!(object.verify());
+        if !try!(self.contains(&object.hash)) {
+            return self.put_raw(object)
+        }
+        self.write_file(&object.hash, &object.to_file())
+    }
+
+    fn contains(&self, hash: &Hash) -> Result<bool> {
+        Ok(Path::new(&self.filename_for_hash(hash)).exists())
+    }
+
+    fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    fn raw_source(&self) -> Option<Rc<RawSource>> {
+        Some(Rc::new(self.clone()))
+    }
+}
+
+// Without the parent index, `remove` scans the whole store for the parents
+// and for the objects below the pins.
+impl HashIORemove for HashIOFile {
+    fn pins(&self) -> Result<BTreeSet<Hash>> {
+        let mut content = String::new();
+        match File::open(self.pins_filename()) {
+            Ok(mut read) => { try!(read.read_to_string(&mut content)); },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(HashIOError::IOError(err))
+        }
+        Ok(content.lines()
+           .filter(|line| line.len() == 64 && is_hex(line))
+           .map(|line| Hash::from_string(line.to_string()))
+           .collect())
+    }
+
+    fn pin(&self, hash: &Hash) -> Result<bool> {
+        if !try!(self.contains(hash)) {
+            return Err(HashIOError::NotFound(*hash))
+        }
+        let mut pins = try!(self.pins());
+        if !pins.insert(*hash) {
+            return Ok(false)
+        }
+        try!(self.write_pins(&pins));
+        Ok(true)
+    }
+
+    fn unpin(&self, hash: &Hash) -> Result<bool> {
+        let mut pins = try!(self.pins());
+        if !pins.remove(hash) {
+            return Ok(false)
+        }
+        try!(self.write_pins(&pins));
+        Ok(true)
+    }
+
+    fn remove(&self, hash: &Hash, force: bool) -> Result<bool> {
+        if !try!(self.contains(hash)) {
+            return Ok(false)
+        }
+        if try!(self.is_protected(hash)) {
+            return Err(HashIOError::Pinned(*hash))
+        }
+        if !force {
+            let parents = try!(self.referrers_of(hash));
+            if !parents.is_empty() {
+                return Err(HashIOError::Referenced(*hash, parents))
+            }
+        }
+        try!(self.delete_object(hash));
+        Ok(true)
+    }
+}
+
+impl RawSource for HashIOFile {
+    fn load_raw(&self, hash: &Hash) -> Result<RawObject> {
+        self.get_raw(hash)
+    }
 }
 
 
@@ -177,3 +641,243 @@ mod test {
         }
     }
 }*/
+
+
+#[cfg(test)]
+mod test_mmap {
+    use super::*;
+    use std::fs::remove_dir_all;
+
+    #[test]
+    fn test_mmap() {
+        remove_dir_all("./unittest/mmaptest/").ok();
+        let hash_io = HashIOFile::new("unittest/mmaptest".to_string()).with_mmap(true);
+        let strings = Rc::new(vec![Rc::new("Hello".to_string()), Rc::new("World".to_string())]);
+        let hash = strings.as_hash();
+        hash_io.put(strings.clone()).unwrap();
+
+        let loaded: Rc<Vec<Rc<String>>> = hash_io.get(&hash).unwrap();
+        assert_eq!(strings, loaded);
+
+        let mapped = hash_io.map(&strings[1].as_hash()).unwrap();
+        let (string, rest) = read_str(mapped.as_slice()).unwrap();
+        assert_eq!("World", string);
+        assert!(rest.is_empty());
+    }
+}
+
+
+#[cfg(test)]
+mod test_contains {
+    use super::*;
+    use std::fs::remove_dir_all;
+
+    #[test]
+    fn test_contains() {
+        remove_dir_all("./unittest/containstest/").ok();
+        let hash_io = HashIOFile::new("unittest/containstest".to_string());
+        let stored = Rc::new("stored".to_string());
+        let other = Rc::new("other".to_string());
+        hash_io.put(stored.clone()).unwrap();
+
+        assert!(hash_io.contains(&stored.as_hash()).unwrap());
+        assert!(!hash_io.contains(&other.as_hash()).unwrap());
+        assert_eq!(vec![other.as_hash()],
+                   hash_io.missing(&[stored.as_hash(), other.as_hash()]).unwrap());
+    }
+}
+
+
+#[cfg(test)]
+mod test_raw {
+    use super::*;
+    use std::fs::remove_dir_all;
+
+    #[test]
+    fn test_raw() {
+        remove_dir_all("./unittest/rawtest/").ok();
+        let source = HashIOFile::new("unittest/rawtest/source".to_string());
+        let target = HashIOFile::new("unittest/rawtest/target".to_string());
+        let title = Rc::new("title".to_string());
+        let list = Rc::new(vec![title.clone()]);
+        source.put(list.clone()).unwrap();
+
+        // Unlike put, put_raw does not store the children.
+        let object = source.get_raw(&list.as_hash()).unwrap();
+        assert_eq!(None, object.header);
+        target.put_raw(&object).unwrap();
+        assert!(!target.contains(&title.as_hash()).unwrap());
+        target.put_raw(&source.get_raw(&title.as_hash()).unwrap()).unwrap();
+        assert_eq!(list, target.get::<Vec<Rc<String>>>(&list.as_hash()).unwrap());
+
+        let headered = RawObject::new(Some((1, title.as_hash())), b"payload".to_vec());
+        target.put_raw(&headered).unwrap();
+        assert_eq!(headered, target.get_raw(&headered.hash).unwrap());
+        assert_eq!(headered.file_len(), target.read_file(&headered.hash).unwrap().len());
+
+        let mut broken = headered.clone();
+        broken.payload.push(0);
+        match target.put_raw(&broken) {
+            Err(HashIOError::HashMismatch(hash)) => assert_eq!(headered.hash, hash),
+            _ => panic!("Expected a hash mismatch")
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test_parent_index {
+    use super::*;
+    use std::fs::remove_dir_all;
+
+    #[test]
+    fn test_parent_index() {
+        remove_dir_all("./unittest/parentindextest/").ok();
+        let indexed = HashIOFile::new("unittest/parentindextest/indexed".to_string())
+            .with_parent_index(true);
+        let plain = HashIOFile::new("unittest/parentindextest/plain".to_string());
+        let a = Rc::new("a".to_string());
+        let b = Rc::new("b".to_string());
+        let first = Rc::new(vec![a.clone(), b.clone(), a.clone()]);
+        let second = Rc::new(vec![a.clone()]);
+        let mut expected = vec![first.as_hash(), second.as_hash()];
+        expected.sort();
+        for hash_io in [&indexed, &plain].iter() {
+            hash_io.put(first.clone()).unwrap();
+            hash_io.put(second.clone()).unwrap();
+            hash_io.put(first.clone()).unwrap();
+        }
+
+        let mut parents = indexed.parents_of(&a.as_hash()).unwrap();
+        parents.sort();
+        assert_eq!(expected, parents);
+        assert_eq!(vec![first.as_hash()], indexed.parents_of(&b.as_hash()).unwrap());
+        assert!(indexed.parents_of(&first.as_hash()).unwrap().is_empty());
+
+        assert!(plain.parents_of(&a.as_hash()).unwrap().is_empty());
+        plain.rebuild_parent_index().unwrap();
+        let mut parents = plain.parents_of(&a.as_hash()).unwrap();
+        parents.sort();
+        assert_eq!(expected, parents);
+
+        // Removed parents are not reported.
+        indexed.remove(&second.as_hash(), false).unwrap();
+        assert_eq!(vec![first.as_hash()], indexed.parents_of(&a.as_hash()).unwrap());
+
+        // The index answers remove without scanning the store.
+        match indexed.remove(&a.as_hash(), false) {
+            Err(HashIOError::Referenced(_, parents)) => assert_eq!(vec![first.as_hash()], parents),
+            _ => panic!("Expected the object to be referenced")
+        }
+        indexed.pin(&first.as_hash()).unwrap();
+        match indexed.remove(&b.as_hash(), true) {
+            Err(HashIOError::Pinned(hash)) => assert_eq!(b.as_hash(), hash),
+            _ => panic!("Expected the object to be pinned")
+        }
+    }
+
+    #[test]
+    fn test_parent_index_maintenance() {
+        remove_dir_all("./unittest/parentindexmaintenancetest/").ok();
+        let source = HashIOFile::new("unittest/parentindexmaintenancetest/source".to_string());
+        let copy = HashIOFile::new("unittest/parentindexmaintenancetest/copy".to_string())
+            .with_parent_index(true);
+        let a = Rc::new("a".to_string());
+        let first = Rc::new(vec![a.clone(), a.clone()]);
+        let second = Rc::new(vec![a.clone()]);
+        source.put(first.clone()).unwrap();
+        source.put(second.clone()).unwrap();
+
+        // Raw copies are recorded as well, children first.
+        for hash in [a.as_hash(), first.as_hash(), second.as_hash()].iter() {
+            copy.put_raw(&source.get_raw(hash).unwrap()).unwrap();
+            copy.put_raw(&source.get_raw(hash).unwrap()).unwrap();
+        }
+        let mut expected = vec![first.as_hash(), second.as_hash()];
+        expected.sort();
+        assert_eq!(expected, copy.parents_of(&a.as_hash()).unwrap());
+
+        // Removed objects are dropped from the index files.
+        copy.remove(&second.as_hash(), false).unwrap();
+        let parents: Vec<Hash> = copy.read_parents(&a.as_hash()).unwrap().into_iter().collect();
+        assert_eq!(vec![first.as_hash()], parents);
+        ::inspect::collect_garbage(&copy, &[], false).unwrap();
+        assert!(copy.hashes().unwrap().is_empty());
+        assert!(!Path::new(&(copy.parents_directory() + &a.as_hash().as_string())).exists());
+    }
+}
+
+
+#[cfg(test)]
+mod test_limits {
+    use super::*;
+    use hamt::Hamt;
+    use std::fs;
+    use std::fs::remove_dir_all;
+
+    fn assert_limit<T>(res: Result<T>, expected_limit: &str, expected_value: usize) {
+        let err = res.err();
+        match err.as_ref().map(|err| err.root_cause()) {
+            Some(&HashIOError::LimitExceeded(ref limit, value)) => {
+                assert_eq!(expected_limit, limit);
+                assert_eq!(expected_value, value);
+            },
+            Some(err) => panic!("Unexpected error {}", err),
+            None => panic!("Expected the {} limit to be exceeded", expected_limit)
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        remove_dir_all("./unittest/limitstest/").ok();
+        let hash_io = HashIOFile::new("unittest/limitstest".to_string());
+        let strings: Vec<Rc<String>> = (0..10).map(|i| Rc::new(format!("{}", i))).collect();
+        let strings = Rc::new(strings);
+        let long_string = Rc::new("a".repeat(100));
+        let nested = Rc::new(vec![Rc::new(vec![long_string.clone()])]);
+        hash_io.put(strings.clone()).unwrap();
+        hash_io.put(nested.clone()).unwrap();
+
+        let limited = hash_io.clone().with_limits(Limits {
+            max_collection_len: 5,
+            max_string_len: 10,
+            max_depth: 2
+        });
+        assert_limit(limited.get::<Vec<Rc<String>>>(&strings.as_hash()), "max_collection_len", 10);
+        assert_limit(limited.get::<String>(&long_string.as_hash()), "max_string_len", 100);
+        assert_limit(limited.get::<Vec<Rc<Vec<Rc<String>>>>>(&nested.as_hash()), "max_depth", 3);
+        assert_eq!(strings, hash_io.get::<Vec<Rc<String>>>(&strings.as_hash()).unwrap());
+    }
+
+    #[test]
+    fn test_cycle() {
+        remove_dir_all("./unittest/cycletest/").ok();
+        let hash_io = HashIOFile::new("unittest/cycletest".to_string());
+        // A manipulated node which has itself as child
+        let hash = Hash::hash_string("cycle".to_string());
+        fs::create_dir_all(hash_io.directory_for_hash(&hash)).unwrap();
+        {
+            let mut write = File::create(hash_io.filename_for_hash(&hash)).unwrap();
+            write_u32(1, &mut write).unwrap();
+            write_hash(&Hamt::<String, String>::type_hash(), &mut write).unwrap();
+            write_u32(1, &mut write).unwrap();
+            write_u8(1, &mut write).unwrap();
+            write_hash(&hash, &mut write).unwrap();
+        }
+        let err = hash_io.get::<Hamt<String, String>>(&hash).err();
+        match err.as_ref().map(|err| err.root_cause()) {
+            Some(&HashIOError::CycleDetected(cycle_hash)) => assert_eq!(hash, cycle_hash),
+            _ => panic!("Expected a cycle")
+        }
+    }
+
+    #[test]
+    fn test_clone_without_stack() {
+        let hash_io = HashIOFile::new("unittest/clonetest".to_string());
+        hash_io.loading.borrow_mut().push(Hash::hash_bytes(b"loading"));
+        let cloned = hash_io.clone();
+        assert!(cloned.loading.borrow().is_empty());
+        assert_eq!(hash_io, cloned);
+        assert_ne!(hash_io, cloned.with_mmap(true));
+    }
+}
@@ -0,0 +1,170 @@
+//! HashIO support for `HashMap`.
+//!
+//! The iteration order of a `HashMap` is random, so the entries are
+//! sorted by the hashes of their keys before they are written.  This way
+//! the same map always results in the same content hash.
+
+use hash::*;
+use io::*;
+use hashio::*;
+use std::io::{Read, Write};
+use std::result;
+use std::io;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash as StdHash;
+use std::rc::Rc;
+
+/// Returns the entries ordered by the hashes of the keys.
+fn sorted_entries<T, U>(map: &HashMap<Rc<T>, Rc<U>>) -> Vec<(Hash, Rc<T>, Rc<U>)>
+        where T: HashIOParse + Eq + StdHash, U: HashIOParse {
+    let mut res: Vec<(Hash, Rc<T>, Rc<U>)> = map.iter()
+        .map(|(key, item)| (key.as_hash(), key.clone(), item.clone()))
+        .collect();
+    res.sort_by(|a, b| a.0.cmp(&b.0));
+    res
+}
+
+impl<T,U> Writable for HashMap<Rc<T>, Rc<U>>
+            where T: HashIOParse + Eq + StdHash, U: HashIOParse {
+    fn write_to<W: Write>(&self, write: &mut W) -> result::Result<usize, io::Error> {
+        try!(write_u32(0, write));
+        try!(write_u32(self.len() as u32, write));
+        for (key_hash, _, item) in sorted_entries(self) {
+            try!(write_hash(&key_hash, write));
+            try!(write_hash(&item.as_hash(), write));
+        }
+        return Ok(8 + self.len() * 64)
+    }
+}
+
+impl<T, U> Hashable for HashMap<Rc<T>, Rc<U>>
+        where T: HashIOParse + Eq + StdHash, U: HashIOParse {
+    fn as_hash(&self) -> Hash {
+        self.writable_to_hash()
+    }
+}
+
+impl<T, U> Typeable for HashMap<Rc<T>, Rc<U>>
+        where T: HashIOParse + Eq + StdHash, U: HashIOParse {
+    fn type_hash() -> Hash {
+        Hash::hash_string(HashMap::<Rc<T>, Rc<U>>::type_name())
+    }
+
+    fn type_name() -> String {
+        "HashMap<".to_string() + &T::type_name() + "," +
+                &U::type_name() + ">"
+    }
+}
+
+impl<T, U> HashIOType for HashMap<Rc<T>, Rc<U>>
+        where T: HashIOParse + Eq + StdHash + 'static, U: HashIOParse + 'static {
+    fn childs(&self) -> BTreeMap<String, Rc<HashIOType>> {
+        let mut res: BTreeMap<String, Rc<HashIOType>> = BTreeMap::new();
+        for (key, item) in self {
+            let key_str = format!("{:?}", key);
+            let boxed_item_object: Rc<HashIOType> = item.clone() as Rc<HashIOType>;
+            res.insert(key_str, boxed_item_object);
+        }
+        res
+    }
+
+    /// Collections store nothing beside their entries.
+    fn attributes_hash(&self) -> Option<Hash> {
+        Some(Hash::hash_bytes(&[]))
+    }
+
+    fn type_hash_obj(&self) -> Hash {
+        HashMap::<Rc<T>, Rc<U>>::type_hash()
+    }
+
+    fn type_name_obj(&self) -> String {
+        HashMap::<Rc<T>, Rc<U>>::type_name()
+    }
+}
+
+impl<T, U> HashIOParse for HashMap<Rc<T>, Rc<U>>
+            where T: HashIOParse + Eq + StdHash + 'static, U: HashIOParse + 'static {
+    fn parse<H, R>(hash_io: &H, read: &mut R, _: &Option<Hash>)
+                -> Result<Rc<Self>> where H: HashIO, R: Read {
+        // read and ignore version
+        try!(read_u32(read));
+        let len = try!(read_u32(read));
+        try!(hash_io.limits().check_collection_len(len as usize));
+        let mut res: HashMap<Rc<T>, Rc<U>> = HashMap::new();
+        for i in 0..len {
+            let key_hash = try!(read_hash(read));
+            let key: Rc<T> = try!(hash_io.get(&key_hash)
+                                  .map_err(|err| err.in_item(format!("key {}", i))));
+            let val_hash = try!(read_hash(read));
+            let val: Rc<U> = try!(hash_io.get(&val_hash)
+                                  .map_err(|err| err.in_item(i)));
+            res.insert(key, val);
+        }
+        Ok(Rc::new(res))
+    }
+
+    fn store<H, W>(&self, _: &H, write: &mut W) -> Result<()> where H: HashIO, W: Write {
+        try!(self.write_to(write));
+        Ok(())
+    }
+
+    fn store_childs<H>(&self, hash_io: &H) -> Result<()>
+            where H: HashIO {
+        for (key, item) in self {
+            try!(hash_io.put(key.clone()));
+            try!(hash_io.put(item.clone()));
+        }
+        Ok(())
+    }
+    fn unsafe_loader() -> bool {
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::io::*;
+    use super::super::hashio::*;
+    use std::io::{Read, Write};
+    use std::io;
+    use hash::*;
+    use std::collections::{BTreeMap, HashMap};
+    use std::result;
+    use std::rc::Rc;
+    use hashiofile::HashIOFile;
+    use std::fs::remove_dir_all;
+
+    hashio_type!{ TestType {
+    } {
+        a: HashMap< Rc<String>, Rc<String> >
+    }}
+
+    #[test]
+    fn test() {
+        remove_dir_all("./unittest/hashmaptest/").ok();
+        let hash_io = HashIOFile::new("unittest/hashmaptest".to_string());
+        let mut map1: HashMap<Rc<String>, Rc<String>> = HashMap::new();
+        let mut map2: HashMap<Rc<String>, Rc<String>> = HashMap::new();
+        for &(key, value) in &[("a", "1"), ("b", "2"), ("c", "3"), ("d", "4")] {
+            map1.insert(Rc::new(key.to_string()), Rc::new(value.to_string()));
+        }
+        for &(key, value) in &[("d", "4"), ("c", "3"), ("b", "2"), ("a", "1")] {
+            map2.insert(Rc::new(key.to_string()), Rc::new(value.to_string()));
+        }
+        assert_eq!(map1.as_hash(), map2.as_hash());
+
+        let my_obj = TestType {
+            a: Rc::new(map1)
+        };
+        let my_hash = my_obj.as_hash();
+        hash_io.put(Rc::new(my_obj)).unwrap();
+
+        let my_obj: Rc<TestType> = hash_io.get(&my_hash).unwrap();
+        let my_map = my_obj.a.clone();
+        assert_eq!("HashMap<String,String>", my_map.type_name_obj());
+        assert_eq!(&Rc::new("1".to_string()), my_map.get(&Rc::new("a".to_string())).unwrap());
+        assert_eq!(&Rc::new("4".to_string()), my_map.get(&Rc::new("d".to_string())).unwrap());
+        assert!(my_map.childs().contains_key("\"b\""));
+    }
+}
@@ -0,0 +1,157 @@
+//! HashIO support for `HashSet`.
+//!
+//! The iteration order of a `HashSet` is random, so the elements are
+//! sorted by their hashes before they are written.  This way the same set
+//! always results in the same content hash.
+
+use hash::*;
+use io::*;
+use hashio::*;
+use std::io::{Read, Write};
+use std::result;
+use std::io;
+use std::collections::{BTreeMap, HashSet};
+use std::hash::Hash as StdHash;
+use std::rc::Rc;
+
+/// Returns the elements ordered by their hashes.
+fn sorted_items<T>(set: &HashSet<Rc<T>>) -> Vec<(Hash, Rc<T>)>
+        where T: HashIOParse + Eq + StdHash {
+    let mut res: Vec<(Hash, Rc<T>)> = set.iter()
+        .map(|item| (item.as_hash(), item.clone()))
+        .collect();
+    res.sort_by(|a, b| a.0.cmp(&b.0));
+    res
+}
+
+impl<T> Writable for HashSet<Rc<T>> where T: HashIOParse + Eq + StdHash {
+    fn write_to<W: Write>(&self, write: &mut W) -> result::Result<usize, io::Error> {
+        try!(write_u32(0, write));
+        try!(write_u32(self.len() as u32, write));
+        for (hash, _) in sorted_items(self) {
+            try!(write_hash(&hash, write));
+        }
+        return Ok(8 + self.len() * 32)
+    }
+}
+
+impl<T> Hashable for HashSet<Rc<T>> where T: HashIOParse + Eq + StdHash {
+    fn as_hash(&self) -> Hash {
+        self.writable_to_hash()
+    }
+}
+
+impl<T> Typeable for HashSet<Rc<T>> where T: HashIOParse + Eq + StdHash {
+    fn type_hash() -> Hash {
+        Hash::hash_string(HashSet::<Rc<T>>::type_name())
+    }
+
+    fn type_name() -> String {
+        "HashSet<".to_string() + &T::type_name() + ">"
+    }
+}
+
+impl<T> HashIOType for HashSet<Rc<T>> where T: HashIOParse + Eq + StdHash + 'static {
+    fn childs(&self) -> BTreeMap<String, Rc<HashIOType>> {
+        let mut res: BTreeMap<String, Rc<HashIOType>> = BTreeMap::new();
+        for (i, (_, item)) in sorted_items(self).into_iter().enumerate() {
+            let i_str = format!("{}", i);
+            let boxed_item_object: Rc<HashIOType> = item as Rc<HashIOType>;
+            res.insert(i_str, boxed_item_object);
+        }
+        res
+    }
+
+    /// Collections store nothing beside their entries.
+    fn attributes_hash(&self) -> Option<Hash> {
+        Some(Hash::hash_bytes(&[]))
+    }
+
+    fn type_hash_obj(&self) -> Hash {
+        HashSet::<Rc<T>>::type_hash()
+    }
+
+    fn type_name_obj(&self) -> String {
+        HashSet::<Rc<T>>::type_name()
+    }
+}
+
+impl<T> HashIOParse for HashSet<Rc<T>> where T: HashIOParse + Eq + StdHash + 'static {
+    fn parse<H, R>(hash_io: &H, read: &mut R, _: &Option<Hash>)
+                -> Result<Rc<Self>> where H: HashIO, R: Read {
+        // read and ignore version
+        try!(read_u32(read));
+        let len = try!(read_u32(read));
+        try!(hash_io.limits().check_collection_len(len as usize));
+        let mut res: HashSet<Rc<T>> = HashSet::new();
+        for i in 0..len {
+            let item_hash = try!(read_hash(read));
+            let item: Rc<T> = try!(hash_io.get(&item_hash).map_err(|err| err.in_item(i)));
+            res.insert(item);
+        }
+        Ok(Rc::new(res))
+    }
+
+    fn store<H, W>(&self, _: &H, write: &mut W) -> Result<()> where H: HashIO, W: Write {
+        try!(self.write_to(write));
+        Ok(())
+    }
+
+    fn store_childs<H>(&self, hash_io: &H) -> Result<()>
+            where H: HashIO {
+        for item in self {
+            try!(hash_io.put(item.clone()));
+        }
+        Ok(())
+    }
+    fn unsafe_loader() -> bool {
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::io::*;
+    use super::super::hashio::*;
+    use std::io::{Read, Write};
+    use std::io;
+    use hash::*;
+    use std::collections::{BTreeMap, HashSet};
+    use std::result;
+    use std::rc::Rc;
+    use hashiofile::HashIOFile;
+    use std::fs::remove_dir_all;
+
+    hashio_type!{ TestType {
+    } {
+        a: HashSet< Rc<String> >
+    }}
+
+    #[test]
+    fn test() {
+        remove_dir_all("./unittest/hashsettest/").ok();
+        let hash_io = HashIOFile::new("unittest/hashsettest".to_string());
+        let mut set1: HashSet<Rc<String>> = HashSet::new();
+        let mut set2: HashSet<Rc<String>> = HashSet::new();
+        for value in &["a", "b", "c", "d", "e"] {
+            set1.insert(Rc::new(value.to_string()));
+        }
+        for value in &["e", "d", "c", "b", "a"] {
+            set2.insert(Rc::new(value.to_string()));
+        }
+        assert_eq!(set1.as_hash(), set2.as_hash());
+
+        let my_obj = TestType {
+            a: Rc::new(set1)
+        };
+        let my_hash = my_obj.as_hash();
+        hash_io.put(Rc::new(my_obj)).unwrap();
+
+        let my_obj: Rc<TestType> = hash_io.get(&my_hash).unwrap();
+        assert_eq!("HashSet<String>", my_obj.a.type_name_obj());
+        assert_eq!(5, my_obj.a.len());
+        assert!(my_obj.a.contains(&Rc::new("c".to_string())));
+        assert_eq!(my_hash, my_obj.as_hash());
+    }
+}
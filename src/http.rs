@@ -0,0 +1,473 @@
+//! HTTP backend to share a store between processes and machines.
+//!
+//! # Usage
+//! `HashIOServer` exposes a local `HashIOFile` and `HashIOHttp` implements
+//! `HashIO` on top of it.  The objects are transferred as raw file content
+//! including the header:
+//!
+//! * `GET /objects/<hash>` returns the object or 404.
+//! * `PUT /objects/<hash>` stores the object.  The server verifies that the
+//!   content matches the hash.
+//! * `POST /have` takes one hash per line and returns the ones which exist.
+//!
+//! The client verifies the content of every object it receives, so it
+//! doesn't have to trust the server.  Only a minimal subset of HTTP/1.1 is
+//! implemented, every request uses its own connection.
+
+use hash::*;
+use hashio::*;
+use format::*;
+use hashiofile::*;
+use io::DEFAULT_MAX_BYTES_LEN;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::rc::Rc;
+
+/// Maximum size of a request body the server accepts.
+pub const MAX_BODY_LEN: usize = DEFAULT_MAX_BYTES_LEN + HEADER_LEN;
+
+/// HashIO client for a `HashIOServer`.
+pub struct HashIOHttp {
+    /// Host and port of the server like `localhost:8080`.
+    pub address: String,
+    pub limits: Limits,
+    /// Number of nested `get` calls.
+    depth: Cell<usize>,
+    /// Objects of the graph which is currently put, true if they still have
+    /// to be sent.
+    sending: RefCell<Option<BTreeMap<Hash, bool>>>,
+    requests: Cell<usize>
+}
+
+/// The state of running calls is not copied or compared.
+impl Clone for HashIOHttp {
+    fn clone(&self) -> HashIOHttp {
+        HashIOHttp::new(self.address.clone()).with_limits(self.limits)
+    }
+}
+
+impl PartialEq for HashIOHttp {
+    fn eq(&self, other: &HashIOHttp) -> bool {
+        self.address == other.address && self.limits == other.limits
+    }
+}
+
+impl fmt::Debug for HashIOHttp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HashIOHttp")
+            .field("address", &self.address)
+            .field("limits", &self.limits)
+            .finish()
+    }
+}
+
+impl HashIOHttp {
+    pub fn new(address: String) -> HashIOHttp {
+        HashIOHttp {
+            address: address,
+            limits: Limits::default(),
+            depth: Cell::new(0),
+            sending: RefCell::new(None),
+            requests: Cell::new(0)
+        }
+    }
+
+    /// Builder style setter for the parsing limits.
+    pub fn with_limits(mut self, limits: Limits) -> HashIOHttp {
+        self.limits = limits;
+        self
+    }
+
+    /// Returns the hashes which exist on the server.
+    pub fn have(&self, hashes: &[Hash]) -> Result<Vec<Hash>> {
+        let body: String = hashes.iter().map(|hash| hash.as_string() + "\n").collect();
+        let (status, response) = try!(self.request("POST", "/have", body.as_bytes()));
+        if status != 200 {
+            return Err(status_error(status, "/have"))
+        }
+        parse_hash_lines(&response)
+    }
+
+    /// Loads the file content of the object and verifies it.
+    pub fn get_file(&self, hash: &Hash) -> Result<Vec<u8>> {
+        let path = object_path(hash);
+        let (status, data) = try!(self.request("GET", &path, &[]));
+        match status {
+            200 => (),
+            404 => return Err(HashIOError::NotFound(*hash)),
+            _ => return Err(status_error(status, &path))
+        }
+        if split_header(hash, &data).is_none() {
+            return Err(HashIOError::HashMismatch(*hash))
+        }
+        Ok(data)
+    }
+
+    /// Stores the file content of an object.
+    pub fn put_file(&self, hash: &Hash, data: &[u8]) -> Result<()> {
+        let path = object_path(hash);
+        let (status, _) = try!(self.request("PUT", &path, data));
+        if status != 200 && status != 201 {
+            return Err(status_error(status, &path))
+        }
+        Ok(())
+    }
+
+    /// Number of requests this client sent.
+    pub fn requests(&self) -> usize {
+        self.requests.get()
+    }
+
+    /// Stores the object unless the server has it, its children first.
+    ///
+    /// Objects of the graph which is currently put were checked at once,
+    /// all others are checked one by one.
+    fn put_missing<T>(&self, item: Rc<T>) -> Result<()> where T: HashIOParse {
+        let hash = item.as_hash();
+        let known = match *self.sending.borrow() {
+            Some(ref sending) => sending.get(&hash).cloned(),
+            None => None
+        };
+        let missing = match known {
+            Some(missing) => missing,
+            None => !try!(self.contains(&hash))
+        };
+        if !missing {
+            return Ok(())
+        }
+        if let Some(ref mut sending) = *self.sending.borrow_mut() {
+            sending.insert(hash, false);
+        }
+        try!(item.store_childs(self));
+        let mut data: Vec<u8> = Vec::new();
+        try!(write_object(self, &*item, &mut data));
+        self.put_file(&hash, &data)
+    }
+
+    /// Sends a request and returns the status and the body of the response.
+    fn request(&self, method: &str, path: &str, body: &[u8]) -> Result<(u32, Vec<u8>)> {
+        trace!("HashIOHttp {} {}", method, path);
+        self.requests.set(self.requests.get() + 1);
+        let mut stream = try!(TcpStream::connect(&*self.address));
+        try!(write!(stream, "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\
+                             Connection: close\r\n\r\n",
+                    method, path, self.address, body.len()));
+        try!(stream.write_all(body));
+        try!(stream.flush());
+        let mut reader = BufReader::new(stream);
+        let status_line = try!(read_line(&mut reader));
+        let status = try!(status_line.split(' ').nth(1)
+            .and_then(|status| status.parse::<u32>().ok())
+            .ok_or(invalid_data(format!("Invalid status line '{}'", status_line))));
+        let content_length = try!(read_headers(&mut reader));
+        let mut response: Vec<u8> = Vec::new();
+        try!(reader.take(content_length as u64).read_to_end(&mut response));
+        if response.len() != content_length {
+            return Err(HashIOError::IOError(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                           "Incomplete response")))
+        }
+        Ok((status, response))
+    }
+}
+
+impl HashIO for HashIOHttp {
+    fn get<T>(&self, hash: &Hash) -> Result<Rc<T>> where T: HashIOParse {
+        let depth = self.depth.get() + 1;
+        try!(self.limits.check_depth(depth).map_err(|err| err.in_object(hash, T::type_name())));
+        self.depth.set(depth);
+        let res = self.get_file(hash).and_then(|data| {
+            let mut read: &[u8] = &data;
+            parse_object(self, &mut read)
+        });
+        self.depth.set(depth - 1);
+        res.map_err(|err| err.in_object(hash, T::type_name()))
+    }
+
+    /// Asks the server once which objects of the graph are missing and only
+    /// sends these.
+    fn put<T>(&self, item: Rc<T>) -> Result<()> where T: HashIOParse {
+        if self.sending.borrow().is_some() {
+            return self.put_missing(item)
+        }
+        let hashes = graph_hashes(&*item);
+        let missing: BTreeSet<Hash> = try!(self.missing(&hashes)).into_iter().collect();
+        *self.sending.borrow_mut() = Some(hashes.into_iter()
+            .map(|hash| (hash, missing.contains(&hash)))
+            .collect());
+        let res = self.put_missing(item);
+        *self.sending.borrow_mut() = None;
+        res
+    }
+
+    fn get_raw(&self, hash: &Hash) -> Result<RawObject> {
+        RawObject::from_file(hash, &try!(self.get_file(hash)))
+    }
+
+    fn put_raw(&self, object: &RawObject) -> Result<()> {
+        try!(object.verify());
+        self.put_file(&object.hash, &object.to_file())
+    }
+
+    fn contains(&self, hash: &Hash) -> Result<bool> {
+        Ok(!try!(self.have(&[*hash])).is_empty())
+    }
+
+    /// Asks the server for all hashes with one request.
+    fn missing(&self, hashes: &[Hash]) -> Result<Vec<Hash>> {
+        let present = try!(self.have(hashes));
+        Ok(hashes.iter().filter(|hash| !present.contains(hash)).cloned().collect())
+    }
+
+    fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    fn raw_source(&self) -> Option<Rc<RawSource>> {
+        Some(Rc::new(self.clone()))
+    }
+}
+
+impl RawSource for HashIOHttp {
+    fn load_raw(&self, hash: &Hash) -> Result<RawObject> {
+        self.get_raw(hash)
+    }
+}
+
+
+/// Returns the hashes of the object and all loaded objects below it.
+fn graph_hashes(root: &HashIOType) -> Vec<Hash> {
+    let mut res: Vec<Hash> = vec![root.as_hash()];
+    let mut visited: BTreeSet<Hash> = res.iter().cloned().collect();
+    let mut pending: Vec<Rc<HashIOType>> = root.childs().values().cloned().collect();
+    while let Some(item) = pending.pop() {
+        let hash = item.as_hash();
+        if visited.insert(hash) {
+            res.push(hash);
+            pending.extend(item.childs().values().cloned());
+        }
+    }
+    res
+}
+
+
+/// Serves a `HashIOFile` over HTTP.
+pub struct HashIOServer {
+    hash_io: HashIOFile,
+    listener: TcpListener
+}
+
+impl HashIOServer {
+    /// Listens on the address, use port 0 to pick a free port.
+    pub fn bind(hash_io: HashIOFile, address: &str) -> Result<HashIOServer> {
+        Ok(HashIOServer {
+            hash_io: hash_io,
+            listener: try!(TcpListener::bind(address))
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(try!(self.listener.local_addr()))
+    }
+
+    /// Handles the requests one after another until accepting fails.
+    pub fn serve(&self) -> Result<()> {
+        loop {
+            try!(self.handle_one());
+        }
+    }
+
+    /// Accepts one connection and answers its request.
+    ///
+    /// Errors of the connection are only logged, so a broken client doesn't
+    /// stop the server.
+    pub fn handle_one(&self) -> Result<()> {
+        let (stream, _) = try!(self.listener.accept());
+        if let Err(err) = self.handle(stream) {
+            warn!("HashIOServer: {}", err);
+        }
+        Ok(())
+    }
+
+    fn handle(&self, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(try!(stream.try_clone()));
+        let request_line = try!(read_line(&mut reader));
+        let content_length = try!(read_headers(&mut reader));
+        let mut writer = stream;
+        if content_length > MAX_BODY_LEN {
+            return respond(&mut writer, 413, b"")
+        }
+        let mut body: Vec<u8> = Vec::new();
+        try!(reader.take(content_length as u64).read_to_end(&mut body));
+
+        let parts: Vec<&str> = request_line.split(' ').collect();
+        if parts.len() != 3 {
+            return respond(&mut writer, 400, b"Invalid request line")
+        }
+        let (method, path) = (parts[0], parts[1]);
+        trace!("HashIOServer {} {}", method, path);
+        if path == "/have" {
+            return match method {
+                "POST" => match parse_hash_lines(&body) {
+                    Ok(hashes) => {
+                        let missing = try!(self.hash_io.missing(&hashes));
+                        let present: String = hashes.iter()
+                            .filter(|hash| !missing.contains(hash))
+                            .map(|hash| hash.as_string() + "\n")
+                            .collect();
+                        respond(&mut writer, 200, present.as_bytes())
+                    },
+                    Err(_) => respond(&mut writer, 400, b"Invalid hash")
+                },
+                _ => respond(&mut writer, 405, b"")
+            }
+        }
+        let hash = match path.trim_start_matches("/objects/") {
+            hex if path.starts_with("/objects/") && hex.len() == 64 && is_hex(hex) =>
+                Hash::from_string(hex.to_string()),
+            _ => return respond(&mut writer, 404, b"")
+        };
+        match method {
+            "GET" => match self.hash_io.read_file(&hash) {
+                Ok(data) => respond(&mut writer, 200, &data),
+                Err(HashIOError::NotFound(_)) => respond(&mut writer, 404, b""),
+                Err(err) => {
+                    try!(respond(&mut writer, 500, b""));
+                    Err(err)
+                }
+            },
+            "PUT" => match RawObject::from_file(&hash, &body) {
+                Ok(object) => {
+                    try!(self.hash_io.put_raw(&object));
+                    respond(&mut writer, 201, b"")
+                },
+                Err(_) => respond(&mut writer, 400, b"Content doesn't match the hash")
+            },
+            _ => respond(&mut writer, 405, b"")
+        }
+    }
+}
+
+fn respond<W: Write>(write: &mut W, status: u32, body: &[u8]) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error"
+    };
+    try!(write!(write, "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status, reason, body.len()));
+    try!(write.write_all(body));
+    try!(write.flush());
+    Ok(())
+}
+
+fn object_path(hash: &Hash) -> String {
+    format!("/objects/{}", hash.as_string())
+}
+
+fn read_line<R: BufRead>(read: &mut R) -> Result<String> {
+    let mut line = String::new();
+    try!(read.read_line(&mut line));
+    Ok(line.trim_end().to_string())
+}
+
+/// Reads the headers and returns the content length.
+fn read_headers<R: BufRead>(read: &mut R) -> Result<usize> {
+    let mut content_length = 0;
+    loop {
+        let line = try!(read_line(read));
+        if line.is_empty() {
+            return Ok(content_length)
+        }
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim().to_lowercase();
+        let value = parts.next().unwrap_or("").trim();
+        if name == "content-length" {
+            content_length = try!(value.parse::<usize>()
+                .map_err(|_| invalid_data(format!("Invalid content length '{}'", value))));
+        }
+    }
+}
+
+fn parse_hash_lines(body: &[u8]) -> Result<Vec<Hash>> {
+    let mut res: Vec<Hash> = Vec::new();
+    for line in String::from_utf8_lossy(body).lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue
+        }
+        if line.len() != 64 || !is_hex(line) {
+            return Err(invalid_data(format!("Invalid hash '{}'", line)))
+        }
+        res.push(Hash::from_string(line.to_string()));
+    }
+    Ok(res)
+}
+
+fn invalid_data(msg: String) -> HashIOError {
+    HashIOError::IOError(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+fn status_error(status: u32, path: &str) -> HashIOError {
+    HashIOError::Undefined(format!("Unexpected HTTP status {} for {}", status, path))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fixtures::*;
+    use std::fs::remove_dir_all;
+    use std::thread;
+
+    fn start_server(path: &str) -> HashIOHttp {
+        remove_dir_all(path).ok();
+        let server = HashIOServer::bind(HashIOFile::new(path.to_string()), "127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.serve().unwrap());
+        HashIOHttp::new(format!("{}", address))
+    }
+
+    #[test]
+    fn test_http() {
+        let client = start_server("unittest/httptest");
+        let local = HashIOFile::new("unittest/httptest".to_string());
+        let changed = storage(&["a", "c"]);
+        let storage = storage(&["a", "b", "a"]);
+        let root = storage.as_hash();
+        assert!(client.have(&[root]).unwrap().is_empty());
+        match client.get::<FixtureStorage>(&root).unwrap_err().root_cause() {
+            &HashIOError::NotFound(hash) => assert_eq!(root, hash),
+            err => panic!("Unexpected error {:?}", err)
+        }
+
+        let requests = client.requests();
+        client.put(Rc::new(storage.clone())).unwrap();
+        assert_eq!(6, local.hashes().unwrap().len());
+        // One question for the whole graph and one request per object
+        assert_eq!(1 + 6, client.requests() - requests);
+        let requests = client.requests();
+        client.put(Rc::new(changed.clone())).unwrap();
+        // Only the root, the list and task c with its title are sent.
+        assert_eq!(1 + 4, client.requests() - requests);
+        assert_eq!(10, local.hashes().unwrap().len());
+        assert_eq!(client, client.clone());
+        let other = Rc::new("other".to_string()).as_hash();
+        assert_eq!(vec![root], client.have(&[other, root]).unwrap());
+        assert_eq!(vec![other], client.missing(&[other, root]).unwrap());
+        assert!(client.contains(&root).unwrap());
+        let loaded: Rc<FixtureStorage> = client.get(&root).unwrap();
+        assert_eq!(root, loaded.as_hash());
+        assert_eq!(local.read_file(&root).unwrap(), client.get_file(&root).unwrap());
+
+        assert!(client.put_file(&other, b"wrong content").is_err());
+        assert!(client.have(&[other]).unwrap().is_empty());
+    }
+}
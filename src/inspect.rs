@@ -0,0 +1,216 @@
+//! Type independent inspection of a `HashIOFile` store.
+//!
+//! # Usage
+//! Tools like the `hashio` command line program don't know the model types.
+//! The functions in this module only rely on the file format and find the
+//! references of an object with the `ReferenceDecoder` of the store.  Only
+//! references to objects which are stored in the same store are reported.
+//!
+//! Objects whose type is neither registered at the decoder nor described by
+//! one of its schemas are scanned for hash sequences.  Such a scan may
+//! report false references, so `collect_garbage` may keep some garbage and
+//! `referrers` may list objects which don't reference the object.
+
+use hash::*;
+use hashio::*;
+use format::split_header;
+use hashiofile::*;
+use std::collections::BTreeSet;
+use std::fs::read_dir;
+use std::path::Path;
+
+/// Returns the roots and all objects which are referenced by them.
+pub fn reachable(hash_io: &HashIOFile, roots: &[Hash]) -> Result<BTreeSet<Hash>> {
+    let known: BTreeSet<Hash> = try!(hash_io.hashes()).into_iter().collect();
+    let mut res: BTreeSet<Hash> = BTreeSet::new();
+    let mut pending: Vec<Hash> = roots.to_vec();
+    while let Some(hash) = pending.pop() {
+        if !res.insert(hash) {
+            continue
+        }
+        let object = try!(hash_io.get_raw(&hash));
+        pending.extend(try!(hash_io.decoder.references(&object, &known)));
+    }
+    Ok(res)
+}
+
+/// Returns the stored objects which reference the object.
+pub fn referrers(hash_io: &HashIOFile, hash: &Hash) -> Result<Vec<Hash>> {
+    let mut known: BTreeSet<Hash> = BTreeSet::new();
+    known.insert(*hash);
+    let mut res: Vec<Hash> = Vec::new();
+    for parent in try!(hash_io.hashes()) {
+        let object = try!(hash_io.get_raw(&parent));
+        if !try!(hash_io.decoder.references(&object, &known)).is_empty() {
+            res.push(parent);
+        }
+    }
+    Ok(res)
+}
+
+/// Removes all objects which are not reachable from the roots or the pins.
+///
+/// Returns the hashes of the removed objects.  With dry_run, the objects
+/// are only reported.  Make sure no other process writes into the store
+/// meanwhile, otherwise objects of an unfinished `put` could be removed.
+pub fn collect_garbage(hash_io: &HashIOFile, roots: &[Hash], dry_run: bool)
+        -> Result<Vec<Hash>> {
+    let mut roots = roots.to_vec();
+    roots.extend(try!(hash_io.pins()));
+    let keep = try!(reachable(hash_io, &roots));
+    let garbage: Vec<Hash> = try!(hash_io.hashes()).into_iter()
+        .filter(|hash| !keep.contains(hash))
+        .collect();
+    if !dry_run {
+        for hash in garbage.iter() {
+            try!(hash_io.delete_object(hash));
+        }
+    }
+    Ok(garbage)
+}
+
+/// Problem in a store which was found by `fsck`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// The content doesn't match the hash.
+    Corrupt(Hash),
+    /// A temporary file of a `put` which didn't finish.
+    Unfinished(String),
+    /// A file which doesn't belong to the store.
+    Unknown(String)
+}
+
+/// Verifies the content of all objects and looks for unexpected files.
+pub fn fsck(hash_io: &HashIOFile) -> Result<Vec<Problem>> {
+    let mut res: Vec<Problem> = Vec::new();
+    if !Path::new(&hash_io.base_path).exists() {
+        return Ok(res)
+    }
+    for hash in try!(hash_io.hashes()) {
+        let data = try!(hash_io.read_file(&hash));
+        if split_header(&hash, &data).is_none() {
+            res.push(Problem::Corrupt(hash));
+        }
+    }
+    for dir_entry in try!(read_dir(&hash_io.base_path)) {
+        let dir_entry = try!(dir_entry);
+        let dir_name = dir_entry.file_name().to_string_lossy().into_owned();
+        let path = dir_entry.path().to_string_lossy().into_owned();
+        if dir_name == "pins" || dir_name == "parents" {
+            continue
+        }
+        if dir_name.len() != 2 || !is_hex(&dir_name) || !try!(dir_entry.file_type()).is_dir() {
+            res.push(Problem::Unknown(path));
+            continue
+        }
+        for file_entry in try!(read_dir(dir_entry.path())) {
+            let file_entry = try!(file_entry);
+            let file_name = file_entry.file_name().to_string_lossy().into_owned();
+            let path = file_entry.path().to_string_lossy().into_owned();
+            if file_name.len() == 63 && file_name.ends_with('_') && is_hex(&file_name[..62]) {
+                res.push(Problem::Unfinished(path));
+            } else if file_name.len() != 62 || !is_hex(&file_name) {
+                res.push(Problem::Unknown(path));
+            }
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fixtures::*;
+    use std::io::Write;
+    use std::rc::Rc;
+    use std::fs::{File, remove_dir_all};
+
+    #[test]
+    fn test_references() {
+        remove_dir_all("./unittest/inspecttest/").ok();
+        let hash_io = HashIOFile::new("unittest/inspecttest".to_string());
+        let storage = storage(&["a", "b", "a"]);
+        let root = storage.as_hash();
+        hash_io.put(Rc::new(storage.clone())).unwrap();
+
+        let known: BTreeSet<Hash> = hash_io.hashes().unwrap().into_iter().collect();
+        let object = hash_io.get_raw(&root).unwrap();
+        assert_eq!(Some(FixtureStorage::type_hash()), object.type_hash());
+        assert_eq!(vec![storage.tasks.as_hash()],
+                   hash_io.decoder.references(&object, &known).unwrap());
+        let list = hash_io.get_raw(&storage.tasks.as_hash()).unwrap();
+        assert_eq!(None, list.header);
+        let task_hashes: Vec<Hash> = storage.tasks.iter().map(|task| task.as_hash()).collect();
+        assert_eq!(task_hashes, hash_io.decoder.references(&list, &known).unwrap());
+
+        assert_eq!(6, reachable(&hash_io, &[root]).unwrap().len());
+    }
+
+    #[test]
+    fn test_gc_and_fsck() {
+        remove_dir_all("./unittest/inspectgctest/").ok();
+        let hash_io = HashIOFile::new("unittest/inspectgctest".to_string());
+        let old = storage(&["a", "b"]);
+        let new = storage(&["a", "c"]);
+        hash_io.put(Rc::new(old.clone())).unwrap();
+        hash_io.put(Rc::new(new.clone())).unwrap();
+        assert!(fsck(&hash_io).unwrap().is_empty());
+
+        let garbage = collect_garbage(&hash_io, &[new.as_hash()], true).unwrap();
+        // old root, old list, task b and its title
+        assert_eq!(4, garbage.len());
+        assert_eq!(10, hash_io.hashes().unwrap().len());
+        assert_eq!(garbage, collect_garbage(&hash_io, &[new.as_hash()], false).unwrap());
+        assert_eq!(6, hash_io.hashes().unwrap().len());
+        let loaded: Rc<FixtureStorage> = hash_io.get(&new.as_hash()).unwrap();
+        assert_eq!(new.as_hash(), loaded.as_hash());
+
+        let title_hash = new.tasks[1].title.as_hash();
+        File::create(hash_io.filename_for_hash(&title_hash)).unwrap()
+            .write_all(b"broken").unwrap();
+        File::create(hash_io.filename_for_hash(&title_hash) + "_").unwrap();
+        assert_eq!(vec![
+            Problem::Corrupt(title_hash),
+            Problem::Unfinished(hash_io.filename_for_hash(&title_hash) + "_")
+        ], fsck(&hash_io).unwrap());
+    }
+
+    #[test]
+    fn test_remove_and_pins() {
+        remove_dir_all("./unittest/inspectpintest/").ok();
+        let hash_io = HashIOFile::new("unittest/inspectpintest".to_string());
+        let old = storage(&["a", "b"]);
+        let new = storage(&["a", "c"]);
+        hash_io.put(Rc::new(old.clone())).unwrap();
+        hash_io.put(Rc::new(new.clone())).unwrap();
+        assert!(hash_io.pin(&old.as_hash()).unwrap());
+        assert!(!hash_io.pin(&old.as_hash()).unwrap());
+        assert!(hash_io.is_pinned(&old.as_hash()).unwrap());
+        assert!(fsck(&hash_io).unwrap().is_empty());
+
+        // The pinned old root keeps its graph alive.
+        assert!(collect_garbage(&hash_io, &[new.as_hash()], false).unwrap().is_empty());
+        match hash_io.remove(&old.tasks[1].as_hash(), true) {
+            Err(HashIOError::Pinned(hash)) => assert_eq!(old.tasks[1].as_hash(), hash),
+            _ => panic!("Expected the object to be pinned")
+        }
+
+        let task_c = new.tasks[1].as_hash();
+        match hash_io.remove(&task_c, false) {
+            Err(HashIOError::Referenced(hash, parents)) => {
+                assert_eq!(task_c, hash);
+                assert_eq!(vec![new.tasks.as_hash()], parents);
+            },
+            _ => panic!("Expected the object to be referenced")
+        }
+        assert!(hash_io.remove(&new.as_hash(), false).unwrap());
+        assert!(!hash_io.remove(&new.as_hash(), false).unwrap());
+        assert!(hash_io.remove(&task_c, true).unwrap());
+        assert!(!hash_io.contains(&task_c).unwrap());
+
+        assert!(hash_io.unpin(&old.as_hash()).unwrap());
+        assert!(hash_io.pins().unwrap().is_empty());
+        // all except the new root and task c
+        assert_eq!(8, collect_garbage(&hash_io, &[], false).unwrap().len());
+    }
+}
@@ -9,6 +9,7 @@ use self::crypto::sha3::Sha3;
 use self::crypto::digest::Digest;
 use self::byteorder::{BigEndian, ByteOrder};
 use std::io;
+use std::{cmp, str};
 
 
 use hash::*;
@@ -22,7 +23,7 @@ pub fn write_u8<W>(i: u8, write: &mut W) -> Result<usize, io::Error> where W: Wr
 
 pub fn read_u8<R>(read: &mut R) -> Result<u8, io::Error> where R: Read {
     let mut bytes = [0u8; 1];
-    try!(read.read(&mut bytes));
+    try!(read.read_exact(&mut bytes));
     Ok(bytes[0])
 }
 
@@ -36,7 +37,7 @@ pub fn write_u32<W>(i: u32, write: &mut W) -> Result<usize, io::Error> where W:
 
 pub fn read_u32<R>(read: &mut R) -> Result<u32, io::Error> where R: Read {
     let mut bytes = [0u8; 4];
-    try!(read.read(&mut bytes));
+    try!(read.read_exact(&mut bytes));
     Ok(BigEndian::read_u32(&bytes))
 }
 
@@ -48,7 +49,7 @@ pub fn write_i32<W>(i: i32, write: &mut W) -> Result<usize, io::Error> where W:
 
 pub fn read_i32<R>(read: &mut R) -> Result<i32, io::Error> where R: Read {
     let mut bytes = [0u8; 4];
-    try!(read.read(&mut bytes));
+    try!(read.read_exact(&mut bytes));
     Ok(BigEndian::read_i32(&bytes))
 }
 
@@ -60,7 +61,7 @@ pub fn write_i16<W>(i: i16, write: &mut W) -> Result<usize, io::Error> where W:
 
 pub fn read_i16<R>(read: &mut R) -> Result<i16, io::Error> where R: Read {
     let mut bytes = [0u8; 2];
-    try!(read.read(&mut bytes));
+    try!(read.read_exact(&mut bytes));
     Ok(BigEndian::read_i16(&bytes))
 }
 
@@ -72,7 +73,7 @@ pub fn write_f32<W>(f: f32, write: &mut W) -> Result<usize, io::Error> where W:
 
 pub fn read_f32<R>(read: &mut R) -> Result<f32, io::Error> where R: Read {
     let mut bytes = [0u8; 4];
-    try!(read.read(&mut bytes));
+    try!(read.read_exact(&mut bytes));
     Ok(BigEndian::read_f32(&bytes))
 }
 
@@ -153,16 +154,50 @@ pub fn usize_to_u32_bytes(x: usize) -> [u8; 4] {
     res
 }
 
+/// Length limit of `read_bytes`.
+pub const DEFAULT_MAX_BYTES_LEN: usize = 64 * 1024 * 1024;
+
+/// Reads exactly n bytes.
+///
+/// Fails with `InvalidData` if n is larger than `DEFAULT_MAX_BYTES_LEN`.
 pub fn read_bytes(reader: &mut Read, n: usize) -> Result<Vec<u8>, io::Error> {
-    let mut buffer: [u8; 1] = [0; 1];
-    let mut res: Vec<u8> = Vec::with_capacity(n);
-    for _ in 0..n {
-        try!(reader.read(&mut buffer));
-        res.push(buffer[0]);
+    read_bytes_max(reader, n, DEFAULT_MAX_BYTES_LEN)
+}
+
+/// Reads exactly n bytes and fails with `InvalidData` if n is larger than max.
+///
+/// The buffer grows with the data which is actually read, so a corrupted
+/// length can't allocate more memory than the input provides.
+pub fn read_bytes_max(reader: &mut Read, n: usize, max: usize) -> Result<Vec<u8>, io::Error> {
+    if n > max {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("Length {} exceeds the limit of {} bytes", n, max)))
+    }
+    let mut res: Vec<u8> = Vec::with_capacity(cmp::min(n, 64 * 1024));
+    try!(reader.take(n as u64).read_to_end(&mut res));
+    if res.len() < n {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer"))
     }
     Ok(res)
 }
 
+/// Parses a length prefixed string like it is written by `String` without
+/// copying it.
+///
+/// Returns the string and the remaining bytes.
+pub fn read_str(bytes: &[u8]) -> Result<(&str, &[u8]), io::Error> {
+    if bytes.len() < 4 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer"))
+    }
+    let len = BigEndian::read_u32(&bytes[0..4]) as usize;
+    if bytes.len() - 4 < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer"))
+    }
+    let string = try!(str::from_utf8(&bytes[4..4 + len])
+                      .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)));
+    Ok((string, &bytes[4 + len..]))
+}
+
 pub fn write_hash<W>(hash: &Hash, write: &mut W) -> Result<usize, io::Error> where W: Write {
     let bytes = hash.get_bytes();
     try!(write_u8(match hash {
@@ -177,7 +212,7 @@ pub fn read_hash<R>(read: &mut R) -> Result<Hash, io::Error> where R: Read {
     match identifier {
         1 => {
             let mut bytes = [0u8; 32];
-            try!(read.read(&mut bytes));
+            try!(read.read_exact(&mut bytes));
             Ok(Hash::Sha3(bytes))
         }
         _ => Ok(Hash::None)
@@ -225,4 +260,39 @@ pub fn read_tm<R>(read: &mut R) -> Result<Tm, io::Error> where R: Read {
         tm_utcoff: utcoff,
         tm_nsec: nsec
     })
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_read_bytes_max() {
+        let data = [1u8, 2, 3, 4, 5];
+        let mut read: &[u8] = &data;
+        assert_eq!(vec![1u8, 2, 3], read_bytes_max(&mut read, 3, 3).unwrap());
+        assert_eq!(vec![4u8, 5], read_bytes(&mut read, 2).unwrap());
+
+        let mut read: &[u8] = &data;
+        assert_eq!(io::ErrorKind::InvalidData,
+                   read_bytes_max(&mut read, 4, 3).unwrap_err().kind());
+        assert_eq!(io::ErrorKind::InvalidData,
+                   read_bytes(&mut read, 0xffffffff).unwrap_err().kind());
+        assert_eq!(io::ErrorKind::UnexpectedEof,
+                   read_bytes(&mut read, 1000).unwrap_err().kind());
+        let mut read: &[u8] = &data[0..2];
+        assert_eq!(io::ErrorKind::UnexpectedEof, read_u32(&mut read).unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_read_str() {
+        let mut data: Vec<u8> = Vec::new();
+        "Hello".to_string().write_to(&mut data).unwrap();
+        data.push(42);
+        let (string, rest) = read_str(&data).unwrap();
+        assert_eq!("Hello", string);
+        assert_eq!(&[42u8], rest);
+        assert!(read_str(&data[0..6]).is_err());
+    }
+}
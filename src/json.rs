@@ -0,0 +1,517 @@
+//! Minimal JSON representation used for machine-readable output.
+//!
+//! # Usage
+//! Build a `Json` value and turn it into a string with `to_string` or
+//! `to_pretty_string`.  `Json::parse` reads a string back.  Objects keep
+//! their keys sorted so the output is deterministic.
+//!
+//! The helpers like `get_string` and `get_hash` read the values of parsed
+//! documents and report missing values as `FieldError`.
+
+use hash::*;
+use std::collections::BTreeMap;
+use std::{error, fmt, result};
+
+/// Maximum nesting of arrays and objects `Json::parse` accepts.
+pub const MAX_DEPTH: usize = 128;
+
+/// JSON value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(BTreeMap<String, Json>)
+}
+
+/// Error while parsing JSON which contains the position of the failure.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonError {
+    pub position: usize,
+    pub message: String
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "JSON error at {}: {}", self.position, self.message)
+    }
+}
+
+impl error::Error for JsonError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+/// Value of a JSON document which is missing or invalid.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldError {
+    pub message: String
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for FieldError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+fn field_error(message: String) -> FieldError {
+    FieldError { message: message }
+}
+
+/// Builds an object from its entries.
+pub fn json_object(entries: Vec<(&str, Json)>) -> Json {
+    let mut object: BTreeMap<String, Json> = BTreeMap::new();
+    for (key, value) in entries {
+        object.insert(key.to_string(), value);
+    }
+    Json::Object(object)
+}
+
+pub fn json_str(string: &str) -> Json {
+    Json::String(string.to_string())
+}
+
+/// Returns the string which is stored under the key of an object.
+pub fn get_string(json: &Json, key: &str) -> result::Result<String, FieldError> {
+    json.get(key)
+        .and_then(|value| value.as_str())
+        .map(|value| value.to_string())
+        .ok_or(field_error(format!("Missing string '{}'", key)))
+}
+
+/// Returns the array which is stored under the key of an object.
+pub fn get_array<'a>(json: &'a Json, key: &str) -> result::Result<&'a Vec<Json>, FieldError> {
+    json.get(key)
+        .and_then(|value| value.as_array())
+        .ok_or(field_error(format!("Missing array '{}'", key)))
+}
+
+/// Returns the hash which is stored as hex string under the key of an object.
+pub fn get_hash(json: &Json, key: &str) -> result::Result<Hash, FieldError> {
+    parse_hash(&try!(get_string(json, key)))
+}
+
+/// Reads a hash from the string of `Hash::as_string`.
+pub fn parse_hash(string: &str) -> result::Result<Hash, FieldError> {
+    if string.len() != 64 || !is_hex(string) {
+        return Err(field_error(format!("Invalid hash '{}'", string)))
+    }
+    Ok(Hash::from_string(string.to_string()))
+}
+
+impl Json {
+    /// Returns the string if this is a string value.
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref string) => Some(string),
+            _ => None
+        }
+    }
+
+    /// Returns the number if this is a number value.
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Json::Number(number) => Some(number),
+            _ => None
+        }
+    }
+
+    /// Returns the elements if this is an array.
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        match *self {
+            Json::Array(ref array) => Some(array),
+            _ => None
+        }
+    }
+
+    /// Returns the entries if this is an object.
+    pub fn as_object(&self) -> Option<&BTreeMap<String, Json>> {
+        match *self {
+            Json::Object(ref object) => Some(object),
+            _ => None
+        }
+    }
+
+    /// Looks up a key if this is an object.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        self.as_object().and_then(|object| object.get(key))
+    }
+
+    /// Serializes the value into a compact string.
+    pub fn to_string(&self) -> String {
+        let mut res = String::new();
+        self.write_json(&mut res, None, 0);
+        res
+    }
+
+    /// Serializes the value into an indented string.
+    pub fn to_pretty_string(&self) -> String {
+        let mut res = String::new();
+        self.write_json(&mut res, Some(2), 0);
+        res
+    }
+
+    fn write_json(&self, out: &mut String, indent: Option<usize>, level: usize) {
+        match *self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(value) => out.push_str(if value { "true" } else { "false" }),
+            Json::Number(number) => out.push_str(&format_number(number)),
+            Json::String(ref string) => write_string(out, string),
+            Json::Array(ref array) => {
+                if array.is_empty() {
+                    out.push_str("[]");
+                    return
+                }
+                out.push('[');
+                for (i, item) in array.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    write_newline(out, indent, level + 1);
+                    item.write_json(out, indent, level + 1);
+                }
+                write_newline(out, indent, level);
+                out.push(']');
+            },
+            Json::Object(ref object) => {
+                if object.is_empty() {
+                    out.push_str("{}");
+                    return
+                }
+                out.push('{');
+                for (i, (key, item)) in object.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    write_newline(out, indent, level + 1);
+                    write_string(out, key);
+                    out.push(':');
+                    if indent.is_some() {
+                        out.push(' ');
+                    }
+                    item.write_json(out, indent, level + 1);
+                }
+                write_newline(out, indent, level);
+                out.push('}');
+            }
+        }
+    }
+
+    /// Parses a JSON string.
+    pub fn parse(input: &str) -> Result<Json, JsonError> {
+        let mut parser = Parser {
+            chars: input.chars().collect(),
+            pos: 0,
+            depth: 0
+        };
+        let value = try!(parser.parse_value());
+        parser.skip_whitespace();
+        if parser.pos < parser.chars.len() {
+            return Err(parser.error("Unexpected trailing characters"))
+        }
+        Ok(value)
+    }
+}
+
+fn format_number(number: f64) -> String {
+    if number == 0.0 && number.is_sign_negative() {
+        "-0".to_string()
+    } else if number.is_finite() && number == number.trunc() && number.abs() < 1e15 {
+        format!("{}", number as i64)
+    } else {
+        format!("{:?}", number)
+    }
+}
+
+fn write_newline(out: &mut String, indent: Option<usize>, level: usize) {
+    if let Some(indent) = indent {
+        out.push('\n');
+        for _ in 0..(indent * level) {
+            out.push(' ');
+        }
+    }
+}
+
+fn write_string(out: &mut String, string: &str) {
+    out.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    /// Number of arrays and objects around the current position.
+    depth: usize
+}
+
+impl Parser {
+    fn error(&self, message: &str) -> JsonError {
+        JsonError {
+            position: self.pos,
+            message: message.to_string()
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), JsonError> {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("Expected '{}'", c)))
+        }
+    }
+
+    fn expect_word(&mut self, word: &str, value: Json) -> Result<Json, JsonError> {
+        for c in word.chars() {
+            if self.peek() != Some(c) {
+                return Err(self.error(&format!("Expected '{}'", word)))
+            }
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+
+    fn parse_value(&mut self) -> Result<Json, JsonError> {
+        self.skip_whitespace();
+        match self.peek() {
+            None => Err(self.error("Unexpected end of input")),
+            Some('n') => self.expect_word("null", Json::Null),
+            Some('t') => self.expect_word("true", Json::Bool(true)),
+            Some('f') => self.expect_word("false", Json::Bool(false)),
+            Some('"') => Ok(Json::String(try!(self.parse_string()))),
+            Some(c) if c == '[' || c == '{' => {
+                if self.depth >= MAX_DEPTH {
+                    return Err(self.error("Nesting too deep"))
+                }
+                self.depth += 1;
+                let res = if c == '[' { self.parse_array() } else { self.parse_object() };
+                self.depth -= 1;
+                res
+            },
+            Some(_) => self.parse_number()
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<Json, JsonError> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_digit(10) || c == '-' || c == '+' || c == '.' || c == 'e' || c == 'E' {
+                self.pos += 1;
+            } else {
+                break
+            }
+        }
+        let number_string: String = self.chars[start..self.pos].iter().cloned().collect();
+        number_string.parse::<f64>()
+            .map(Json::Number)
+            .map_err(|_| JsonError {
+                position: start,
+                message: format!("Invalid number '{}'", number_string)
+            })
+    }
+
+    fn parse_string(&mut self) -> Result<String, JsonError> {
+        try!(self.expect('"'));
+        let mut res = String::new();
+        loop {
+            let c = match self.peek() {
+                None => return Err(self.error("Unterminated string")),
+                Some(c) => c
+            };
+            self.pos += 1;
+            match c {
+                '"' => return Ok(res),
+                '\\' => {
+                    let escaped = match self.peek() {
+                        None => return Err(self.error("Unterminated string")),
+                        Some(escaped) => escaped
+                    };
+                    self.pos += 1;
+                    match escaped {
+                        '"' => res.push('"'),
+                        '\\' => res.push('\\'),
+                        '/' => res.push('/'),
+                        'b' => res.push('\u{8}'),
+                        'f' => res.push('\u{c}'),
+                        'n' => res.push('\n'),
+                        'r' => res.push('\r'),
+                        't' => res.push('\t'),
+                        'u' => {
+                            let mut code = try!(self.parse_code_unit());
+                            if (0xd800..0xdc00).contains(&code) {
+                                // High surrogate, the low one must follow.
+                                if self.peek() != Some('\\') {
+                                    return Err(self.error("Unpaired surrogate"))
+                                }
+                                self.pos += 1;
+                                if self.peek() != Some('u') {
+                                    return Err(self.error("Unpaired surrogate"))
+                                }
+                                self.pos += 1;
+                                let low = try!(self.parse_code_unit());
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return Err(self.error("Unpaired surrogate"))
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            res.push(try!(::std::char::from_u32(code)
+                                .ok_or(self.error("Invalid unicode escape"))));
+                        },
+                        _ => return Err(self.error("Invalid escape sequence"))
+                    }
+                },
+                c => res.push(c)
+            }
+        }
+    }
+
+    /// Reads the four hex digits of a `\u` escape.
+    fn parse_code_unit(&mut self) -> Result<u32, JsonError> {
+        if self.pos + 4 > self.chars.len() {
+            return Err(self.error("Invalid unicode escape"))
+        }
+        let code_string: String = self.chars[self.pos..self.pos + 4].iter().cloned().collect();
+        self.pos += 4;
+        u32::from_str_radix(&code_string, 16).map_err(|_| self.error("Invalid unicode escape"))
+    }
+
+    fn parse_array(&mut self) -> Result<Json, JsonError> {
+        try!(self.expect('['));
+        let mut res: Vec<Json> = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(Json::Array(res))
+        }
+        loop {
+            res.push(try!(self.parse_value()));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => {
+                    self.pos += 1;
+                    return Ok(Json::Array(res))
+                },
+                _ => return Err(self.error("Expected ',' or ']'"))
+            }
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<Json, JsonError> {
+        try!(self.expect('{'));
+        let mut res: BTreeMap<String, Json> = BTreeMap::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Json::Object(res))
+        }
+        loop {
+            self.skip_whitespace();
+            let key = try!(self.parse_string());
+            try!(self.expect(':'));
+            let value = try!(self.parse_value());
+            res.insert(key, value);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => {
+                    self.pos += 1;
+                    return Ok(Json::Object(res))
+                },
+                _ => return Err(self.error("Expected ',' or '}'"))
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut object: BTreeMap<String, Json> = BTreeMap::new();
+        object.insert("name".to_string(), Json::String("a \"quoted\"\n\u{1}".to_string()));
+        object.insert("int".to_string(), Json::Number(42.0));
+        object.insert("float".to_string(), Json::Number(0.2f32 as f64));
+        object.insert("list".to_string(), Json::Array(vec![Json::Null, Json::Bool(true),
+                                                          Json::Array(vec![])]));
+        object.insert("empty".to_string(), Json::Object(BTreeMap::new()));
+        let json = Json::Object(object);
+
+        assert_eq!(json, Json::parse(&json.to_string()).unwrap());
+        assert_eq!(json, Json::parse(&json.to_pretty_string()).unwrap());
+        assert_eq!("{\"empty\":{},\"float\":0.20000000298023224,\"int\":42,",
+                   &json.to_string()[0..49]);
+        assert_eq!(0.2f32, json.get("float").unwrap().as_f64().unwrap() as f32);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(Json::parse("{\"a\": }").is_err());
+        assert!(Json::parse("[1, 2").is_err());
+        assert!(Json::parse("\"abc").is_err());
+        assert!(Json::parse("1 2").is_err());
+        assert_eq!(Json::String("\u{e9}".to_string()), Json::parse("\"\\u00e9\"").unwrap());
+    }
+
+    #[test]
+    fn test_surrogates() {
+        assert_eq!(Json::String("\u{1f600}".to_string()),
+                   Json::parse("\"\\uD83D\\uDE00\"").unwrap());
+        assert!(Json::parse("\"\\ud83d\"").is_err());
+        assert!(Json::parse("\"\\ud83dx\"").is_err());
+        assert!(Json::parse("\"\\ud83d\\u0041\"").is_err());
+        assert!(Json::parse("\"\\ude00\"").is_err());
+    }
+
+    #[test]
+    fn test_depth() {
+        let nested = |depth: usize| "[".repeat(depth) + &"]".repeat(depth);
+        assert!(Json::parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(Json::parse(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(Json::parse(&"{\"a\":".repeat(100000)).is_err());
+    }
+
+    #[test]
+    fn test_negative_zero() {
+        let json = Json::Array(vec![Json::Number(-0.0), Json::Number(0.0)]);
+        assert_eq!("[-0,0]", json.to_string());
+        let parsed = Json::parse(&json.to_string()).unwrap();
+        assert!(parsed.as_array().unwrap()[0].as_f64().unwrap().is_sign_negative());
+        assert!(parsed.as_array().unwrap()[1].as_f64().unwrap().is_sign_positive());
+    }
+}
@@ -8,12 +8,37 @@ pub mod io;
 #[macro_use]
 pub mod hashio_model;
 pub mod hashio;
+pub mod format;
+pub mod json;
+pub mod schema;
+pub mod references;
 
 pub mod hashiofile;
+pub mod migration;
+pub mod registry;
 
 pub mod string;
 pub mod vec;
 pub mod btreemap;
+pub mod btreeset;
+pub mod hashmap;
+pub mod hashset;
+pub mod vecdeque;
+pub mod hamt;
+pub mod pvec;
+pub mod blob;
+pub mod diff;
+#[macro_use]
+pub mod merge;
+pub mod export;
+pub mod inspect;
+pub mod dot;
+pub mod stats;
+pub mod sync;
+pub mod bundle;
+pub mod http;
+#[cfg(test)]
+mod fixtures;
 
 pub mod lazyio;
 pub mod logger;
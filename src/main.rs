@@ -0,0 +1,343 @@
+//! Command line tool to inspect a `HashIOFile` store.
+//!
+//! Run `hashio` without arguments for the list of commands.  Hashes can be
+//! abbreviated as long as the prefix is unique in the store.
+
+extern crate hashio;
+
+use hashio::hash::Hash;
+use hashio::hashio::{HashIO, HashIOError, HashIORemove, Result};
+use hashio::hashiofile::HashIOFile;
+use hashio::inspect::*;
+use hashio::io::read_str;
+use hashio::export::JsonExporter;
+use hashio::dot::DotGraph;
+use hashio::http::HashIOServer;
+use hashio::references::ReferenceDecoder;
+use hashio::stats::*;
+use hashio::schema::{TypeSchema, schemas_from_json};
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs::{File, metadata};
+use std::io::Read;
+use std::process::exit;
+
+const USAGE: &str = "Usage: hashio <store> <command> [arguments]
+
+Commands:
+    ls                              List all objects with their sizes
+    cat <hash> [--hex]              Print the header and the payload of an object
+    children <hash>                 List the objects referenced by an object
+    parents <hash>                  List the objects which reference an object
+                                    according to the reverse index
+    reindex                         Rebuild the reverse index from all objects
+    fsck                            Verify the content of all objects
+    gc --root <hash>... [--dry-run] Remove objects which are not reachable from
+                                    the roots or the pinned objects
+    rm <hash> [--force]             Remove an object, with --force even if it is
+                                    referenced by other objects
+    pin <hash>                      Protect an object and its children from removal
+    unpin <hash>                    Remove the protection of an object
+    pins                            List the pinned objects
+    stats [--root <hash>]           Print object counts and sizes per type or the
+                                    unique and shared size below a root
+    export <hash> [--type <name>]   Print the graph below the object as JSON
+    dot <hash>                      Print the graph below the object for Graphviz
+    serve <address>                 Serve the store over HTTP, like localhost:8080
+
+Options:
+    --schemas <file>                Schema snapshot used for type names, references
+                                    and export";
+
+/// Parsed command line arguments.
+struct Args {
+    positional: Vec<String>,
+    options: BTreeMap<String, Vec<String>>,
+    flags: BTreeSet<String>
+}
+
+impl Args {
+    fn parse(args: Vec<String>) -> Args {
+        let mut res = Args {
+            positional: Vec::new(),
+            options: BTreeMap::new(),
+            flags: BTreeSet::new()
+        };
+        let mut iter = args.into_iter();
+        while let Some(arg) = iter.next() {
+            match &*arg {
+                "--hex" | "--dry-run" | "--force" => {
+                    res.flags.insert(arg.clone());
+                },
+                "--root" | "--type" | "--schemas" => {
+                    let value = iter.next().unwrap_or_else(|| fail(&format!("{} needs a value", arg)));
+                    res.options.entry(arg.clone()).or_default().push(value);
+                },
+                _ if arg.starts_with("--") => fail(&format!("Unknown option {}", arg)),
+                _ => res.positional.push(arg)
+            }
+        }
+        res
+    }
+
+    fn option(&self, name: &str) -> Option<&String> {
+        self.options.get(name).and_then(|values| values.last())
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.contains(name)
+    }
+
+    /// Returns the positional argument or exits with the usage.
+    fn positional(&self, i: usize) -> &str {
+        match self.positional.get(i) {
+            Some(value) => value,
+            None => fail(USAGE)
+        }
+    }
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    exit(2)
+}
+
+fn main() {
+    let args = Args::parse(env::args().skip(1).collect());
+    let schemas = match args.option("--schemas") {
+        Some(filename) => load_schemas(filename).unwrap_or_else(|err| fail(&err)),
+        None => Vec::new()
+    };
+    let hash_io = HashIOFile::new(args.positional(0).to_string())
+        .with_decoder(ReferenceDecoder::new().with_schemas(&schemas));
+    let res = match args.positional(1) {
+        "ls" => ls(&hash_io, &schemas),
+        "cat" => cat(&hash_io, &schemas, args.positional(2), args.flag("--hex")),
+        "children" => children(&hash_io, args.positional(2)),
+        "parents" => parents(&hash_io, args.positional(2)),
+        "reindex" => hash_io.rebuild_parent_index().map(|_| true),
+        "fsck" => check(&hash_io),
+        "gc" => gc(&hash_io, args.options.get("--root"), args.flag("--dry-run")),
+        "rm" => rm(&hash_io, args.positional(2), args.flag("--force")),
+        "pin" => pin(&hash_io, args.positional(2)),
+        "unpin" => unpin(&hash_io, args.positional(2)),
+        "pins" => pins(&hash_io),
+        "stats" => stats(&hash_io, &schemas, args.option("--root")),
+        "export" => export(&hash_io, &schemas, args.positional(2), args.option("--type")),
+        "dot" => dot(&hash_io, &schemas, args.positional(2)),
+        "serve" => serve(hash_io.clone(), args.positional(2)),
+        _ => fail(USAGE)
+    };
+    match res {
+        Ok(true) => (),
+        Ok(false) => exit(1),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            exit(1)
+        }
+    }
+}
+
+fn load_schemas(filename: &str) -> ::std::result::Result<Vec<TypeSchema>, String> {
+    let mut input = String::new();
+    try!(File::open(filename)
+         .and_then(|mut file| file.read_to_string(&mut input))
+         .map_err(|err| format!("Cannot read {}: {}", filename, err)));
+    schemas_from_json(&input).map_err(|err| format!("Cannot read {}: {}", filename, err))
+}
+
+/// Finds the object whose hash starts with the given prefix.
+fn resolve(hash_io: &HashIOFile, prefix: &str) -> Result<Hash> {
+    let matches: Vec<Hash> = try!(hash_io.hashes()).into_iter()
+        .filter(|hash| hash.as_string().starts_with(&prefix.to_lowercase()))
+        .collect();
+    match matches.len() {
+        1 => Ok(matches[0]),
+        0 => Err(HashIOError::Undefined(format!("No object matches {}", prefix))),
+        _ => Err(HashIOError::Undefined(format!("{} is ambiguous", prefix)))
+    }
+}
+
+fn type_name(schemas: &[TypeSchema], type_hash: &Hash) -> Option<String> {
+    schemas.iter()
+        .find(|schema| schema.accepts(type_hash))
+        .map(|schema| schema.name.clone())
+}
+
+/// Type name or type hash of the header, "-" for objects without header.
+fn type_label(schemas: &[TypeSchema], header: &Option<(u32, Hash)>) -> String {
+    match *header {
+        None => "-".to_string(),
+        Some((_, ref type_hash)) => type_name(schemas, type_hash)
+            .unwrap_or_else(|| type_hash.as_string())
+    }
+}
+
+fn known_hashes(hash_io: &HashIOFile) -> Result<BTreeSet<Hash>> {
+    Ok(try!(hash_io.hashes()).into_iter().collect())
+}
+
+fn ls(hash_io: &HashIOFile, schemas: &[TypeSchema]) -> Result<bool> {
+    for hash in try!(hash_io.hashes()) {
+        let size = try!(metadata(hash_io.filename_for_hash(&hash))).len();
+        let label = match hash_io.get_raw(&hash) {
+            Ok(object) => type_label(schemas, &object.header),
+            Err(_) => "corrupt".to_string()
+        };
+        println!("{} {:>10} {}", hash.as_string(), size, label);
+    }
+    Ok(true)
+}
+
+fn cat(hash_io: &HashIOFile, schemas: &[TypeSchema], prefix: &str, hex: bool) -> Result<bool> {
+    let object = try!(hash_io.get_raw(&try!(resolve(hash_io, prefix))));
+    println!("hash:      {}", object.hash.as_string());
+    if let Some((version, type_hash)) = object.header {
+        println!("version:   {}", version);
+        println!("type hash: {}", type_hash.as_string());
+        if let Some(type_name) = type_name(schemas, &type_hash) {
+            println!("type:      {}", type_name);
+        }
+    }
+    println!("size:      {}", object.file_len());
+    match read_str(&object.payload) {
+        Ok((string, rest)) if !hex && object.header.is_none() && rest.is_empty() =>
+            println!("string:    {:?}", string),
+        _ => {
+            println!("payload:");
+            for (i, line) in object.payload.chunks(16).enumerate() {
+                let bytes: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+                println!("{:08x}  {}", i * 16, bytes.join(" "));
+            }
+        }
+    }
+    Ok(true)
+}
+
+fn children(hash_io: &HashIOFile, prefix: &str) -> Result<bool> {
+    let object = try!(hash_io.get_raw(&try!(resolve(hash_io, prefix))));
+    for hash in try!(hash_io.decoder.references(&object, &try!(known_hashes(hash_io)))) {
+        println!("{}", hash.as_string());
+    }
+    Ok(true)
+}
+
+fn parents(hash_io: &HashIOFile, prefix: &str) -> Result<bool> {
+    for hash in try!(hash_io.parents_of(&try!(resolve(hash_io, prefix)))) {
+        println!("{}", hash.as_string());
+    }
+    Ok(true)
+}
+
+fn check(hash_io: &HashIOFile) -> Result<bool> {
+    let problems = try!(fsck(hash_io));
+    for problem in problems.iter() {
+        match *problem {
+            Problem::Corrupt(ref hash) => println!("corrupt:    {}", hash.as_string()),
+            Problem::Unfinished(ref path) => println!("unfinished: {}", path),
+            Problem::Unknown(ref path) => println!("unknown:    {}", path)
+        }
+    }
+    Ok(problems.is_empty())
+}
+
+fn gc(hash_io: &HashIOFile, roots: Option<&Vec<String>>, dry_run: bool) -> Result<bool> {
+    let roots = match roots {
+        Some(roots) => roots,
+        None => fail("gc needs at least one --root")
+    };
+    let mut root_hashes: Vec<Hash> = Vec::new();
+    for root in roots {
+        root_hashes.push(try!(resolve(hash_io, root)));
+    }
+    let removed = try!(collect_garbage(hash_io, &root_hashes, dry_run));
+    for hash in removed.iter() {
+        println!("{}", hash.as_string());
+    }
+    println!("{} {} objects", if dry_run { "Would remove" } else { "Removed" }, removed.len());
+    Ok(true)
+}
+
+fn rm(hash_io: &HashIOFile, prefix: &str, force: bool) -> Result<bool> {
+    let hash = try!(resolve(hash_io, prefix));
+    match hash_io.remove(&hash, force) {
+        Err(HashIOError::Referenced(_, ref parents)) => {
+            println!("Referenced by:");
+            for parent in parents.iter() {
+                println!("{}", parent.as_string());
+            }
+            println!("Use --force to remove it anyway");
+            Ok(false)
+        },
+        res => res
+    }
+}
+
+fn pin(hash_io: &HashIOFile, prefix: &str) -> Result<bool> {
+    try!(hash_io.pin(&try!(resolve(hash_io, prefix))));
+    Ok(true)
+}
+
+fn unpin(hash_io: &HashIOFile, prefix: &str) -> Result<bool> {
+    hash_io.unpin(&try!(resolve(hash_io, prefix)))
+}
+
+fn pins(hash_io: &HashIOFile) -> Result<bool> {
+    for hash in try!(hash_io.pins()) {
+        println!("{}", hash.as_string());
+    }
+    Ok(true)
+}
+
+fn stats(hash_io: &HashIOFile, schemas: &[TypeSchema], root: Option<&String>) -> Result<bool> {
+    match root {
+        Some(root) => {
+            let subtree = try!(subtree_stats(hash_io, &try!(resolve(hash_io, root))));
+            println!("objects: {:>8} {:>12} bytes", subtree.object_count, subtree.total_bytes);
+            println!("unique:  {:>8} {:>12} bytes", subtree.unique_count, subtree.unique_bytes);
+            println!("shared:  {:>8} {:>12} bytes", subtree.shared_count, subtree.shared_bytes);
+        },
+        None => print!("{}", try!(StoreStats::collect(hash_io, schemas)).report())
+    }
+    Ok(true)
+}
+
+fn export(hash_io: &HashIOFile, schemas: &[TypeSchema], prefix: &str,
+          type_name: Option<&String>) -> Result<bool> {
+    let root = try!(resolve(hash_io, prefix));
+    let mut exporter = JsonExporter::new();
+    for schema in schemas {
+        exporter.add_schema(schema.clone());
+    }
+    let type_name = type_name.map(|type_name| type_name.as_str()).unwrap_or("");
+    println!("{}", try!(exporter.export_as(hash_io, &root, type_name)).to_pretty_string());
+    Ok(true)
+}
+
+/// Renders the references which are found in the stored data.  Without the
+/// types, the edges are labelled with the position of the reference.
+fn dot(hash_io: &HashIOFile, schemas: &[TypeSchema], prefix: &str) -> Result<bool> {
+    let root = try!(resolve(hash_io, prefix));
+    let known = try!(known_hashes(hash_io));
+    let mut graph = DotGraph::new();
+    let mut pending: Vec<Hash> = vec![root];
+    while let Some(hash) = pending.pop() {
+        let object = try!(hash_io.get_raw(&hash));
+        if !graph.add_node(&hash, &type_label(schemas, &object.header)) {
+            continue
+        }
+        for (i, child) in try!(hash_io.decoder.references(&object, &known)).into_iter().enumerate() {
+            graph.add_edge(&hash, &child, &format!("{}", i));
+            pending.push(child);
+        }
+    }
+    print!("{}", graph.to_dot());
+    Ok(true)
+}
+
+fn serve(hash_io: HashIOFile, address: &str) -> Result<bool> {
+    let server = try!(HashIOServer::bind(hash_io, address));
+    eprintln!("Listening on {}", try!(server.local_addr()));
+    try!(server.serve());
+    Ok(true)
+}
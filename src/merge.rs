@@ -0,0 +1,593 @@
+//! Three-way merge of object graphs.
+//!
+//! # Usage
+//! Types which implement `Merge3` can combine the changes of two versions
+//! which were both derived from a common base.  `String` is merged as one
+//! value, `Vec` with a diff3 over the item hashes and `BTreeMap` key by key.
+//! Models opt in with the `hashio_merge!` macro which merges attribute by
+//! attribute and child by child.
+//!
+//! If both sides changed the same value differently, the resolver which was
+//! registered for the type in the `MergeContext` is asked.  If there is no
+//! resolver or it doesn't decide, a `Conflict` is recorded and our version is
+//! kept.  If one side removed a map entry which the other side changed, the
+//! changed entry is kept and a conflict is recorded as well.
+
+use hash::*;
+use hashio::*;
+use diff::join_path;
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+use std::rc::Rc;
+
+/// Value which was changed differently on both sides.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    pub path: String,
+    pub type_name: String,
+    pub base: Option<Hash>,
+    pub ours: Option<Hash>,
+    pub theirs: Option<Hash>
+}
+
+/// Result of a merge of stored roots.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeResult {
+    pub root: Hash,
+    pub conflicts: Vec<Conflict>
+}
+
+/// Function which decides a conflict for a type.
+///
+/// It gets the base, if there is one, our and their version.  If it returns
+/// None, the conflict is recorded.
+pub type Resolver<T> = Box<Fn(Option<&Rc<T>>, &Rc<T>, &Rc<T>) -> Option<Rc<T>>>;
+
+/// Holds the resolvers and collects the conflicts during a merge.
+pub struct MergeContext {
+    resolvers: BTreeMap<Hash, Box<Any>>,
+    conflicts: Vec<Conflict>
+}
+
+impl MergeContext {
+    pub fn new() -> MergeContext {
+        MergeContext {
+            resolvers: BTreeMap::new(),
+            conflicts: Vec::new()
+        }
+    }
+
+    /// Registers the resolver for conflicts of type T.
+    pub fn add_resolver<T, F>(&mut self, resolver: F)
+            where T: HashIOParse + 'static,
+                  F: Fn(Option<&Rc<T>>, &Rc<T>, &Rc<T>) -> Option<Rc<T>> + 'static {
+        let resolver: Resolver<T> = Box::new(resolver);
+        self.resolvers.insert(T::type_hash(), Box::new(resolver));
+    }
+
+    /// Builder style variant of add_resolver.
+    pub fn with_resolver<T, F>(mut self, resolver: F) -> MergeContext
+            where T: HashIOParse + 'static,
+                  F: Fn(Option<&Rc<T>>, &Rc<T>, &Rc<T>) -> Option<Rc<T>> + 'static {
+        self.add_resolver::<T, F>(resolver);
+        self
+    }
+
+    /// Conflicts which were found so far.
+    pub fn conflicts(&self) -> &[Conflict] {
+        &self.conflicts
+    }
+
+    /// Asks the resolver of the type and records a conflict if it can't decide.
+    ///
+    /// Returns the resolved value or our version.
+    pub fn conflict<T>(&mut self, path: &str, base: Option<&Rc<T>>,
+                       ours: &Rc<T>, theirs: &Rc<T>) -> Rc<T>
+            where T: HashIOParse + 'static {
+        let resolved = self.resolvers.get(&T::type_hash())
+            .and_then(|resolver| resolver.downcast_ref::<Resolver<T>>())
+            .and_then(|resolver| resolver(base, ours, theirs));
+        match resolved {
+            Some(res) => res,
+            None => {
+                self.add_conflict::<T>(path, base.map(|x| x.as_hash()),
+                                       Some(ours.as_hash()), Some(theirs.as_hash()));
+                ours.clone()
+            }
+        }
+    }
+
+    /// Records a conflict without asking a resolver.
+    pub fn add_conflict<T>(&mut self, path: &str, base: Option<Hash>,
+                           ours: Option<Hash>, theirs: Option<Hash>)
+            where T: HashIOParse {
+        self.conflicts.push(Conflict {
+            path: path.to_string(),
+            type_name: T::type_name(),
+            base: base,
+            ours: ours,
+            theirs: theirs
+        });
+    }
+}
+
+impl Default for MergeContext {
+    fn default() -> MergeContext {
+        MergeContext::new()
+    }
+}
+
+
+/// Types which support three-way merges.
+pub trait Merge3: HashIOParse + 'static {
+    /// Combines the changes from base to ours and from base to theirs.
+    fn merge3(ctx: &mut MergeContext, path: &str,
+              base: &Rc<Self>, ours: &Rc<Self>, theirs: &Rc<Self>) -> Rc<Self>;
+}
+
+/// Handles the cases where at most one side changed the value.
+pub fn merge_trivial<T>(base: &Rc<T>, ours: &Rc<T>, theirs: &Rc<T>) -> Option<Rc<T>>
+        where T: HashIOParse {
+    let base_hash = base.as_hash();
+    let ours_hash = ours.as_hash();
+    let theirs_hash = theirs.as_hash();
+    if ours_hash == theirs_hash || base_hash == theirs_hash {
+        Some(ours.clone())
+    } else if base_hash == ours_hash {
+        Some(theirs.clone())
+    } else {
+        None
+    }
+}
+
+/// Loads the three roots, merges them and stores the result.
+pub fn merge<T, H>(hash_io: &H, ctx: &mut MergeContext,
+                   base: &Hash, ours: &Hash, theirs: &Hash) -> Result<MergeResult>
+        where T: Merge3, H: HashIO {
+    let base_obj: Rc<T> = try!(hash_io.get(base));
+    let ours_obj: Rc<T> = try!(hash_io.get(ours));
+    let theirs_obj: Rc<T> = try!(hash_io.get(theirs));
+    let merged = T::merge3(ctx, "", &base_obj, &ours_obj, &theirs_obj);
+    let root = merged.as_hash();
+    try!(hash_io.put(merged));
+    Ok(MergeResult {
+        root: root,
+        conflicts: mem::replace(&mut ctx.conflicts, Vec::new())
+    })
+}
+
+
+impl Merge3 for String {
+    fn merge3(ctx: &mut MergeContext, path: &str,
+              base: &Rc<Self>, ours: &Rc<Self>, theirs: &Rc<Self>) -> Rc<Self> {
+        match merge_trivial(base, ours, theirs) {
+            Some(res) => res,
+            None => ctx.conflict(path, Some(base), ours, theirs)
+        }
+    }
+}
+
+/// Finds the longest common subsequence and maps each index of a to the
+/// matching index of b.
+///
+/// Uses the linear space variant of the Myers diff, so the cost depends on
+/// the number of differences instead of the product of the lengths.
+fn lcs_matches(a: &[Hash], b: &[Hash]) -> Vec<Option<usize>> {
+    let mut res = vec![None; a.len()];
+    diff_matches(a, b, 0, 0, &mut res);
+    res
+}
+
+/// Records the matches of a and b which start at the offsets.
+fn diff_matches(a: &[Hash], b: &[Hash], a_offset: usize, b_offset: usize,
+                res: &mut [Option<usize>]) {
+    let prefix = a.iter().zip(b.iter()).take_while(|&(x, y)| x == y).count();
+    let suffix = a[prefix..].iter().rev().zip(b[prefix..].iter().rev())
+        .take_while(|&(x, y)| x == y).count();
+    for i in 0..prefix {
+        res[a_offset + i] = Some(b_offset + i);
+    }
+    for i in 0..suffix {
+        res[a_offset + a.len() - 1 - i] = Some(b_offset + b.len() - 1 - i);
+    }
+    let a = &a[prefix..a.len() - suffix];
+    let b = &b[prefix..b.len() - suffix];
+    let (a_offset, b_offset) = (a_offset + prefix, b_offset + prefix);
+    if a.is_empty() || b.is_empty() {
+        return
+    }
+    // Both sides start and end with a difference, so there are at least two
+    // and both halves around the middle snake have fewer.
+    let (x, y, u, v) = middle_snake(a, b);
+    for i in 0..(u - x) {
+        res[a_offset + x + i] = Some(b_offset + y + i);
+    }
+    diff_matches(&a[..x], &b[..y], a_offset, b_offset, res);
+    diff_matches(&a[u..], &b[v..], a_offset + u, b_offset + v, res);
+}
+
+/// Finds the snake in the middle of a shortest edit script of a and b.
+///
+/// Returns its start in a and b and its end in a and b.  The forward and
+/// the reverse search run at the same time until their paths overlap.
+fn middle_snake(a: &[Hash], b: &[Hash]) -> (usize, usize, usize, usize) {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let delta = n - m;
+    let odd = delta % 2 != 0;
+    let max = (n + m + 1) / 2;
+    let offset = max + 1;
+    // Furthest x on the diagonal k = x - y, the reverse search counts from the end.
+    let mut forward = vec![0isize; 2 * offset as usize + 1];
+    let mut reverse = vec![0isize; 2 * offset as usize + 1];
+    for d in 0..(max + 1) {
+        let mut k = -d;
+        while k <= d {
+            let i = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && forward[i - 1] < forward[i + 1]) {
+                forward[i + 1]
+            } else {
+                forward[i - 1] + 1
+            };
+            let (start_x, start_y) = (x, x - k);
+            while x < n && x - k < m && a[x as usize] == b[(x - k) as usize] {
+                x += 1;
+            }
+            forward[i] = x;
+            let reverse_k = delta - k;
+            if odd && reverse_k > -d && reverse_k < d
+                    && x + reverse[(reverse_k + offset) as usize] >= n {
+                return (start_x as usize, start_y as usize, x as usize, (x - k) as usize)
+            }
+            k += 2;
+        }
+        let mut k = -d;
+        while k <= d {
+            let i = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && reverse[i - 1] < reverse[i + 1]) {
+                reverse[i + 1]
+            } else {
+                reverse[i - 1] + 1
+            };
+            let (end_x, end_y) = (n - x, m - (x - k));
+            while x < n && x - k < m
+                    && a[(n - 1 - x) as usize] == b[(m - 1 - (x - k)) as usize] {
+                x += 1;
+            }
+            reverse[i] = x;
+            let forward_k = delta - k;
+            if !odd && forward_k >= -d && forward_k <= d
+                    && x + forward[(forward_k + offset) as usize] >= n {
+                return ((n - x) as usize, (m - (x - k)) as usize, end_x as usize, end_y as usize)
+            }
+            k += 2;
+        }
+    }
+    unreachable!()
+}
+
+fn hashes<T>(items: &[Rc<T>]) -> Vec<Hash> where T: HashIOParse {
+    items.iter().map(|item| item.as_hash()).collect()
+}
+
+impl<T> Merge3 for Vec<Rc<T>> where T: Merge3 {
+    fn merge3(ctx: &mut MergeContext, path: &str,
+              base: &Rc<Self>, ours: &Rc<Self>, theirs: &Rc<Self>) -> Rc<Self> {
+        if let Some(res) = merge_trivial(base, ours, theirs) {
+            return res
+        }
+        let base_hashes = hashes(base);
+        let ours_hashes = hashes(ours);
+        let theirs_hashes = hashes(theirs);
+        let ours_matches = lcs_matches(&base_hashes, &ours_hashes);
+        let theirs_matches = lcs_matches(&base_hashes, &theirs_hashes);
+        let mut res: Vec<Rc<T>> = Vec::new();
+        let (mut b, mut o, mut t) = (0, 0, 0);
+        loop {
+            // The next item which is unchanged on both sides
+            let stable = (b..base.len()).filter_map(|k| match (ours_matches[k], theirs_matches[k]) {
+                (Some(x), Some(y)) if x >= o && y >= t => Some((k, x, y)),
+                _ => None
+            }).next();
+            let (next_b, next_o, next_t) = stable.unwrap_or((base.len(), ours.len(), theirs.len()));
+            if next_b == b && next_o == o && next_t == t {
+                if b == base.len() {
+                    break
+                }
+                res.push(ours[o].clone());
+                b += 1;
+                o += 1;
+                t += 1;
+                continue
+            }
+            merge_chunk(ctx, path, &base[b..next_b], &ours[o..next_o], &theirs[t..next_t],
+                        &mut res);
+            b = next_b;
+            o = next_o;
+            t = next_t;
+        }
+        Rc::new(res)
+    }
+}
+
+/// Merges a range of the vectors where at least one side has changes.
+fn merge_chunk<T>(ctx: &mut MergeContext, path: &str,
+                  base: &[Rc<T>], ours: &[Rc<T>], theirs: &[Rc<T>], res: &mut Vec<Rc<T>>)
+        where T: Merge3 {
+    let base_hashes = hashes(base);
+    let ours_hashes = hashes(ours);
+    let theirs_hashes = hashes(theirs);
+    if ours_hashes == base_hashes {
+        res.extend(theirs.iter().cloned());
+    } else if theirs_hashes == base_hashes || ours_hashes == theirs_hashes {
+        res.extend(ours.iter().cloned());
+    } else if base.len() == ours.len() && base.len() == theirs.len() {
+        for i in 0..base.len() {
+            let item_path = join_path(path, &format!("{}", res.len()));
+            res.push(T::merge3(ctx, &item_path, &base[i], &ours[i], &theirs[i]));
+        }
+    } else {
+        let chunk_path = join_path(path, &format!("{}", res.len()));
+        ctx.add_conflict::<Vec<Rc<T>>>(&chunk_path,
+                                       Some(Rc::new(base.to_vec()).as_hash()),
+                                       Some(Rc::new(ours.to_vec()).as_hash()),
+                                       Some(Rc::new(theirs.to_vec()).as_hash()));
+        res.extend(ours.iter().cloned());
+    }
+}
+
+impl<K, V> Merge3 for BTreeMap<Rc<K>, Rc<V>>
+        where K: HashIOParse + Ord + 'static, V: Merge3 {
+    fn merge3(ctx: &mut MergeContext, path: &str,
+              base: &Rc<Self>, ours: &Rc<Self>, theirs: &Rc<Self>) -> Rc<Self> {
+        if let Some(res) = merge_trivial(base, ours, theirs) {
+            return res
+        }
+        let keys: BTreeSet<&Rc<K>> = base.keys().chain(ours.keys()).chain(theirs.keys()).collect();
+        let mut res: BTreeMap<Rc<K>, Rc<V>> = BTreeMap::new();
+        for key in keys {
+            let key_path = join_path(path, &format!("{:?}", key));
+            let merged = match (base.get(key), ours.get(key), theirs.get(key)) {
+                (Some(b), Some(o), Some(t)) => Some(V::merge3(ctx, &key_path, b, o, t)),
+                (None, Some(o), Some(t)) => if o.as_hash() == t.as_hash() {
+                    Some(o.clone())
+                } else {
+                    Some(ctx.conflict(&key_path, None, o, t))
+                },
+                (None, Some(o), None) => Some(o.clone()),
+                (None, None, Some(t)) => Some(t.clone()),
+                (Some(b), Some(o), None) | (Some(b), None, Some(o)) => {
+                    if o.as_hash() == b.as_hash() {
+                        None
+                    } else {
+                        // Removed on one side and changed on the other
+                        let (ours_hash, theirs_hash) = if ours.contains_key(key) {
+                            (Some(o.as_hash()), None)
+                        } else {
+                            (None, Some(o.as_hash()))
+                        };
+                        ctx.add_conflict::<V>(&key_path, Some(b.as_hash()), ours_hash, theirs_hash);
+                        Some(o.clone())
+                    }
+                },
+                (_, None, None) => None
+            };
+            if let Some(value) = merged {
+                res.insert(key.clone(), value);
+            }
+        }
+        Rc::new(res)
+    }
+}
+
+
+/// Implements `Merge3` for a model which was created with `hashio_type!`.
+///
+/// It takes the names of the attributes and of the children.  All child
+/// types must implement `Merge3` as well.  If an attribute was changed
+/// differently on both sides, the whole object is treated as conflict.
+///
+/// ```ignore
+/// hashio_merge! {
+///     Task {
+///         factor
+///     } {
+///         title, category
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! hashio_merge {
+    ($model_name:ident {
+            $($attr_name:ident),*
+        } {
+            $($hash_name:ident),*
+        }
+    ) => {
+        impl $crate::merge::Merge3 for $model_name {
+            fn merge3(ctx: &mut $crate::merge::MergeContext, path: &str,
+                      base: &Rc<Self>, ours: &Rc<Self>, theirs: &Rc<Self>) -> Rc<Self> {
+                if let Some(res) = $crate::merge::merge_trivial(base, ours, theirs) {
+                    return res
+                }
+                $(
+                    let $attr_name = if ours.$attr_name == theirs.$attr_name
+                            || base.$attr_name == theirs.$attr_name {
+                        ours.$attr_name.clone()
+                    } else if base.$attr_name == ours.$attr_name {
+                        theirs.$attr_name.clone()
+                    } else {
+                        return ctx.conflict(path, Some(base), ours, theirs)
+                    };
+                )*
+                $(
+                    let $hash_name = $crate::merge::Merge3::merge3(ctx,
+                        &$crate::diff::join_path(path, stringify!($hash_name)),
+                        &base.$hash_name, &ours.$hash_name, &theirs.$hash_name);
+                )*
+                Rc::new($model_name {
+                    $($attr_name: $attr_name,)*
+                    $($hash_name: $hash_name),*
+                })
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use io::*;
+    use hashiofile::HashIOFile;
+    use std::io::{Read, Write};
+    use std::io;
+    use std::collections::BTreeMap;
+    use std::result;
+    use std::rc::Rc;
+    use std::fs::remove_dir_all;
+
+    hashio_type! {
+        MergeTask {
+            done: u8, read_u8, write_u8
+        } {
+            title: String
+        }
+    }
+    hashio_merge! {
+        MergeTask {
+            done
+        } {
+            title
+        }
+    }
+    hashio_type! {
+        MergeStorage {
+        } {
+            tasks: Vec<Rc<MergeTask>>,
+            tags: BTreeMap<Rc<String>, Rc<String>>
+        }
+    }
+    hashio_merge! {
+        MergeStorage {
+        } {
+            tasks, tags
+        }
+    }
+
+    fn task(title: &str, done: u8) -> Rc<MergeTask> {
+        Rc::new(MergeTask {
+            done: done,
+            title: Rc::new(title.to_string())
+        })
+    }
+
+    fn storage(tasks: Vec<Rc<MergeTask>>, tags: &[(&str, &str)]) -> Rc<MergeStorage> {
+        Rc::new(MergeStorage {
+            tasks: Rc::new(tasks),
+            tags: Rc::new(tags.iter()
+                          .map(|&(key, value)| (Rc::new(key.to_string()),
+                                                Rc::new(value.to_string())))
+                          .collect())
+        })
+    }
+
+    fn put(hash_io: &HashIOFile, item: Rc<MergeStorage>) -> Hash {
+        let hash = item.as_hash();
+        hash_io.put(item).unwrap();
+        hash
+    }
+
+    #[test]
+    fn test_merge() {
+        remove_dir_all("./unittest/mergetest/").ok();
+        let hash_io = HashIOFile::new("unittest/mergetest".to_string());
+        let base = put(&hash_io, storage(
+            vec![task("a", 0), task("b", 0), task("c", 0), task("d", 0), task("e", 0)],
+            &[("color", "red"), ("size", "big")]));
+        let ours = put(&hash_io, storage(
+            vec![task("a", 0), task("b2", 0), task("c-ours", 0), task("d", 0), task("e", 0)],
+            &[("color", "blue"), ("size", "big"), ("owner", "me")]));
+        let theirs = put(&hash_io, storage(
+            vec![task("a", 0), task("b", 0), task("c-theirs", 0), task("d", 1), task("e", 0),
+                 task("f", 0)],
+            &[("color", "red")]));
+
+        let mut ctx = MergeContext::new();
+        let result = merge::<MergeStorage, _>(&hash_io, &mut ctx, &base, &ours, &theirs).unwrap();
+        let merged: Rc<MergeStorage> = hash_io.get(&result.root).unwrap();
+        assert_eq!(storage(
+            vec![task("a", 0), task("b2", 0), task("c-ours", 0), task("d", 1), task("e", 0),
+                 task("f", 0)],
+            &[("color", "blue"), ("owner", "me")]), merged);
+        assert_eq!(vec![Conflict {
+            path: "tasks/2/title".to_string(),
+            type_name: "String".to_string(),
+            base: Some(Rc::new("c".to_string()).as_hash()),
+            ours: Some(Rc::new("c-ours".to_string()).as_hash()),
+            theirs: Some(Rc::new("c-theirs".to_string()).as_hash())
+        }], result.conflicts);
+
+        // A resolver decides the conflict
+        let mut ctx = MergeContext::new().with_resolver::<String, _>(|_, ours, theirs| {
+            Some(Rc::new(format!("{}/{}", ours, theirs)))
+        });
+        let result = merge::<MergeStorage, _>(&hash_io, &mut ctx, &base, &ours, &theirs).unwrap();
+        assert!(result.conflicts.is_empty());
+        let merged: Rc<MergeStorage> = hash_io.get(&result.root).unwrap();
+        assert_eq!(Rc::new("c-ours/c-theirs".to_string()), merged.tasks[2].title);
+    }
+
+    /// Length of the longest common subsequence with the quadratic table.
+    fn lcs_len(a: &[Hash], b: &[Hash]) -> usize {
+        let mut lengths = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lengths[i][j] = if a[i] == b[j] {
+                    lengths[i + 1][j + 1] + 1
+                } else {
+                    ::std::cmp::max(lengths[i + 1][j], lengths[i][j + 1])
+                };
+            }
+        }
+        lengths[0][0]
+    }
+
+    fn assert_lcs(a: &[Hash], b: &[Hash], expected_len: usize) {
+        let matches = lcs_matches(a, b);
+        let pairs: Vec<(usize, usize)> = matches.iter().enumerate()
+            .filter_map(|(i, j)| j.map(|j| (i, j)))
+            .collect();
+        assert_eq!(expected_len, pairs.len());
+        for &(i, j) in pairs.iter() {
+            assert_eq!(a[i], b[j]);
+        }
+        for window in pairs.windows(2) {
+            assert!(window[0].1 < window[1].1);
+        }
+    }
+
+    #[test]
+    fn test_lcs_matches() {
+        let symbols: Vec<Hash> = (0..4).map(|i| Hash::hash_string(format!("{}", i))).collect();
+        let mut seed = 17u32;
+        let mut next = || {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as usize
+        };
+        for _ in 0..300 {
+            let a: Vec<Hash> = (0..next() % 20).map(|_| symbols[next() % 4]).collect();
+            let b: Vec<Hash> = (0..next() % 20).map(|_| symbols[next() % 4]).collect();
+            assert_lcs(&a, &b, lcs_len(&a, &b));
+        }
+
+        // Long vectors with few changes don't need a table of all pairs.
+        let a: Vec<Hash> = (0..10000).map(|i| Hash::hash_string(format!("{}", i))).collect();
+        let mut b = a.clone();
+        b.remove(100);
+        b.insert(6000, symbols[0]);
+        b[9000] = symbols[1];
+        assert_lcs(&a, &b, 9998);
+    }
+}
@@ -0,0 +1,168 @@
+//! Keep track of stored objects which use older versions of a type.
+//!
+//! # Usage
+//! Types generated by `hashio_type!` can declare older types with
+//! `fallback => OldType`.  If the old type has fallbacks as well, a chain
+//! like `Task1 -> Task -> Task3` is created and data of any ancestor is
+//! migrated step by step using the `From` implementations when it is loaded.
+//!
+//! The migration report scans a store and lists which objects still use one
+//! of the older types.  To avoid the conversion cost on every load, `migrate`
+//! rewrites everything reachable from some roots to the current types.
+//! Both need a `HashIOFile`, since `migrate` rewrites the headers of stored
+//! objects in place.
+
+use hash::*;
+use hashio::*;
+use hashiofile::HashIOFile;
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
+
+/// Stored object which uses an older version of a type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutdatedObject {
+    pub hash: Hash,
+    pub type_name: String,
+    pub type_hash: Hash,
+}
+
+/// Overview which objects of a type are up to date and which are not.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationReport {
+    pub type_name: String,
+    pub current: Vec<Hash>,
+    pub outdated: Vec<OutdatedObject>,
+    /// Objects whose header couldn't be read.
+    pub unreadable: Vec<Hash>,
+}
+
+impl MigrationReport {
+    /// Returns true if no object uses an older version.
+    pub fn is_up_to_date(&self) -> bool {
+        self.outdated.is_empty()
+    }
+}
+
+/// Scans the store for objects which are stored as T or as one of its ancestors.
+///
+/// Only types which store a type hash can be detected, so this doesn't work
+/// for types with an unsafe loader.
+pub fn migration_report<T>(hash_io: &HashIOFile) -> Result<MigrationReport>
+        where T: HashIOParse {
+    let type_hash = T::type_hash();
+    let fallbacks = T::fallback_types();
+    let mut report = MigrationReport {
+        type_name: T::type_name(),
+        current: Vec::new(),
+        outdated: Vec::new(),
+        unreadable: Vec::new(),
+    };
+    for hash in try!(hash_io.hashes()) {
+        let (version, object_type_hash) = match hash_io.read_header(&hash) {
+            Ok(header) => header,
+            Err(_) => {
+                report.unreadable.push(hash);
+                continue
+            }
+        };
+        if version != 1 {
+            continue
+        }
+        if object_type_hash == type_hash {
+            report.current.push(hash);
+        } else if let Some(&(ref name, _)) = fallbacks.iter()
+                .find(|&&(_, ref fallback_hash)| *fallback_hash == object_type_hash) {
+            report.outdated.push(OutdatedObject {
+                hash: hash,
+                type_name: name.clone(),
+                type_hash: object_type_hash,
+            });
+        }
+    }
+    Ok(report)
+}
+
+
+/// Rewrites the objects reachable from the roots to the current version of T.
+///
+/// Each root is loaded as T, so all outdated objects are converted through the
+/// fallback path, and stored again.  The result maps the old root hashes to
+/// the new ones.  Objects whose content doesn't change by the migration keep
+/// their hash, their header is replaced with the current type.
+///
+/// The stored graph is walked with the decoder of the store, so objects
+/// which are not loaded with their parents are visited as well.  Their
+/// header is only replaced if it uses a legacy type hash scheme of a type
+/// which is registered with the decoder.
+pub fn migrate<T>(hash_io: &HashIOFile, roots: &[Hash]) -> Result<BTreeMap<Hash, Hash>>
+        where T: HashIOParse {
+    let mut res: BTreeMap<Hash, Hash> = BTreeMap::new();
+    for root in roots {
+        let item: Rc<T> = try!(hash_io.get(root));
+        let new_root = item.as_hash();
+        try!(hash_io.put(item.clone()));
+        let types = loaded_types(&*item);
+        let mut visited: BTreeSet<Hash> = BTreeSet::new();
+        let mut pending: Vec<Hash> = vec![new_root];
+        while let Some(hash) = pending.pop() {
+            if !visited.insert(hash) {
+                continue
+            }
+            let object = try!(hash_io.get_raw(&hash));
+            pending.extend(try!(hash_io.decoder.stored_references(hash_io, &object)));
+            let type_hash = match object.header {
+                Some((_, old_type_hash)) => types.get(&hash).cloned()
+                    .or_else(|| hash_io.decoder.current_type(&old_type_hash)),
+                None => None
+            };
+            if let Some(type_hash) = type_hash {
+                try!(update_header(hash_io, object, type_hash));
+            }
+        }
+        trace!("migrate<{}>: {} => {}", T::type_name(), root.as_string(), new_root.as_string());
+        res.insert(*root, new_root);
+    }
+    Ok(res)
+}
+
+/// Returns the current type hashes of the object and the loaded objects
+/// below it by their hashes.
+fn loaded_types(item: &HashIOType) -> BTreeMap<Hash, Hash> {
+    let mut res: BTreeMap<Hash, Hash> = BTreeMap::new();
+    res.insert(item.as_hash(), item.type_hash_obj());
+    let mut pending: Vec<Rc<HashIOType>> = item.childs().values().cloned().collect();
+    while let Some(child) = pending.pop() {
+        if res.insert(child.as_hash(), child.type_hash_obj()).is_none() {
+            pending.extend(child.childs().values().cloned());
+        }
+    }
+    res
+}
+
+/// Replaces an outdated header of a stored object.
+///
+/// `put` skips objects which already exist, so an object whose payload
+/// didn't change by the migration still has the header of the old type.
+fn update_header(hash_io: &HashIOFile, object: RawObject, type_hash: Hash) -> Result<()> {
+    if object.header == Some((1, type_hash)) {
+        return Ok(())
+    }
+    trace!("migrate: header of {}", object.hash.as_string());
+    hash_io.replace_raw(&RawObject { header: Some((1, type_hash)), ..object })
+}
+
+/// Points named references to the migrated roots.
+///
+/// Returns the number of updated references.
+pub fn update_refs(refs: &mut BTreeMap<String, Hash>, mapping: &BTreeMap<Hash, Hash>) -> usize {
+    let mut updated = 0;
+    for (_, hash) in refs.iter_mut() {
+        if let Some(new_hash) = mapping.get(hash) {
+            if *new_hash != *hash {
+                *hash = *new_hash;
+                updated += 1;
+            }
+        }
+    }
+    updated
+}
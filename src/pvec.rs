@@ -0,0 +1,952 @@
+//! Persistent vector which is split into multiple nodes.
+//!
+//! # Usage
+//! `Vec<Rc<T>>` is stored as one object listing all item hashes, so appending
+//! an item rewrites the whole list.  `PVec` stores the items in a tree
+//! of nodes with up to 32 entries.  Leaves hold the item hashes and branches
+//! hold the hashes and lengths of their children.  All leaves have the same
+//! depth.
+//!
+//! `push`, `slice` and `concat` return new vectors which share all
+//! unaffected nodes with the original ones, so storing the result only writes
+//! the changed nodes.  `PVec::lookup`, `PVec::push_onto`, `PVec::slice_stored`
+//! and `PVec::concat_stored` work on stored vectors and only load the nodes
+//! they need.
+//!
+//! Unlike `Hamt`, the node layout depends on how the vector was built, so
+//! vectors with the same items may have different hashes.
+
+use hash::*;
+use io::*;
+use hashio::*;
+use std::io::{Read, Write};
+use std::result;
+use std::io;
+use std::collections::BTreeMap;
+use std::cmp;
+use std::fmt;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+const FANOUT: usize = 32;
+const NODE_LEAF: u8 = 0;
+const NODE_BRANCH: u8 = 1;
+
+
+struct PVecChild<T> {
+    len: usize,
+    hash: Hash,
+    node: Rc<PVec<T>>
+}
+
+impl<T> Clone for PVecChild<T> {
+    fn clone(&self) -> PVecChild<T> {
+        PVecChild {
+            len: self.len,
+            hash: self.hash,
+            node: self.node.clone()
+        }
+    }
+}
+
+enum PVecNode<T> {
+    Leaf(Vec<Rc<T>>),
+    Branch(Vec<PVecChild<T>>)
+}
+
+/// Node of a persistent vector and the vector itself.
+pub struct PVec<T> {
+    node: PVecNode<T>
+}
+
+impl<T> Clone for PVec<T> {
+    fn clone(&self) -> PVec<T> {
+        PVec {
+            node: match self.node {
+                PVecNode::Leaf(ref items) => PVecNode::Leaf(items.clone()),
+                PVecNode::Branch(ref children) => PVecNode::Branch(children.clone())
+            }
+        }
+    }
+}
+
+impl<T> fmt::Debug for PVec<T> where T: HashIOParse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.to_vec().iter()).finish()
+    }
+}
+
+impl<T> PVec<T> where T: HashIOParse {
+    /// Creates an empty vector.
+    pub fn new() -> PVec<T> {
+        PVec {
+            node: PVecNode::Leaf(Vec::new())
+        }
+    }
+
+    /// Creates a vector which contains the items.
+    pub fn from_items(items: &[Rc<T>]) -> PVec<T> {
+        let leaves = leaves(items.to_vec());
+        let mut nodes: Vec<PVec<T>> = leaves;
+        while nodes.len() > 1 {
+            nodes = branches(nodes.into_iter().map(child).collect());
+        }
+        nodes.pop().unwrap_or_else(PVec::new)
+    }
+
+    /// Number of items in the vector.
+    pub fn len(&self) -> usize {
+        match self.node {
+            PVecNode::Leaf(ref items) => items.len(),
+            PVecNode::Branch(ref children) => children.iter().map(|child| child.len).sum()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn height(&self) -> usize {
+        match self.node {
+            PVecNode::Leaf(_) => 0,
+            PVecNode::Branch(ref children) => children[0].node.height() + 1
+        }
+    }
+
+    /// Returns all items in order.
+    pub fn to_vec(&self) -> Vec<Rc<T>> {
+        let mut res: Vec<Rc<T>> = Vec::new();
+        self.collect_items(&mut res);
+        res
+    }
+
+    fn collect_items(&self, res: &mut Vec<Rc<T>>) {
+        match self.node {
+            PVecNode::Leaf(ref items) => res.extend(items.iter().cloned()),
+            PVecNode::Branch(ref children) => for child in children.iter() {
+                child.node.collect_items(res);
+            }
+        }
+    }
+
+    /// Returns the item at the index.
+    pub fn get(&self, index: usize) -> Option<Rc<T>> {
+        let mut node = self;
+        let mut index = index;
+        loop {
+            match node.node {
+                PVecNode::Leaf(ref items) => return items.get(index).cloned(),
+                PVecNode::Branch(ref children) => {
+                    match children.iter().position(|child| {
+                        if index < child.len {
+                            true
+                        } else {
+                            index -= child.len;
+                            false
+                        }
+                    }) {
+                        Some(pos) => node = &children[pos].node,
+                        None => return None
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns a new vector with the item appended.
+    ///
+    /// Only the nodes on the rightmost path are copied.
+    pub fn push(&self, item: Rc<T>) -> PVec<T> {
+        wrap(self.push_node(item))
+    }
+
+    fn push_node(&self, item: Rc<T>) -> Vec<PVec<T>> {
+        match self.node {
+            PVecNode::Leaf(ref items) => {
+                let mut items = items.clone();
+                items.push(item);
+                leaves(items)
+            },
+            PVecNode::Branch(ref children) => {
+                let mut children = children.clone();
+                let last = children.pop().expect("Branches are never empty");
+                children.extend(last.node.push_node(item).into_iter().map(child));
+                branches(children)
+            }
+        }
+    }
+
+    /// Returns a new vector with the items from start to end (exclusive).
+    ///
+    /// Nodes which are completely inside the range are shared.
+    pub fn slice(&self, start: usize, end: usize) -> PVec<T> {
+        let end = cmp::min(end, self.len());
+        if start >= end {
+            return PVec::new()
+        }
+        let mut res = self.slice_node(start, end);
+        loop {
+            let single = match res.node {
+                PVecNode::Branch(ref children) if children.len() == 1 =>
+                    (*children[0].node).clone(),
+                _ => return res
+            };
+            res = single;
+        }
+    }
+
+    fn slice_node(&self, start: usize, end: usize) -> PVec<T> {
+        match self.node {
+            PVecNode::Leaf(ref items) => PVec {
+                node: PVecNode::Leaf(items[start..end].to_vec())
+            },
+            PVecNode::Branch(ref children) => {
+                let mut res: Vec<PVecChild<T>> = Vec::new();
+                let mut offset = 0;
+                for item in children.iter() {
+                    let child_start = cmp::max(start, offset);
+                    let child_end = cmp::min(end, offset + item.len);
+                    if child_start < child_end {
+                        if child_start == offset && child_end == offset + item.len {
+                            res.push(item.clone());
+                        } else {
+                            res.push(child(item.node.slice_node(child_start - offset,
+                                                                child_end - offset)));
+                        }
+                    }
+                    offset += item.len;
+                }
+                PVec {
+                    node: PVecNode::Branch(res)
+                }
+            }
+        }
+    }
+
+    /// Returns a new vector with the items of both vectors.
+    ///
+    /// Only the nodes along the border of both vectors are copied.
+    pub fn concat(&self, other: &PVec<T>) -> PVec<T> {
+        if self.is_empty() {
+            return other.clone()
+        }
+        if other.is_empty() {
+            return self.clone()
+        }
+        wrap(concat_nodes(self, other))
+    }
+}
+
+impl<T> Default for PVec<T> where T: HashIOParse {
+    fn default() -> PVec<T> {
+        PVec::new()
+    }
+}
+
+fn child<T>(node: PVec<T>) -> PVecChild<T> where T: HashIOParse {
+    PVecChild {
+        len: node.len(),
+        hash: node.as_hash(),
+        node: Rc::new(node)
+    }
+}
+
+/// Splits the items into leaves.
+fn leaves<T>(items: Vec<Rc<T>>) -> Vec<PVec<T>> {
+    if items.len() <= FANOUT {
+        return vec![PVec { node: PVecNode::Leaf(items) }]
+    }
+    items.chunks(FANOUT).map(|chunk| PVec { node: PVecNode::Leaf(chunk.to_vec()) }).collect()
+}
+
+/// Splits the children into branches.
+fn branches<T>(children: Vec<PVecChild<T>>) -> Vec<PVec<T>> {
+    if children.len() <= FANOUT {
+        return vec![PVec { node: PVecNode::Branch(children) }]
+    }
+    children.chunks(FANOUT).map(|chunk| PVec { node: PVecNode::Branch(chunk.to_vec()) }).collect()
+}
+
+/// Turns the nodes of the same height into one root node.
+fn wrap<T>(mut nodes: Vec<PVec<T>>) -> PVec<T> where T: HashIOParse {
+    if nodes.len() == 1 {
+        nodes.pop().unwrap()
+    } else {
+        PVec {
+            node: PVecNode::Branch(nodes.into_iter().map(child).collect())
+        }
+    }
+}
+
+/// Concatenates two non empty nodes.
+///
+/// The resulting nodes have the height of the higher node.
+fn concat_nodes<T>(left: &PVec<T>, right: &PVec<T>) -> Vec<PVec<T>> where T: HashIOParse {
+    let left_height = left.height();
+    let right_height = right.height();
+    match (&left.node, &right.node) {
+        (&PVecNode::Branch(ref children), _) if left_height > right_height => {
+            let mut children = children.clone();
+            let last = children.pop().expect("Branches are never empty");
+            children.extend(concat_nodes(&last.node, right).into_iter().map(child));
+            branches(children)
+        },
+        (_, &PVecNode::Branch(ref children)) if left_height < right_height => {
+            let mut res: Vec<PVecChild<T>> = concat_nodes(left, &children[0].node)
+                .into_iter().map(child).collect();
+            res.extend(children[1..].iter().cloned());
+            branches(res)
+        },
+        (&PVecNode::Leaf(ref left_items), &PVecNode::Leaf(ref right_items)) => {
+            let mut items = left_items.clone();
+            items.extend(right_items.iter().cloned());
+            leaves(items)
+        },
+        (&PVecNode::Branch(ref left_children), &PVecNode::Branch(ref right_children)) => {
+            let mut children = left_children.clone();
+            children.extend(right_children.iter().cloned());
+            branches(children)
+        },
+        _ => unreachable!()
+    }
+}
+
+impl<T> Writable for PVec<T> where T: HashIOParse {
+    fn write_to<W: Write>(&self, write: &mut W) -> result::Result<usize, io::Error> {
+        let mut size = 0;
+        match self.node {
+            PVecNode::Leaf(ref items) => {
+                size += try!(write_u8(NODE_LEAF, write));
+                size += try!(write_u32(items.len() as u32, write));
+                for item in items.iter() {
+                    size += try!(write_hash(&item.as_hash(), write));
+                }
+            },
+            PVecNode::Branch(ref children) => {
+                size += try!(write_u8(NODE_BRANCH, write));
+                size += try!(write_u32(children.len() as u32, write));
+                for child in children.iter() {
+                    size += try!(write_u32(child.len as u32, write));
+                    size += try!(write_hash(&child.hash, write));
+                }
+            }
+        }
+        Ok(size)
+    }
+}
+
+impl<T> Hashable for PVec<T> where T: HashIOParse {
+    fn as_hash(&self) -> Hash {
+        self.writable_to_hash()
+    }
+}
+
+impl<T> Typeable for PVec<T> where T: HashIOParse {
+    fn type_hash() -> Hash {
+        Hash::hash_string(PVec::<T>::type_name())
+    }
+
+    fn type_name() -> String {
+        "PVec<".to_string() + &T::type_name() + ">"
+    }
+}
+
+impl<T> HashIOType for PVec<T> where T: HashIOParse + 'static {
+    fn childs(&self) -> BTreeMap<String, Rc<HashIOType>> {
+        let mut res: BTreeMap<String, Rc<HashIOType>> = BTreeMap::new();
+        match self.node {
+            PVecNode::Leaf(ref items) => for (i, item) in items.iter().enumerate() {
+                res.insert(format!("{}", i), item.clone() as Rc<HashIOType>);
+            },
+            PVecNode::Branch(ref children) => for (i, child) in children.iter().enumerate() {
+                res.insert(format!("#{}", i), child.node.clone() as Rc<HashIOType>);
+            }
+        }
+        res
+    }
+
+    fn type_hash_obj(&self) -> Hash {
+        PVec::<T>::type_hash()
+    }
+
+    fn type_name_obj(&self) -> String {
+        PVec::<T>::type_name()
+    }
+}
+
+impl<T> HashIOParse for PVec<T> where T: HashIOParse + 'static {
+    fn parse<H, R>(hash_io: &H, read: &mut R, _: &Option<Hash>)
+                -> Result<Rc<Self>> where H: HashIO, R: Read {
+        let node = match try!(ShallowPVec::<T>::read(read)).node {
+            ShallowNode::Leaf(hashes) => {
+                let mut items: Vec<Rc<T>> = Vec::new();
+                for hash in hashes {
+                    items.push(try!(hash_io.get(&hash)));
+                }
+                PVecNode::Leaf(items)
+            },
+            ShallowNode::Branch(entries) => {
+                let mut children: Vec<PVecChild<T>> = Vec::new();
+                for (len, hash) in entries {
+                    let node: Rc<PVec<T>> = try!(hash_io.get(&hash));
+                    try!(check_len(&hash, len, node.len()));
+                    if !children.is_empty() && children[0].node.height() != node.height() {
+                        return Err(HashIOError::Undefined(
+                            format!("PVec node {} has a different height", hash.as_string())))
+                    }
+                    children.push(PVecChild {
+                        len: len,
+                        hash: hash,
+                        node: node
+                    });
+                }
+                PVecNode::Branch(children)
+            }
+        };
+        Ok(Rc::new(PVec { node: node }))
+    }
+
+    fn store<H, W>(&self, _: &H, write: &mut W) -> Result<()> where H: HashIO, W: Write {
+        try!(self.write_to(write));
+        Ok(())
+    }
+
+    fn store_childs<H>(&self, hash_io: &H) -> Result<()>
+            where H: HashIO {
+        match self.node {
+            PVecNode::Leaf(ref items) => for item in items.iter() {
+                try!(hash_io.put(item.clone()));
+            },
+            PVecNode::Branch(ref children) => for child in children.iter() {
+                try!(hash_io.put(child.node.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn type_hash_valid(hash: &Hash) -> bool {
+        *hash == PVec::<T>::type_hash()
+    }
+
+    fn payload_references(payload: &[u8], _: &Option<Hash>) -> Result<Option<Vec<Hash>>> {
+        let mut read: &[u8] = payload;
+        Ok(Some(match try!(ShallowPVec::<T>::read(&mut read)).node {
+            ShallowNode::Leaf(hashes) => hashes,
+            ShallowNode::Branch(entries) => entries.into_iter().map(|(_, hash)| hash).collect()
+        }))
+    }
+}
+
+
+impl<T> PVec<T> where T: HashIOParse + 'static {
+    /// Returns the item at the index of a stored vector and only loads the
+    /// nodes on the path.
+    pub fn lookup<H>(hash_io: &H, root: &Hash, index: usize) -> Result<Option<Rc<T>>>
+            where H: HashIO {
+        let mut node_hash = *root;
+        let mut node_len: Option<usize> = None;
+        let mut index = index;
+        loop {
+            let node: Rc<ShallowPVec<T>> = try!(hash_io.get(&node_hash));
+            if let Some(len) = node_len {
+                try!(check_len(&node_hash, len, node.len()));
+            }
+            match node.node {
+                ShallowNode::Leaf(ref hashes) => return match hashes.get(index) {
+                    Some(hash) => Ok(Some(try!(hash_io.get(hash)))),
+                    None => Ok(None)
+                },
+                ShallowNode::Branch(ref entries) => {
+                    match entries.iter().find(|&&(len, _)| {
+                        if index < len {
+                            true
+                        } else {
+                            index -= len;
+                            false
+                        }
+                    }) {
+                        Some(&(len, hash)) => {
+                            node_hash = hash;
+                            node_len = Some(len);
+                        },
+                        None => return Ok(None)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Appends an item to a stored vector and returns the hash of the new root.
+    ///
+    /// Only the nodes on the rightmost path are loaded and written.
+    pub fn push_onto<H>(hash_io: &H, root: &Hash, item: Rc<T>) -> Result<Hash>
+            where H: HashIO {
+        let item_hash = item.as_hash();
+        try!(hash_io.put(item));
+        let root_node: Rc<ShallowPVec<T>> = try!(hash_io.get(root));
+        let nodes = try!(root_node.push_node(hash_io, item_hash));
+        store_root(hash_io, nodes)
+    }
+
+    /// Stores the items from start to end (exclusive) of a stored vector and
+    /// returns the hash of the new root.
+    ///
+    /// Only the nodes along the borders of the range are loaded and written.
+    pub fn slice_stored<H>(hash_io: &H, root: &Hash, start: usize, end: usize) -> Result<Hash>
+            where H: HashIO {
+        let root_node: Rc<ShallowPVec<T>> = try!(hash_io.get(root));
+        let end = cmp::min(end, root_node.len());
+        if start >= end {
+            return store_root(hash_io, vec![ShallowPVec::<T>::new(ShallowNode::Leaf(Vec::new()))])
+        }
+        let mut res = try!(root_node.slice_node(hash_io, start, end));
+        loop {
+            let single = match res.node {
+                ShallowNode::Branch(ref entries) if entries.len() == 1 =>
+                    try!(load_node::<T, H>(hash_io, &entries[0])),
+                _ => return store_root(hash_io, vec![res])
+            };
+            res = single;
+        }
+    }
+
+    /// Stores the items of both stored vectors and returns the hash of the
+    /// new root.
+    ///
+    /// Only the nodes along the border of both vectors are loaded and written.
+    pub fn concat_stored<H>(hash_io: &H, left: &Hash, right: &Hash) -> Result<Hash>
+            where H: HashIO {
+        let left_node: Rc<ShallowPVec<T>> = try!(hash_io.get(left));
+        let right_node: Rc<ShallowPVec<T>> = try!(hash_io.get(right));
+        if left_node.len() == 0 {
+            return Ok(*right)
+        }
+        if right_node.len() == 0 {
+            return Ok(*left)
+        }
+        let left_height = try!(left_node.height(hash_io));
+        let right_height = try!(right_node.height(hash_io));
+        let nodes = try!(concat_stored_nodes(hash_io, &left_node, left_height,
+                                             &right_node, right_height));
+        store_root(hash_io, nodes)
+    }
+}
+
+/// Stores the nodes of the same height as one root node and returns its hash.
+fn store_root<T, H>(hash_io: &H, mut nodes: Vec<ShallowPVec<T>>) -> Result<Hash>
+        where T: HashIOParse + 'static, H: HashIO {
+    let root = if nodes.len() == 1 {
+        nodes.pop().unwrap()
+    } else {
+        let mut entries: Vec<(usize, Hash)> = Vec::new();
+        for node in nodes {
+            entries.push(try!(store_node(hash_io, node)));
+        }
+        ShallowPVec::new(ShallowNode::Branch(entries))
+    };
+    let root_hash = root.as_hash();
+    try!(hash_io.put(Rc::new(root)));
+    Ok(root_hash)
+}
+
+/// Stores the node and returns its entry for the parent.
+fn store_node<T, H>(hash_io: &H, node: ShallowPVec<T>) -> Result<(usize, Hash)>
+        where T: HashIOParse + 'static, H: HashIO {
+    let entry = (node.len(), node.as_hash());
+    try!(hash_io.put(Rc::new(node)));
+    Ok(entry)
+}
+
+/// Loads the child of a branch entry and checks its length.
+fn load_node<T, H>(hash_io: &H, entry: &(usize, Hash)) -> Result<ShallowPVec<T>>
+        where T: HashIOParse + 'static, H: HashIO {
+    let (len, hash) = *entry;
+    let node: Rc<ShallowPVec<T>> = try!(hash_io.get(&hash));
+    try!(check_len(&hash, len, node.len()));
+    Ok(ShallowPVec::new(node.node.clone()))
+}
+
+/// Concatenates two non empty stored nodes like `concat_nodes`.
+fn concat_stored_nodes<T, H>(hash_io: &H, left: &ShallowPVec<T>, left_height: usize,
+                             right: &ShallowPVec<T>, right_height: usize)
+                             -> Result<Vec<ShallowPVec<T>>>
+        where T: HashIOParse + 'static, H: HashIO {
+    match (left.node.clone(), right.node.clone()) {
+        (ShallowNode::Branch(mut entries), _) if left_height > right_height => {
+            let last = try!(load_node(hash_io, &entries.pop().expect("Branches are never empty")));
+            for node in try!(concat_stored_nodes(hash_io, &last, left_height - 1,
+                                                 right, right_height)) {
+                entries.push(try!(store_node(hash_io, node)));
+            }
+            Ok(ShallowPVec::branches(&entries))
+        },
+        (_, ShallowNode::Branch(entries)) if left_height < right_height => {
+            let first = try!(load_node(hash_io, &entries[0]));
+            let mut res: Vec<(usize, Hash)> = Vec::new();
+            for node in try!(concat_stored_nodes(hash_io, left, left_height,
+                                                 &first, right_height - 1)) {
+                res.push(try!(store_node(hash_io, node)));
+            }
+            res.extend(entries[1..].iter().cloned());
+            Ok(ShallowPVec::branches(&res))
+        },
+        (ShallowNode::Leaf(mut hashes), ShallowNode::Leaf(right_hashes)) => {
+            hashes.extend(right_hashes);
+            Ok(ShallowPVec::leaves(&hashes))
+        },
+        (ShallowNode::Branch(mut entries), ShallowNode::Branch(right_entries)) => {
+            entries.extend(right_entries);
+            Ok(ShallowPVec::branches(&entries))
+        },
+        _ => Err(HashIOError::Undefined("PVec nodes with different heights".to_string()))
+    }
+}
+
+
+/// Returns an error if a stored child doesn't have the length its parent
+/// recorded, so the lengths of the children sum up to the parent length.
+fn check_len(hash: &Hash, expected: usize, len: usize) -> Result<()> {
+    if len != expected {
+        return Err(HashIOError::Undefined(format!("PVec node {} has {} items instead of {}",
+                                                  hash.as_string(), len, expected)))
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+enum ShallowNode {
+    Leaf(Vec<Hash>),
+    Branch(Vec<(usize, Hash)>)
+}
+
+/// View of a stored `PVec` node which doesn't load its children.
+///
+/// It uses the same type hash and binary representation as `PVec`.
+#[derive(Debug)]
+struct ShallowPVec<T> {
+    node: ShallowNode,
+    phantom: PhantomData<T>
+}
+
+impl<T> ShallowPVec<T> where T: HashIOParse + 'static {
+    fn new(node: ShallowNode) -> ShallowPVec<T> {
+        ShallowPVec {
+            node: node,
+            phantom: PhantomData
+        }
+    }
+
+    fn read<R>(read: &mut R) -> Result<ShallowPVec<T>> where R: Read {
+        let kind = try!(read_u8(read));
+        let len = try!(read_u32(read));
+        if len as usize > FANOUT {
+            return Err(HashIOError::Undefined(format!("PVec node with {} entries", len)))
+        }
+        let node = match kind {
+            NODE_LEAF => {
+                let mut hashes: Vec<Hash> = Vec::new();
+                for _ in 0..len {
+                    hashes.push(try!(read_hash(read)));
+                }
+                ShallowNode::Leaf(hashes)
+            },
+            NODE_BRANCH => {
+                if len == 0 {
+                    return Err(HashIOError::Undefined("PVec branch without children".to_string()))
+                }
+                let mut entries: Vec<(usize, Hash)> = Vec::new();
+                for _ in 0..len {
+                    let child_len = try!(read_u32(read)) as usize;
+                    entries.push((child_len, try!(read_hash(read))));
+                }
+                ShallowNode::Branch(entries)
+            },
+            kind => return Err(HashIOError::Undefined(format!("Invalid PVec node {}", kind)))
+        };
+        Ok(ShallowPVec::new(node))
+    }
+
+    fn len(&self) -> usize {
+        match self.node {
+            ShallowNode::Leaf(ref hashes) => hashes.len(),
+            ShallowNode::Branch(ref entries) => entries.iter().map(|&(len, _)| len).sum()
+        }
+    }
+
+    /// Splits the item hashes into leaves.
+    fn leaves(hashes: &[Hash]) -> Vec<ShallowPVec<T>> {
+        hashes.chunks(FANOUT)
+            .map(|chunk| ShallowPVec::new(ShallowNode::Leaf(chunk.to_vec())))
+            .collect()
+    }
+
+    /// Splits the entries into branches.
+    fn branches(entries: &[(usize, Hash)]) -> Vec<ShallowPVec<T>> {
+        entries.chunks(FANOUT)
+            .map(|chunk| ShallowPVec::new(ShallowNode::Branch(chunk.to_vec())))
+            .collect()
+    }
+
+    /// Loads the leftmost path to find the height of the node.
+    fn height<H>(&self, hash_io: &H) -> Result<usize> where H: HashIO {
+        let mut res = 0;
+        let mut node = ShallowPVec::<T>::new(self.node.clone());
+        while let ShallowNode::Branch(ref entries) = node.node.clone() {
+            node = try!(load_node(hash_io, &entries[0]));
+            res += 1;
+        }
+        Ok(res)
+    }
+
+    fn push_node<H>(&self, hash_io: &H, item_hash: Hash) -> Result<Vec<ShallowPVec<T>>>
+            where H: HashIO {
+        match self.node {
+            ShallowNode::Leaf(ref hashes) => {
+                let mut hashes = hashes.clone();
+                hashes.push(item_hash);
+                Ok(ShallowPVec::leaves(&hashes))
+            },
+            ShallowNode::Branch(ref entries) => {
+                let mut entries = entries.clone();
+                let last_entry = entries.pop().expect("Branches are never empty");
+                let last: ShallowPVec<T> = try!(load_node(hash_io, &last_entry));
+                for node in try!(last.push_node(hash_io, item_hash)) {
+                    entries.push(try!(store_node(hash_io, node)));
+                }
+                Ok(ShallowPVec::branches(&entries))
+            }
+        }
+    }
+
+    fn slice_node<H>(&self, hash_io: &H, start: usize, end: usize) -> Result<ShallowPVec<T>>
+            where H: HashIO {
+        match self.node {
+            ShallowNode::Leaf(ref hashes) =>
+                Ok(ShallowPVec::new(ShallowNode::Leaf(hashes[start..end].to_vec()))),
+            ShallowNode::Branch(ref entries) => {
+                let mut res: Vec<(usize, Hash)> = Vec::new();
+                let mut offset = 0;
+                for entry in entries.iter() {
+                    let len = entry.0;
+                    let child_start = cmp::max(start, offset);
+                    let child_end = cmp::min(end, offset + len);
+                    if child_start < child_end {
+                        if child_start == offset && child_end == offset + len {
+                            res.push(*entry);
+                        } else {
+                            let node = try!(load_node::<T, H>(hash_io, entry));
+                            let sliced = try!(node.slice_node(hash_io, child_start - offset,
+                                                              child_end - offset));
+                            res.push(try!(store_node(hash_io, sliced)));
+                        }
+                    }
+                    offset += len;
+                }
+                Ok(ShallowPVec::new(ShallowNode::Branch(res)))
+            }
+        }
+    }
+}
+
+impl<T> Writable for ShallowPVec<T> {
+    fn write_to<W: Write>(&self, write: &mut W) -> result::Result<usize, io::Error> {
+        let mut size = 0;
+        match self.node {
+            ShallowNode::Leaf(ref hashes) => {
+                size += try!(write_u8(NODE_LEAF, write));
+                size += try!(write_u32(hashes.len() as u32, write));
+                for hash in hashes.iter() {
+                    size += try!(write_hash(hash, write));
+                }
+            },
+            ShallowNode::Branch(ref entries) => {
+                size += try!(write_u8(NODE_BRANCH, write));
+                size += try!(write_u32(entries.len() as u32, write));
+                for &(len, ref hash) in entries.iter() {
+                    size += try!(write_u32(len as u32, write));
+                    size += try!(write_hash(hash, write));
+                }
+            }
+        }
+        Ok(size)
+    }
+}
+
+impl<T> Hashable for ShallowPVec<T> {
+    fn as_hash(&self) -> Hash {
+        self.writable_to_hash()
+    }
+}
+
+impl<T> Typeable for ShallowPVec<T> where T: HashIOParse {
+    fn type_hash() -> Hash {
+        PVec::<T>::type_hash()
+    }
+
+    fn type_name() -> String {
+        PVec::<T>::type_name()
+    }
+}
+
+impl<T> HashIOType for ShallowPVec<T> where T: HashIOParse {
+    fn type_hash_obj(&self) -> Hash {
+        ShallowPVec::<T>::type_hash()
+    }
+
+    fn type_name_obj(&self) -> String {
+        ShallowPVec::<T>::type_name()
+    }
+}
+
+impl<T> HashIOParse for ShallowPVec<T> where T: HashIOParse + 'static {
+    fn parse<H, R>(_: &H, read: &mut R, _: &Option<Hash>)
+                -> Result<Rc<Self>> where H: HashIO, R: Read {
+        Ok(Rc::new(try!(ShallowPVec::read(read))))
+    }
+
+    fn store<H, W>(&self, _: &H, write: &mut W) -> Result<()> where H: HashIO, W: Write {
+        try!(self.write_to(write));
+        Ok(())
+    }
+
+    fn type_hash_valid(hash: &Hash) -> bool {
+        *hash == ShallowPVec::<T>::type_hash()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::rc::Rc;
+    use hashiofile::HashIOFile;
+    use std::fs::{File, create_dir_all, remove_dir_all};
+
+    fn items(range: ::std::ops::Range<usize>) -> Vec<Rc<String>> {
+        range.map(|i| Rc::new(format!("item{}", i))).collect()
+    }
+
+    #[test]
+    fn test_in_memory() {
+        let mut vec: PVec<String> = PVec::new();
+        for item in items(0..1100) {
+            vec = vec.push(item);
+        }
+        assert_eq!(1100, vec.len());
+        assert_eq!(items(0..1100), vec.to_vec());
+        assert_eq!(Some(Rc::new("item1050".to_string())), vec.get(1050));
+        assert_eq!(None, vec.get(1100));
+        assert_eq!(vec.as_hash(), PVec::from_items(&items(0..1100)).as_hash());
+
+        let slice = vec.slice(30, 1070);
+        assert_eq!(items(30..1070), slice.to_vec());
+        assert_eq!(0, vec.slice(20, 10).len());
+
+        let concat = vec.slice(0, 500).concat(&vec.slice(500, 1100));
+        assert_eq!(items(0..1100), concat.to_vec());
+        let concat = PVec::from_items(&items(0..3)).concat(&vec);
+        assert_eq!(1103, concat.len());
+        assert_eq!(Some(Rc::new("item0".to_string())), concat.get(3));
+        let concat = vec.concat(&PVec::from_items(&items(0..40)));
+        assert_eq!(Some(Rc::new("item39".to_string())), concat.get(1139));
+        assert_eq!(items(0..1100), vec.to_vec());
+    }
+
+    #[test]
+    fn test_stored() {
+        remove_dir_all("./unittest/pvectest/").ok();
+        let hash_io = HashIOFile::new("unittest/pvectest".to_string());
+        let vec: PVec<String> = PVec::from_items(&items(0..1000));
+        let root = vec.as_hash();
+        hash_io.put(Rc::new(vec.clone())).unwrap();
+
+        let loaded: Rc<PVec<String>> = hash_io.get(&root).unwrap();
+        assert_eq!(items(0..1000), loaded.to_vec());
+        assert_eq!(Some(Rc::new("item42".to_string())),
+                   PVec::<String>::lookup(&hash_io, &root, 42).unwrap());
+        assert_eq!(None, PVec::<String>::lookup(&hash_io, &root, 1000).unwrap());
+
+        let mut expected = vec.clone();
+        let mut new_root = root;
+        for item in items(1000..1030) {
+            expected = expected.push(item.clone());
+            new_root = PVec::push_onto(&hash_io, &new_root, item).unwrap();
+        }
+        assert_eq!(expected.as_hash(), new_root);
+        let loaded: Rc<PVec<String>> = hash_io.get(&new_root).unwrap();
+        assert_eq!(items(0..1030), loaded.to_vec());
+    }
+
+    #[test]
+    fn test_stored_slice_concat() {
+        remove_dir_all("./unittest/pvecslicetest/").ok();
+        let hash_io = HashIOFile::new("unittest/pvecslicetest".to_string());
+        let vec: PVec<String> = PVec::from_items(&items(0..1100));
+        let small: PVec<String> = PVec::from_items(&items(0..3));
+        let root = vec.as_hash();
+        hash_io.put(Rc::new(vec.clone())).unwrap();
+        hash_io.put(Rc::new(small.clone())).unwrap();
+
+        let slice = PVec::<String>::slice_stored(&hash_io, &root, 30, 1070).unwrap();
+        assert_eq!(vec.slice(30, 1070).as_hash(), slice);
+        let loaded: Rc<PVec<String>> = hash_io.get(&slice).unwrap();
+        assert_eq!(items(30..1070), loaded.to_vec());
+        let empty = PVec::<String>::slice_stored(&hash_io, &root, 20, 10).unwrap();
+        assert_eq!(PVec::<String>::new().as_hash(), empty);
+        let leaf = PVec::<String>::slice_stored(&hash_io, &root, 40, 50).unwrap();
+        assert_eq!(vec.slice(40, 50).as_hash(), leaf);
+
+        let concat = PVec::<String>::concat_stored(&hash_io, &small.as_hash(), &root).unwrap();
+        assert_eq!(small.concat(&vec).as_hash(), concat);
+        let concat = PVec::<String>::concat_stored(&hash_io, &slice, &small.as_hash()).unwrap();
+        assert_eq!(vec.slice(30, 1070).concat(&small).as_hash(), concat);
+        let loaded: Rc<PVec<String>> = hash_io.get(&concat).unwrap();
+        assert_eq!(1043, loaded.len());
+        assert_eq!(root, PVec::<String>::concat_stored(&hash_io, &root, &empty).unwrap());
+    }
+
+    /// Writes a branch node file with the given entries.
+    fn put_branch(hash_io: &HashIOFile, entries: &[(u32, Hash)]) -> Hash {
+        let mut payload: Vec<u8> = Vec::new();
+        write_u8(NODE_BRANCH, &mut payload).unwrap();
+        write_u32(entries.len() as u32, &mut payload).unwrap();
+        for &(len, ref hash) in entries.iter() {
+            write_u32(len, &mut payload).unwrap();
+            write_hash(hash, &mut payload).unwrap();
+        }
+        let hash = Hash::hash_bytes(&payload);
+        let mut data: Vec<u8> = Vec::new();
+        write_u32(1, &mut data).unwrap();
+        write_hash(&PVec::<String>::type_hash(), &mut data).unwrap();
+        data.extend_from_slice(&payload);
+        create_dir_all(hash_io.directory_for_hash(&hash)).unwrap();
+        File::create(hash_io.filename_for_hash(&hash)).unwrap().write_all(&data).unwrap();
+        hash
+    }
+
+    #[test]
+    fn test_invalid_nodes() {
+        remove_dir_all("./unittest/pvecinvalidtest/").ok();
+        let hash_io = HashIOFile::new("unittest/pvecinvalidtest".to_string());
+        let leaf: PVec<String> = PVec::from_items(&items(0..2));
+        hash_io.put(Rc::new(leaf.clone())).unwrap();
+
+        let empty = put_branch(&hash_io, &[]);
+        assert!(hash_io.get::<PVec<String>>(&empty).is_err());
+        assert!(PVec::<String>::lookup(&hash_io, &empty, 0).is_err());
+        assert!(PVec::push_onto(&hash_io, &empty, Rc::new("x".to_string())).is_err());
+
+        // The branch claims more items than the leaf has.
+        let wrong_len = put_branch(&hash_io, &[(5, leaf.as_hash())]);
+        assert!(hash_io.get::<PVec<String>>(&wrong_len).is_err());
+        assert!(PVec::<String>::lookup(&hash_io, &wrong_len, 3).is_err());
+        assert!(PVec::push_onto(&hash_io, &wrong_len, Rc::new("x".to_string())).is_err());
+
+        // A leaf next to a branch breaks the equal depth of the leaves.
+        let branch = put_branch(&hash_io, &[(2, leaf.as_hash())]);
+        let uneven = put_branch(&hash_io, &[(2, leaf.as_hash()), (2, branch)]);
+        assert!(hash_io.get::<PVec<String>>(&uneven).is_err());
+        let valid = put_branch(&hash_io, &[(2, leaf.as_hash()), (2, leaf.as_hash())]);
+        assert_eq!(4, hash_io.get::<PVec<String>>(&valid).unwrap().len());
+    }
+}
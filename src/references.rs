@@ -0,0 +1,297 @@
+//! References of stored objects without loading them as their types.
+//!
+//! # Usage
+//! Tools which work on raw objects, like the garbage collection, the parent
+//! index, `sync` and bundles, need the hashes an object references.
+//! `ReferenceDecoder` reads them from the layout of the object:
+//!
+//! * Objects without header are strings or builtin collections, their
+//!   layout is recognized directly.
+//! * Objects with a header are decoded by the type which was registered
+//!   for their type hash with `register` or by a schema from `add_schema`.
+//!
+//! ```ignore
+//! let decoder = ReferenceDecoder::new().with::<Storage>().with::<Task>();
+//! let children = try!(decoder.decode(&try!(hash_io.get_raw(&hash))));
+//! ```
+//!
+//! The payload of an object with an unknown type is scanned for the bytes
+//! `write_hash` writes, a 1 followed by the 32 bytes of a hash.  The scan
+//! never misses a reference, but a primitive field or binary data which
+//! happens to contain such a sequence is reported as reference too.  Then
+//! the garbage collection keeps objects which are garbage and `remove`
+//! refuses to delete an object which isn't referenced.  Register the types
+//! of a store to avoid the scan.
+
+use hash::*;
+use hashio::*;
+use io::*;
+use schema::TypeSchema;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+type Reader = fn(&[u8], u32, &Hash) -> Result<Option<Vec<Hash>>>;
+
+fn read_references<T>(payload: &[u8], version: u32, type_hash: &Hash)
+        -> Result<Option<Vec<Hash>>> where T: HashIOParse {
+    if !T::version_valid(version) {
+        return Ok(None)
+    }
+    T::payload_references(payload, &Some(*type_hash))
+}
+
+/// Decodes the references of raw objects by their type hash.
+#[derive(Clone)]
+pub struct ReferenceDecoder {
+    readers: BTreeMap<Hash, Reader>,
+    schemas: BTreeMap<Hash, TypeSchema>,
+    /// Current type hashes of the registered types by their legacy hashes.
+    legacy: BTreeMap<Hash, Hash>
+}
+
+impl PartialEq for ReferenceDecoder {
+    fn eq(&self, other: &ReferenceDecoder) -> bool {
+        self.readers.keys().eq(other.readers.keys()) && self.schemas == other.schemas
+    }
+}
+
+impl fmt::Debug for ReferenceDecoder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReferenceDecoder")
+            .field("types", &self.readers.keys().collect::<Vec<&Hash>>())
+            .field("schemas", &self.schemas.keys().collect::<Vec<&Hash>>())
+            .finish()
+    }
+}
+
+impl Default for ReferenceDecoder {
+    fn default() -> ReferenceDecoder {
+        ReferenceDecoder::new()
+    }
+}
+
+impl ReferenceDecoder {
+    /// Creates a decoder which only knows the objects without header.
+    pub fn new() -> ReferenceDecoder {
+        ReferenceDecoder {
+            readers: BTreeMap::new(),
+            schemas: BTreeMap::new(),
+            legacy: BTreeMap::new()
+        }
+    }
+
+    /// Decodes objects of T and of the types T falls back to.
+    pub fn register<T>(&mut self) where T: HashIOParse + 'static {
+        self.readers.insert(T::type_hash(), read_references::<T>);
+        for (_, type_hash) in T::fallback_types() {
+            self.readers.insert(type_hash, read_references::<T>);
+        }
+        for type_hash in T::legacy_type_hashes() {
+            self.legacy.insert(type_hash, T::type_hash());
+        }
+    }
+
+    /// Builder style variant of `register`.
+    pub fn with<T>(mut self) -> ReferenceDecoder where T: HashIOParse + 'static {
+        self.register::<T>();
+        self
+    }
+
+    /// Decodes objects of the type which is described by the schema.
+    pub fn add_schema(&mut self, schema: TypeSchema) {
+        self.schemas.insert(schema.type_hash, schema);
+    }
+
+    /// Builder style variant of `add_schema`.
+    pub fn with_schemas(mut self, schemas: &[TypeSchema]) -> ReferenceDecoder {
+        for schema in schemas {
+            self.add_schema(schema.clone());
+        }
+        self
+    }
+
+    /// Returns the current type hash of a registered type if the type hash
+    /// belongs to one of its legacy schemes, so the layout is the same.
+    pub fn current_type(&self, type_hash: &Hash) -> Option<Hash> {
+        self.legacy.get(type_hash).cloned()
+    }
+
+    /// Reads the references from the layout of the object.
+    ///
+    /// Returns None if the type of the object is unknown.
+    pub fn decode(&self, object: &RawObject) -> Result<Option<Vec<Hash>>> {
+        let res = match object.header {
+            None => headerless_references(&object.payload),
+            Some((version, type_hash)) => match self.readers.get(&type_hash) {
+                Some(reader) => try!(reader(&object.payload, version, &type_hash)),
+                None => match self.schemas.get(&type_hash) {
+                    Some(schema) if version == 1 => schema.payload_references(&object.payload),
+                    _ => None
+                }
+            }
+        };
+        Ok(res.map(|hashes| hashes.into_iter().filter(|hash| *hash != Hash::None).collect()))
+    }
+
+    /// Returns the references of the object to the known objects.
+    ///
+    /// Objects of unknown types are scanned, so the result may contain false
+    /// references, see the module documentation.
+    pub fn references(&self, object: &RawObject, known: &BTreeSet<Hash>) -> Result<Vec<Hash>> {
+        Ok(match try!(self.decode(object)) {
+            Some(hashes) => hashes.into_iter().filter(|hash| known.contains(hash)).collect(),
+            None => find_references(&object.payload, known)
+        })
+    }
+
+    /// Returns the references of the object which exist in the store.
+    ///
+    /// Decoded references are returned as they are.  The hash sequences
+    /// which are found by scanning an object of an unknown type are only
+    /// returned if the store contains them.
+    pub fn stored_references<H>(&self, hash_io: &H, object: &RawObject) -> Result<Vec<Hash>>
+            where H: HashIO {
+        if let Some(hashes) = try!(self.decode(object)) {
+            return Ok(hashes)
+        }
+        let candidates = scan_hashes(&object.payload);
+        let absent: BTreeSet<Hash> = try!(hash_io.missing(&candidates)).into_iter().collect();
+        Ok(candidates.into_iter().filter(|hash| !absent.contains(hash)).collect())
+    }
+}
+
+/// Recognizes strings and the builtin collections by their length.
+///
+/// A string starts with the length of its bytes.  Collections start with a 0
+/// and the number of entries, followed by one hash per entry or by two for
+/// maps.  Both can only be confused if they are empty.
+fn headerless_references(payload: &[u8]) -> Option<Vec<Hash>> {
+    let mut read: &[u8] = payload;
+    let first = match read_u32(&mut read) {
+        Ok(first) => first as usize,
+        Err(_) => return None
+    };
+    if first == read.len() {
+        return Some(Vec::new())
+    }
+    let count = match read_u32(&mut read) {
+        Ok(count) if first == 0 => count as usize,
+        _ => return None
+    };
+    if read.len() != count * 33 && read.len() != count * 66 {
+        return None
+    }
+    let mut res: Vec<Hash> = Vec::new();
+    for bytes in read.chunks(33) {
+        if bytes[0] != 1 {
+            return None
+        }
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&bytes[1..]);
+        res.push(Hash::Sha3(hash));
+    }
+    Some(res)
+}
+
+/// Finds the references to the known objects in the order they appear.
+pub fn find_references(payload: &[u8], known: &BTreeSet<Hash>) -> Vec<Hash> {
+    let mut res: Vec<Hash> = Vec::new();
+    let mut i = 0;
+    while i + 33 <= payload.len() {
+        if payload[i] == 1 {
+            let hash = hash_at(payload, i);
+            if known.contains(&hash) {
+                res.push(hash);
+                i += 33;
+                continue
+            }
+        }
+        i += 1;
+    }
+    res
+}
+
+/// Returns every hash sequence in the payload, including overlapping ones.
+fn scan_hashes(payload: &[u8]) -> Vec<Hash> {
+    let mut res: Vec<Hash> = Vec::new();
+    for i in 0..(payload.len() + 1).saturating_sub(33) {
+        if payload[i] == 1 {
+            res.push(hash_at(payload, i));
+        }
+    }
+    res
+}
+
+fn hash_at(payload: &[u8], i: usize) -> Hash {
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(&payload[i + 1..i + 33]);
+    Hash::Sha3(bytes)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use format::write_object;
+    use hamt::Hamt;
+    use hashiofile::HashIOFile;
+    use schema::HashIOSchema;
+    use std::io::{Read, Write};
+    use std::io;
+    use std::result;
+    use std::rc::Rc;
+
+    hashio_type! {
+        RefTask {
+            priority: u32, read_u32, write_u32
+        } {
+            title: String
+        }
+    }
+
+    fn raw<T>(item: &T) -> RawObject where T: HashIOParse {
+        let hash_io = HashIOFile::new("unittest/referencestest".to_string());
+        let mut data: Vec<u8> = Vec::new();
+        write_object(&hash_io, item, &mut data).unwrap();
+        RawObject::from_file(&item.as_hash(), &data).unwrap()
+    }
+
+    #[test]
+    fn test_decode() {
+        let title = Rc::new("title".to_string());
+        let task = RefTask {
+            priority: 1,
+            title: title.clone()
+        };
+        let decoder = ReferenceDecoder::new().with::<RefTask>();
+        assert_eq!(Some(Vec::new()), decoder.decode(&raw(&*title)).unwrap());
+        assert_eq!(Some(vec![title.as_hash()]), decoder.decode(&raw(&task)).unwrap());
+        let list = vec![Rc::new(task.clone())];
+        assert_eq!(Some(vec![task.as_hash()]), decoder.decode(&raw(&list)).unwrap());
+        assert_eq!(None, ReferenceDecoder::new().decode(&raw(&task)).unwrap());
+
+        let schema_decoder = ReferenceDecoder::new()
+            .with_schemas(&[RefTask::schema()]);
+        assert_eq!(Some(vec![title.as_hash()]), schema_decoder.decode(&raw(&task)).unwrap());
+
+        let map = Hamt::new().insert(title.clone(), Rc::new(task.clone()));
+        let decoder = decoder.with::<Hamt<String, RefTask>>();
+        assert_eq!(Some(vec![title.as_hash(), task.as_hash()]),
+                   decoder.decode(&raw(&map)).unwrap());
+    }
+
+    #[test]
+    fn test_no_false_references() {
+        // A string whose bytes look like a reference to a stored object.
+        let title = Rc::new("title".to_string());
+        let mut payload: Vec<u8> = Vec::new();
+        write_u32(33, &mut payload).unwrap();
+        write_hash(&title.as_hash(), &mut payload).unwrap();
+        let text = RawObject::new(None, payload);
+
+        let mut known: BTreeSet<Hash> = BTreeSet::new();
+        known.insert(title.as_hash());
+        assert_eq!(vec![title.as_hash()], find_references(&text.payload, &known));
+        assert!(ReferenceDecoder::new().references(&text, &known).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,217 @@
+//! Load stored objects without knowing their type at compile time.
+//!
+//! # Usage
+//! `HashIO::get` requires the concrete type.  Tools which inspect arbitrary
+//! objects register all model types in a `TypeRegistry` instead.  The registry
+//! reads the header which is written in front of every object (version and
+//! type hash) and dispatches to the `parse` function of the registered type.
+//!
+//! If a type has fallbacks, objects stored with an older type hash are
+//! dispatched to it as well unless the older type was registered itself.
+//!
+//! Types with an unsafe loader like `String` or `Vec` don't write a header
+//! and can only be loaded as part of their parent objects.
+
+use hash::*;
+use hashio::*;
+use io::*;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::rc::Rc;
+
+struct TypeEntry<H> {
+    type_name: String,
+    direct: bool,
+    loader: Box<Fn(&H, &mut Read, &Hash) -> Result<Rc<HashIOType>>>
+}
+
+/// Maps type hashes to the parse functions of the types.
+pub struct TypeRegistry<H> where H: HashIO {
+    types: BTreeMap<Hash, TypeEntry<H>>
+}
+
+impl<H> TypeRegistry<H> where H: HashIO + 'static {
+    pub fn new() -> TypeRegistry<H> {
+        TypeRegistry {
+            types: BTreeMap::new()
+        }
+    }
+
+    /// Registers the type T and its fallbacks.
+    pub fn register<T>(&mut self) where T: HashIOParse + 'static {
+        self.types.insert(T::type_hash(), TypeEntry {
+            type_name: T::type_name(),
+            direct: true,
+            loader: Box::new(load_dyn::<T, H>)
+        });
+        for (fallback_name, fallback_hash) in T::fallback_types() {
+            let registered = self.types.get(&fallback_hash).map(|entry| entry.direct);
+            if registered != Some(true) {
+                self.types.insert(fallback_hash, TypeEntry {
+                    type_name: fallback_name,
+                    direct: false,
+                    loader: Box::new(load_dyn::<T, H>)
+                });
+            }
+        }
+    }
+
+    /// Builder style variant of register.
+    pub fn with<T>(mut self) -> TypeRegistry<H> where T: HashIOParse + 'static {
+        self.register::<T>();
+        self
+    }
+
+    /// Returns true if objects of the type hash can be loaded.
+    pub fn contains(&self, type_hash: &Hash) -> bool {
+        self.types.contains_key(type_hash)
+    }
+
+    /// Returns the name of the type which is stored with the given type hash.
+    pub fn type_name(&self, type_hash: &Hash) -> Option<String> {
+        self.types.get(type_hash).map(|entry| entry.type_name.clone())
+    }
+
+    /// Lists all type hashes which can be loaded.
+    pub fn type_hashes(&self) -> Vec<Hash> {
+        self.types.keys().cloned().collect()
+    }
+
+    /// Reads the header from the reader and parses the object with the
+    /// registered type.
+    pub fn parse_dyn(&self, hash_io: &H, read: &mut Read) -> Result<Rc<HashIOType>> {
+        let mut read = read;
+        let version = try!(read_u32(&mut read));
+        if version != 1 {
+            return Err(HashIOError::VersionError(version))
+        }
+        let type_hash = try!(read_hash(&mut read));
+        let entry = try!(self.entry(&type_hash));
+        (entry.loader)(hash_io, read, &type_hash)
+    }
+
+    /// Loads the object of the hash using the type which is stored in its
+    /// header.
+    ///
+    /// The object is read with `get_raw`, so a missing object is reported as
+    /// `NotFound` and its content is verified against the hash.  Its children
+    /// are loaded with `get`, which enforces the limits and detects cycles.
+    pub fn get_dyn(&self, hash_io: &H, hash: &Hash) -> Result<Rc<HashIOType>> {
+        trace!("TypeRegistry::get_dyn for {}", hash.as_string());
+        let object = try!(hash_io.get_raw(hash));
+        let type_hash = match object.header {
+            Some((1, type_hash)) => type_hash,
+            Some((version, _)) => return Err(HashIOError::VersionError(version)),
+            None => return Err(HashIOError::Undefined(
+                format!("Object {} has no header", hash.as_string())))
+        };
+        let entry = try!(self.entry(&type_hash));
+        let mut read: &[u8] = &object.payload;
+        (entry.loader)(hash_io, &mut read, &type_hash)
+            .map_err(|err| err.in_object(hash, entry.type_name.clone()))
+    }
+
+    fn entry(&self, type_hash: &Hash) -> Result<&TypeEntry<H>> {
+        self.types.get(type_hash).ok_or(HashIOError::TypeError(*type_hash))
+    }
+}
+
+impl<H> Default for TypeRegistry<H> where H: HashIO + 'static {
+    fn default() -> TypeRegistry<H> {
+        TypeRegistry::new()
+    }
+}
+
+fn load_dyn<T, H>(hash_io: &H, read: &mut Read, type_hash: &Hash) -> Result<Rc<HashIOType>>
+        where T: HashIOParse + 'static, H: HashIO {
+    let mut read = read;
+    let item: Rc<T> = try!(T::parse(hash_io, &mut read, &Some(*type_hash)));
+    Ok(item as Rc<HashIOType>)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::io;
+    use std::collections::BTreeMap;
+    use std::result;
+    use std::rc::Rc;
+    use hashiofile::HashIOFile;
+    use std::fs::remove_dir_all;
+
+    hashio_type! {
+        RegistryOld {
+            x: u32, read_u32, write_u32
+        } {
+        }
+    }
+    hashio_type! {
+        RegistryItem {
+            x: u32, read_u32, write_u32
+        } {
+            name: String,
+            tags: Vec<Rc<String>>
+        }
+        fallback => RegistryOld
+    }
+    impl From<Rc<RegistryOld>> for RegistryItem {
+        fn from(old: Rc<RegistryOld>) -> RegistryItem {
+            RegistryItem {
+                x: old.x,
+                name: Rc::new("".to_string()),
+                tags: Rc::new(Vec::new())
+            }
+        }
+    }
+
+    #[test]
+    fn test_get_dyn() {
+        remove_dir_all("./unittest/registrytest/").ok();
+        let hash_io = HashIOFile::new("unittest/registrytest".to_string());
+        let item = RegistryItem {
+            x: 5,
+            name: Rc::new("item".to_string()),
+            tags: Rc::new(vec![Rc::new("a".to_string())])
+        };
+        let old = RegistryOld { x: 6 };
+        let item_hash = item.as_hash();
+        let old_hash = old.as_hash();
+        hash_io.put(Rc::new(item)).unwrap();
+        hash_io.put(Rc::new(old)).unwrap();
+
+        let registry: TypeRegistry<HashIOFile> = TypeRegistry::new().with::<RegistryItem>();
+        assert!(registry.contains(&RegistryItem::type_hash()));
+        assert_eq!(Some("RegistryOld".to_string()),
+                   registry.type_name(&RegistryOld::type_hash()));
+
+        let loaded = registry.get_dyn(&hash_io, &item_hash).unwrap();
+        assert_eq!("RegistryItem", loaded.type_name_obj());
+        assert_eq!(item_hash, loaded.as_hash());
+        let childs = loaded.childs();
+        assert_eq!(Rc::new("item".to_string()).as_hash(), childs.get("name").unwrap().as_hash());
+        assert_eq!("Vec<String>", childs.get("tags").unwrap().type_name_obj());
+
+        // Old objects are loaded through the fallback
+        let migrated = registry.get_dyn(&hash_io, &old_hash).unwrap();
+        assert_eq!("RegistryItem", migrated.type_name_obj());
+
+        // Registering the old type directly takes precedence
+        let registry = registry.with::<RegistryOld>();
+        let old_loaded = registry.get_dyn(&hash_io, &old_hash).unwrap();
+        assert_eq!("RegistryOld", old_loaded.type_name_obj());
+
+        // Unknown types are rejected
+        let empty: TypeRegistry<HashIOFile> = TypeRegistry::new();
+        match empty.get_dyn(&hash_io, &item_hash) {
+            Err(HashIOError::TypeError(type_hash)) =>
+                assert_eq!(RegistryItem::type_hash(), type_hash),
+            _ => panic!("Expected a type error")
+        }
+        match registry.get_dyn(&hash_io, &Hash::hash_bytes(b"missing")) {
+            Err(HashIOError::NotFound(_)) => (),
+            _ => panic!("Expected a missing object")
+        }
+    }
+}
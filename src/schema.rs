@@ -0,0 +1,388 @@
+//! Machine-readable description of HashIO models.
+//!
+//! # Usage
+//! Every model generated by `hashio_type!` implements `HashIOSchema` and can
+//! describe itself: its name, type hash, primitive fields with their codecs,
+//! children with their type hashes and the types it falls back to.
+//!
+//! Schemas can be stored as JSON snapshots.  `check_compatibility` compares
+//! an old snapshot with the current schemas and reports changes which would
+//! make existing data unreadable or, worse, silently read it into the wrong
+//! fields.  Running it in the tests catches accidental type hash changes.
+
+use hash::*;
+use io::*;
+use json::*;
+use std::{error, fmt};
+
+/// Primitive field of a model which is stored directly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldSchema {
+    pub name: String,
+    pub type_name: String,
+    pub read_fn: String,
+    pub write_fn: String,
+}
+
+/// Child of a model which is stored as own object and referenced by its hash.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChildSchema {
+    pub name: String,
+    pub type_name: String,
+    pub type_hash: Hash,
+}
+
+/// Older type which can still be read by a model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FallbackSchema {
+    pub type_name: String,
+    pub type_hash: Hash,
+}
+
+/// Complete description of a model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeSchema {
+    pub name: String,
+    pub type_hash: Hash,
+    /// Type hash scheme, either "v1" or "v2".
+    pub scheme: String,
+    pub fields: Vec<FieldSchema>,
+    pub childs: Vec<ChildSchema>,
+    pub fallbacks: Vec<FallbackSchema>,
+}
+
+/// Types which are able to describe themselves.
+pub trait HashIOSchema {
+    fn schema() -> TypeSchema;
+}
+
+/// Error when a schema snapshot cannot be read.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchemaError {
+    JsonError(JsonError),
+    InvalidSchema(String),
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SchemaError::JsonError(ref err) => write!(f, "{}", err),
+            SchemaError::InvalidSchema(ref msg) => write!(f, "Invalid schema: {}", msg)
+        }
+    }
+}
+
+impl error::Error for SchemaError {
+    fn description(&self) -> &str {
+        match *self {
+            SchemaError::JsonError(ref err) => &err.message,
+            SchemaError::InvalidSchema(ref msg) => msg
+        }
+    }
+}
+
+impl From<JsonError> for SchemaError {
+    fn from(err: JsonError) -> SchemaError {
+        SchemaError::JsonError(err)
+    }
+}
+
+impl From<FieldError> for SchemaError {
+    fn from(err: FieldError) -> SchemaError {
+        SchemaError::InvalidSchema(err.message)
+    }
+}
+
+
+impl FieldSchema {
+    /// Number of bytes the field takes in the payload, None for unknown codecs.
+    pub fn stored_len(&self) -> Option<usize> {
+        match self.read_fn.trim_start_matches("read_") {
+            "u8" => Some(1),
+            "i16" => Some(2),
+            "u32" | "i32" | "f32" => Some(4),
+            "tm" => Some(44),
+            _ => None
+        }
+    }
+}
+
+impl TypeSchema {
+    /// Returns true if data stored with the given type hash can be read.
+    pub fn accepts(&self, type_hash: &Hash) -> bool {
+        self.type_hash == *type_hash ||
+            self.fallbacks.iter().any(|fallback| fallback.type_hash == *type_hash)
+    }
+
+    /// Reads the child hashes from the payload of an object of this type.
+    ///
+    /// Returns None if a codec is unknown or the payload doesn't match the
+    /// fields and children.
+    pub fn payload_references(&self, payload: &[u8]) -> Option<Vec<Hash>> {
+        let fields_len = self.fields.iter().map(FieldSchema::stored_len).sum::<Option<usize>>();
+        let mut read: &[u8] = match fields_len {
+            Some(len) if len <= payload.len() => &payload[len..],
+            _ => return None
+        };
+        let mut res: Vec<Hash> = Vec::new();
+        for _ in self.childs.iter() {
+            match read_hash(&mut read) {
+                Ok(hash) => res.push(hash),
+                Err(_) => return None
+            }
+        }
+        if !read.is_empty() {
+            return None
+        }
+        Some(res)
+    }
+
+    pub fn to_json(&self) -> Json {
+        json_object(vec![
+            ("name", json_str(&self.name)),
+            ("type_hash", json_str(&self.type_hash.as_string())),
+            ("scheme", json_str(&self.scheme)),
+            ("fields", Json::Array(self.fields.iter().map(|field| json_object(vec![
+                ("name", json_str(&field.name)),
+                ("type_name", json_str(&field.type_name)),
+                ("read_fn", json_str(&field.read_fn)),
+                ("write_fn", json_str(&field.write_fn)),
+            ])).collect())),
+            ("childs", Json::Array(self.childs.iter().map(|child| json_object(vec![
+                ("name", json_str(&child.name)),
+                ("type_name", json_str(&child.type_name)),
+                ("type_hash", json_str(&child.type_hash.as_string())),
+            ])).collect())),
+            ("fallbacks", Json::Array(self.fallbacks.iter().map(|fallback| json_object(vec![
+                ("type_name", json_str(&fallback.type_name)),
+                ("type_hash", json_str(&fallback.type_hash.as_string())),
+            ])).collect())),
+        ])
+    }
+
+    pub fn from_json(json: &Json) -> Result<TypeSchema, SchemaError> {
+        let mut fields: Vec<FieldSchema> = Vec::new();
+        for field in try!(get_array(json, "fields")) {
+            fields.push(FieldSchema {
+                name: try!(get_string(field, "name")),
+                type_name: try!(get_string(field, "type_name")),
+                read_fn: try!(get_string(field, "read_fn")),
+                write_fn: try!(get_string(field, "write_fn")),
+            });
+        }
+        let mut childs: Vec<ChildSchema> = Vec::new();
+        for child in try!(get_array(json, "childs")) {
+            childs.push(ChildSchema {
+                name: try!(get_string(child, "name")),
+                type_name: try!(get_string(child, "type_name")),
+                type_hash: try!(get_hash(child, "type_hash")),
+            });
+        }
+        let mut fallbacks: Vec<FallbackSchema> = Vec::new();
+        for fallback in try!(get_array(json, "fallbacks")) {
+            fallbacks.push(FallbackSchema {
+                type_name: try!(get_string(fallback, "type_name")),
+                type_hash: try!(get_hash(fallback, "type_hash")),
+            });
+        }
+        Ok(TypeSchema {
+            name: try!(get_string(json, "name")),
+            type_hash: try!(get_hash(json, "type_hash")),
+            scheme: try!(get_string(json, "scheme")),
+            fields: fields,
+            childs: childs,
+            fallbacks: fallbacks,
+        })
+    }
+}
+
+/// Serializes a list of schemas into a JSON snapshot.
+pub fn schemas_to_json(schemas: &[TypeSchema]) -> String {
+    Json::Array(schemas.iter().map(|schema| schema.to_json()).collect()).to_pretty_string()
+}
+
+/// Reads a JSON snapshot created by `schemas_to_json`.
+pub fn schemas_from_json(input: &str) -> Result<Vec<TypeSchema>, SchemaError> {
+    let json = try!(Json::parse(input));
+    let array = try!(json.as_array()
+        .ok_or(SchemaError::InvalidSchema("Expected an array of schemas".to_string())));
+    let mut res: Vec<TypeSchema> = Vec::new();
+    for item in array {
+        res.push(try!(TypeSchema::from_json(item)));
+    }
+    Ok(res)
+}
+
+
+/// Difference between two schema snapshots.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchemaChange {
+    /// A new type was added.
+    TypeAdded(String),
+    /// The type doesn't exist anymore and no other type falls back to it.
+    TypeRemoved(String),
+    /// The type hash changed, `migrated` is true if the new type falls back
+    /// to the old type hash.
+    TypeHashChanged {
+        type_name: String,
+        old_hash: Hash,
+        new_hash: Hash,
+        migrated: bool,
+    },
+    /// The fields changed but the type hash stayed the same, so old data
+    /// would be read into the new fields.
+    LayoutChanged(String),
+}
+
+impl SchemaChange {
+    /// Returns true if existing data cannot be read correctly anymore.
+    pub fn is_breaking(&self) -> bool {
+        match *self {
+            SchemaChange::TypeAdded(_) => false,
+            SchemaChange::TypeRemoved(_) => true,
+            SchemaChange::TypeHashChanged { migrated, .. } => !migrated,
+            SchemaChange::LayoutChanged(_) => true
+        }
+    }
+}
+
+/// Compares two schema snapshots and lists all changes.
+pub fn check_compatibility(old: &[TypeSchema], new: &[TypeSchema]) -> Vec<SchemaChange> {
+    let mut res: Vec<SchemaChange> = Vec::new();
+    for old_schema in old {
+        match new.iter().find(|schema| schema.name == old_schema.name) {
+            None => {
+                if !new.iter().any(|schema| schema.accepts(&old_schema.type_hash)) {
+                    res.push(SchemaChange::TypeRemoved(old_schema.name.clone()));
+                }
+            },
+            Some(new_schema) => {
+                if new_schema.type_hash != old_schema.type_hash {
+                    res.push(SchemaChange::TypeHashChanged {
+                        type_name: old_schema.name.clone(),
+                        old_hash: old_schema.type_hash,
+                        new_hash: new_schema.type_hash,
+                        migrated: new_schema.accepts(&old_schema.type_hash),
+                    });
+                } else if new_schema.fields != old_schema.fields ||
+                        new_schema.childs.iter().map(|child| &child.name).collect::<Vec<_>>() !=
+                        old_schema.childs.iter().map(|child| &child.name).collect::<Vec<_>>() {
+                    res.push(SchemaChange::LayoutChanged(old_schema.name.clone()));
+                }
+            }
+        }
+    }
+    for new_schema in new {
+        if !old.iter().any(|schema| schema.name == new_schema.name) {
+            res.push(SchemaChange::TypeAdded(new_schema.name.clone()));
+        }
+    }
+    res
+}
+
+/// Returns only the changes which break existing data.
+pub fn breaking_changes(old: &[TypeSchema], new: &[TypeSchema]) -> Vec<SchemaChange> {
+    check_compatibility(old, new).into_iter()
+        .filter(|change| change.is_breaking())
+        .collect()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::hashio::*;
+    use std::io::{Read, Write};
+    use std::io;
+    use std::collections::BTreeMap;
+    use std::result;
+    use std::rc::Rc;
+
+    hashio_type! {
+        SchemaOld {
+            x: u32, read_u32, write_u32
+        } {
+            a: String
+        }
+    }
+    hashio_type! {
+        SchemaNew {
+            x: u32, read_u32, write_u32,
+            y: f32, read_f32, write_f32
+        } {
+            a: String,
+            b: Vec<Rc<String>>
+        }
+        type_hash => v2
+        fallback => SchemaOld
+    }
+    impl From<Rc<SchemaOld>> for SchemaNew {
+        fn from(old: Rc<SchemaOld>) -> SchemaNew {
+            SchemaNew {
+                x: old.x,
+                y: 0.0,
+                a: old.a.clone(),
+                b: Rc::new(Vec::new())
+            }
+        }
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema = SchemaNew::schema();
+        assert_eq!("SchemaNew", schema.name);
+        assert_eq!(SchemaNew::type_hash(), schema.type_hash);
+        assert_eq!("v2", schema.scheme);
+        assert_eq!(FieldSchema {
+            name: "y".to_string(),
+            type_name: "f32".to_string(),
+            read_fn: "read_f32".to_string(),
+            write_fn: "write_f32".to_string()
+        }, schema.fields[1]);
+        assert_eq!(ChildSchema {
+            name: "b".to_string(),
+            type_name: "Vec<String>".to_string(),
+            type_hash: Vec::<Rc<String>>::type_hash()
+        }, schema.childs[1]);
+        assert_eq!(vec![FallbackSchema {
+            type_name: "SchemaOld".to_string(),
+            type_hash: SchemaOld::type_hash()
+        }], schema.fallbacks);
+        assert_eq!("v1", SchemaOld::schema().scheme);
+
+        let snapshot = schemas_to_json(&[SchemaOld::schema(), schema.clone()]);
+        assert_eq!(vec![SchemaOld::schema(), schema], schemas_from_json(&snapshot).unwrap());
+    }
+
+    #[test]
+    fn test_compatibility() {
+        let old = vec![SchemaOld::schema()];
+        assert_eq!(Vec::<SchemaChange>::new(), check_compatibility(&old, &old));
+
+        // Renamed with a fallback to the old type is fine
+        let new = vec![SchemaNew::schema()];
+        assert_eq!(vec![SchemaChange::TypeAdded("SchemaNew".to_string())],
+                   check_compatibility(&old, &new));
+        assert!(breaking_changes(&old, &new).is_empty());
+
+        // Changing the hash without fallback breaks
+        let mut changed = SchemaOld::schema();
+        changed.type_hash = SchemaNew::type_hash();
+        let changes = breaking_changes(&old, &[changed.clone()]);
+        assert_eq!(1, changes.len());
+        changed.fallbacks.push(FallbackSchema {
+            type_name: "SchemaOld".to_string(),
+            type_hash: SchemaOld::type_hash()
+        });
+        assert!(breaking_changes(&old, &[changed]).is_empty());
+
+        // Renaming a field keeps the v1 hash but changes the layout
+        let mut renamed = SchemaOld::schema();
+        renamed.fields[0].name = "z".to_string();
+        assert_eq!(vec![SchemaChange::LayoutChanged("SchemaOld".to_string())],
+                   breaking_changes(&old, &[renamed]));
+
+        assert_eq!(vec![SchemaChange::TypeRemoved("SchemaOld".to_string())],
+                   check_compatibility(&old, &[]));
+    }
+}
@@ -0,0 +1,297 @@
+//! Statistics and space accounting of a `HashIOFile` store.
+//!
+//! # Usage
+//! `StoreStats::collect` scans all objects of a store and counts the objects
+//! and bytes per type, the largest objects and the distribution of the
+//! number of references per object.  `subtree_stats` tells how much of the
+//! graph below a root is shared with other roots.
+//!
+//! Like the `inspect` module, the statistics only rely on the file format
+//! and the `ReferenceDecoder` of the store.
+//! Objects with a header are grouped by the type name from the given schemas
+//! or by their type hash.  Objects without header are grouped as `String`
+//! or `collection`.
+//!
+//! Sizes are the sizes of the files including the header.
+
+use hash::*;
+use hashio::*;
+use hashiofile::*;
+use io::read_str;
+use schema::TypeSchema;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Number of objects reported as largest objects.
+pub const LARGEST_COUNT: usize = 10;
+
+/// Objects and bytes of one type.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TypeStats {
+    pub count: usize,
+    pub bytes: u64
+}
+
+/// Statistics over all objects of a store.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoreStats {
+    pub object_count: usize,
+    pub total_bytes: u64,
+    pub by_type: BTreeMap<String, TypeStats>,
+    /// Bytes the objects would need if every reference had its own copy.
+    pub logical_bytes: u64,
+    /// The largest objects, largest first.
+    pub largest: Vec<(Hash, u64)>,
+    /// Maps the number of references in an object to the number of objects.
+    pub fan_out: BTreeMap<usize, usize>
+}
+
+/// Space used by the graph below a root.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SubtreeStats {
+    pub object_count: usize,
+    pub total_bytes: u64,
+    /// Objects which are only reachable through the root.  This space is
+    /// freed by the garbage collection if the root is dropped.
+    pub unique_count: usize,
+    pub unique_bytes: u64,
+    /// Objects which are also reachable from other roots.
+    pub shared_count: usize,
+    pub shared_bytes: u64
+}
+
+struct ObjectEntry {
+    label: String,
+    size: u64,
+    references: Vec<Hash>
+}
+
+impl StoreStats {
+    pub fn collect(hash_io: &HashIOFile, schemas: &[TypeSchema]) -> Result<StoreStats> {
+        let objects = try!(scan(hash_io, schemas));
+        let mut res = StoreStats {
+            object_count: objects.len(),
+            total_bytes: 0,
+            by_type: BTreeMap::new(),
+            logical_bytes: 0,
+            largest: Vec::new(),
+            fan_out: BTreeMap::new()
+        };
+        let path_counts = path_counts(&objects);
+        for (hash, entry) in objects.iter() {
+            res.total_bytes += entry.size;
+            res.logical_bytes += entry.size * path_counts.get(hash).cloned().unwrap_or(1);
+            let type_stats = res.by_type.entry(entry.label.clone()).or_default();
+            type_stats.count += 1;
+            type_stats.bytes += entry.size;
+            *res.fan_out.entry(entry.references.len()).or_insert(0) += 1;
+            res.largest.push((*hash, entry.size));
+        }
+        res.largest.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        res.largest.truncate(LARGEST_COUNT);
+        Ok(res)
+    }
+
+    /// Logical bytes divided by stored bytes, 1.0 means nothing is shared.
+    pub fn dedup_ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            1.0
+        } else {
+            self.logical_bytes as f64 / self.total_bytes as f64
+        }
+    }
+
+    /// Human readable summary.
+    pub fn report(&self) -> String {
+        let mut res = String::new();
+        res.push_str(&format!("objects:     {}\n", self.object_count));
+        res.push_str(&format!("bytes:       {}\n", self.total_bytes));
+        res.push_str(&format!("logical:     {}\n", self.logical_bytes));
+        res.push_str(&format!("dedup ratio: {:.2}\n", self.dedup_ratio()));
+        res.push_str("\ntypes:\n");
+        for (label, type_stats) in self.by_type.iter() {
+            res.push_str(&format!("{:>8} {:>12} {}\n", type_stats.count, type_stats.bytes, label));
+        }
+        res.push_str("\nlargest objects:\n");
+        for &(ref hash, size) in self.largest.iter() {
+            res.push_str(&format!("{:>12} {}\n", size, hash.as_string()));
+        }
+        res.push_str("\nreferences per object:\n");
+        for (references, count) in self.fan_out.iter() {
+            res.push_str(&format!("{:>8} {:>8}\n", references, count));
+        }
+        res
+    }
+}
+
+/// Splits the graph below root into the objects which are only reachable
+/// through it and the ones which are shared with other roots.
+///
+/// Other roots are all objects which are not referenced by any object.  If
+/// root itself is referenced, the whole graph counts as shared.
+pub fn subtree_stats(hash_io: &HashIOFile, root: &Hash) -> Result<SubtreeStats> {
+    let objects = try!(scan(hash_io, &[]));
+    let referenced: BTreeSet<Hash> = objects.values()
+        .flat_map(|entry| entry.references.iter().cloned())
+        .collect();
+    let other_roots: Vec<Hash> = objects.keys()
+        .filter(|hash| *hash != root && !referenced.contains(hash))
+        .cloned()
+        .collect();
+    let shared = reachable_in(&objects, &other_roots);
+    let mut res = SubtreeStats::default();
+    for hash in reachable_in(&objects, &[*root]) {
+        let size = objects.get(&hash).map(|entry| entry.size).unwrap_or(0);
+        res.object_count += 1;
+        res.total_bytes += size;
+        if shared.contains(&hash) || referenced.contains(root) {
+            res.shared_count += 1;
+            res.shared_bytes += size;
+        } else {
+            res.unique_count += 1;
+            res.unique_bytes += size;
+        }
+    }
+    Ok(res)
+}
+
+fn scan(hash_io: &HashIOFile, schemas: &[TypeSchema]) -> Result<BTreeMap<Hash, ObjectEntry>> {
+    let hashes = try!(hash_io.hashes());
+    let known: BTreeSet<Hash> = hashes.iter().cloned().collect();
+    let mut res: BTreeMap<Hash, ObjectEntry> = BTreeMap::new();
+    for hash in hashes {
+        let object = try!(hash_io.get_raw(&hash));
+        res.insert(hash, ObjectEntry {
+            label: label(&object, schemas),
+            size: object.file_len() as u64,
+            references: try!(hash_io.decoder.references(&object, &known))
+        });
+    }
+    Ok(res)
+}
+
+fn label(object: &RawObject, schemas: &[TypeSchema]) -> String {
+    match object.header {
+        Some((_, ref type_hash)) => schemas.iter()
+            .find(|schema| schema.accepts(type_hash))
+            .map(|schema| schema.name.clone())
+            .unwrap_or_else(|| type_hash.as_string()),
+        None => match read_str(&object.payload) {
+            Ok((_, [])) => "String".to_string(),
+            _ => "collection".to_string()
+        }
+    }
+}
+
+/// Counts the paths from the roots to each object.
+///
+/// This is the number of copies an object would have without sharing.
+/// Parents are visited before their references, so the count of a parent is
+/// complete when it is passed on.
+fn path_counts(objects: &BTreeMap<Hash, ObjectEntry>) -> BTreeMap<Hash, u64> {
+    let mut in_degree: BTreeMap<Hash, usize> = BTreeMap::new();
+    for entry in objects.values() {
+        for reference in entry.references.iter() {
+            *in_degree.entry(*reference).or_insert(0) += 1;
+        }
+    }
+    let mut res: BTreeMap<Hash, u64> = BTreeMap::new();
+    let mut pending: Vec<Hash> = Vec::new();
+    for hash in objects.keys() {
+        if !in_degree.contains_key(hash) {
+            res.insert(*hash, 1);
+            pending.push(*hash);
+        }
+    }
+    while let Some(hash) = pending.pop() {
+        let count = res.get(&hash).cloned().unwrap_or(0);
+        let references = match objects.get(&hash) {
+            Some(entry) => &entry.references,
+            None => continue
+        };
+        for reference in references.iter() {
+            *res.entry(*reference).or_insert(0) += count;
+            let remaining = in_degree.get_mut(reference).unwrap();
+            *remaining -= 1;
+            if *remaining == 0 {
+                pending.push(*reference);
+            }
+        }
+    }
+    res
+}
+
+fn reachable_in(objects: &BTreeMap<Hash, ObjectEntry>, roots: &[Hash]) -> BTreeSet<Hash> {
+    let mut res: BTreeSet<Hash> = BTreeSet::new();
+    let mut pending: Vec<Hash> = roots.to_vec();
+    while let Some(hash) = pending.pop() {
+        if !res.insert(hash) {
+            continue
+        }
+        if let Some(entry) = objects.get(&hash) {
+            pending.extend(entry.references.iter().cloned());
+        }
+    }
+    res
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fixtures::*;
+    use schema::HashIOSchema;
+    use std::rc::Rc;
+    use std::fs::remove_dir_all;
+
+    #[test]
+    fn test_stats() {
+        remove_dir_all("./unittest/statstest/").ok();
+        let hash_io = HashIOFile::new("unittest/statstest".to_string());
+        let old = storage(&["a", "b", "b"]);
+        let new = storage(&["a", "c"]);
+        hash_io.put(Rc::new(old.clone())).unwrap();
+        hash_io.put(Rc::new(new.clone())).unwrap();
+
+        let stats = StoreStats::collect(&hash_io, &[FixtureTask::schema()]).unwrap();
+        // two roots, two lists, three tasks and three titles
+        assert_eq!(10, stats.object_count);
+        assert_eq!(3, stats.by_type["FixtureTask"].count);
+        assert_eq!(3, stats.by_type["String"].count);
+        assert_eq!(2, stats.by_type["collection"].count);
+        assert_eq!(2, stats.by_type[&FixtureStorage::type_hash().as_string()].count);
+        assert_eq!(stats.total_bytes, stats.by_type.values().map(|s| s.bytes).sum());
+        assert!(stats.dedup_ratio() > 1.0);
+        assert_eq!(LARGEST_COUNT, stats.largest.len());
+        assert!(stats.largest[0].1 >= stats.largest[1].1);
+        // the roots and tasks have one reference, the titles none
+        assert_eq!(Some(&5), stats.fan_out.get(&1));
+        assert_eq!(Some(&3), stats.fan_out.get(&0));
+        assert!(stats.report().contains("FixtureTask"));
+
+        let subtree = subtree_stats(&hash_io, &new.as_hash()).unwrap();
+        // root, list, task c and its title are unique, task a and its
+        // title are shared with the old root.
+        assert_eq!(6, subtree.object_count);
+        assert_eq!(4, subtree.unique_count);
+        assert_eq!(2, subtree.shared_count);
+        assert_eq!(subtree.total_bytes, subtree.unique_bytes + subtree.shared_bytes);
+    }
+
+    #[test]
+    fn test_logical_bytes_nested() {
+        remove_dir_all("./unittest/statsnestedtest/").ok();
+        let hash_io = HashIOFile::new("unittest/statsnestedtest".to_string());
+        // The task and its title two levels below the list are shared by
+        // three entries.
+        let stored = storage(&["a", "a", "a"]);
+        hash_io.put(Rc::new(stored.clone())).unwrap();
+        let size = |hash: &Hash| hash_io.get_raw(hash).unwrap().file_len() as u64;
+        let task = &stored.tasks[0];
+        let expected = size(&stored.as_hash()) + size(&stored.tasks.as_hash())
+            + 3 * size(&task.as_hash()) + 3 * size(&task.title.as_hash());
+
+        let stats = StoreStats::collect(&hash_io, &[]).unwrap();
+        assert_eq!(4, stats.object_count);
+        assert_eq!(expected, stats.logical_bytes);
+    }
+}
@@ -39,10 +39,11 @@ impl HashIOType for String {
 }
 
 impl HashIOParse for String {
-    fn parse<H, R>(_: &H, read: &mut R, _: &Option<Hash>) -> Result<Rc<Self>>
+    fn parse<H, R>(hash_io: &H, read: &mut R, _: &Option<Hash>) -> Result<Rc<Self>>
         where H: HashIO, R: Read {
-        let len = try!(read_u32(read));
-        let bytes = try!(read_bytes(read, len as usize));
+        let len = try!(read_u32(read)) as usize;
+        try!(hash_io.limits().check_string_len(len));
+        let bytes = try!(read_bytes_max(read, len, len));
         let res = try!(String::from_utf8(bytes).map_err(|x| HashIOError::ParseError(Box::new(x))));
         Ok(Rc::new(res))
     }
@@ -0,0 +1,129 @@
+//! Synchronization between two HashIO stores.
+//!
+//! # Usage
+//! `sync` copies the graphs below the given roots from a source to a
+//! destination.  Pushing and pulling is the same operation with swapped
+//! stores.  Like `HashIOFile::put`, it stops at every object which already
+//! exists on the destination, because then its children exist as well.
+//! Only the missing objects are written and counted in the `SyncReport`.
+//!
+//! The objects are copied as raw objects, so the types don't have to be
+//! known.  The `ReferenceDecoder` finds the children of an object, see the
+//! `references` module for objects of unregistered types.  Children are
+//! written before their parents, so an interrupted sync never leaves an
+//! object without its children on the destination.
+
+use hash::*;
+use hashio::*;
+use references::ReferenceDecoder;
+use std::collections::BTreeSet;
+
+/// Summary of a synchronization.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SyncReport {
+    /// Number of objects written to the destination.
+    pub objects: usize,
+    /// Size of the written objects including their headers.
+    pub bytes: u64,
+    /// Number of subtrees which already existed on the destination.
+    pub skipped: usize
+}
+
+/// Copies the objects below the roots which are missing on the destination.
+pub fn sync<S, D>(source: &S, destination: &D, decoder: &ReferenceDecoder, roots: &[Hash])
+        -> Result<SyncReport> where S: HashIO, D: HashIO {
+    let mut report = SyncReport::default();
+    let mut visited: BTreeSet<Hash> = BTreeSet::new();
+    // Objects whose missing children are still copied, with these children.
+    let mut stack: Vec<(RawObject, Vec<Hash>)> = Vec::new();
+    let mut pending = try!(missing_childs(destination, roots, &mut report));
+    loop {
+        let next = match stack.last_mut() {
+            Some(&mut (_, ref mut childs)) => childs.pop(),
+            None => pending.pop()
+        };
+        match next {
+            Some(hash) => {
+                if !visited.insert(hash) {
+                    continue
+                }
+                let object = try!(source.get_raw(&hash));
+                let childs = try!(decoder.stored_references(source, &object));
+                let childs = try!(missing_childs(destination, &childs, &mut report));
+                stack.push((object, childs));
+            },
+            None => match stack.pop() {
+                Some((object, _)) => {
+                    try!(destination.put_raw(&object));
+                    report.objects += 1;
+                    report.bytes += object.file_len() as u64;
+                },
+                None => break
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Returns the hashes which are missing on the destination and counts the others.
+fn missing_childs<D>(destination: &D, hashes: &[Hash], report: &mut SyncReport)
+        -> Result<Vec<Hash>> where D: HashIO {
+    let missing = try!(destination.missing(hashes));
+    report.skipped += hashes.len() - missing.len();
+    Ok(missing)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fixtures::*;
+    use hashiofile::HashIOFile;
+    use std::rc::Rc;
+    use std::fs::remove_dir_all;
+
+    #[test]
+    fn test_sync() {
+        remove_dir_all("./unittest/synctest/").ok();
+        let laptop = HashIOFile::new("unittest/synctest/laptop".to_string());
+        let server = HashIOFile::new("unittest/synctest/server".to_string());
+        let old = storage(&["a", "b"]);
+        let new = storage(&["a", "c"]);
+        laptop.put(Rc::new(old.clone())).unwrap();
+        laptop.put(Rc::new(new.clone())).unwrap();
+
+        let decoder = ReferenceDecoder::new().with::<FixtureStorage>().with::<FixtureTask>();
+        let first = sync(&laptop, &server, &decoder, &[old.as_hash()]).unwrap();
+        assert_eq!(6, first.objects);
+        assert_eq!(0, first.skipped);
+        let loaded: Rc<FixtureStorage> = server.get(&old.as_hash()).unwrap();
+        assert_eq!(old.as_hash(), loaded.as_hash());
+
+        // Only the root, the list and task c with its title are missing.
+        let report = sync(&laptop, &server, &decoder, &[new.as_hash()]).unwrap();
+        assert_eq!(4, report.objects);
+        assert_eq!(1, report.skipped);
+        assert_eq!(laptop.hashes().unwrap(), server.hashes().unwrap());
+        let bytes: usize = server.hashes().unwrap().iter()
+            .map(|hash| server.read_file(hash).unwrap().len())
+            .sum();
+        assert_eq!(bytes as u64, first.bytes + report.bytes);
+
+        let report = sync(&server, &laptop, &decoder, &[new.as_hash()]).unwrap();
+        assert_eq!(SyncReport { objects: 0, bytes: 0, skipped: 1 }, report);
+    }
+
+    #[test]
+    fn test_sync_unknown_types() {
+        remove_dir_all("./unittest/syncrawtest/").ok();
+        let laptop = HashIOFile::new("unittest/syncrawtest/laptop".to_string());
+        let server = HashIOFile::new("unittest/syncrawtest/server".to_string());
+        let storage = storage(&["a", "b"]);
+        laptop.put(Rc::new(storage.clone())).unwrap();
+
+        // Without registered types, the references are found by scanning.
+        let report = sync(&laptop, &server, &ReferenceDecoder::new(), &[storage.as_hash()])
+            .unwrap();
+        assert_eq!(6, report.objects);
+        assert_eq!(laptop.hashes().unwrap(), server.hashes().unwrap());
+    }
+}
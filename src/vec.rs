@@ -48,6 +48,11 @@ impl<T> HashIOType for Vec<Rc<T>> where T: HashIOParse + 'static {
         res
     }
 
+    /// Collections store nothing beside their entries.
+    fn attributes_hash(&self) -> Option<Hash> {
+        Some(Hash::hash_bytes(&[]))
+    }
+
     fn type_hash_obj(&self) -> Hash {
         Vec::<Rc<T>>::type_hash()
     }
@@ -63,10 +68,11 @@ impl<T> HashIOParse for Vec<Rc<T>> where T: HashIOParse + 'static {
         // read and ignore version
         try!(read_u32(read));
         let len = try!(read_u32(read));
+        try!(hash_io.limits().check_collection_len(len as usize));
         let mut res: Vec<Rc<T>> = Vec::new();
-        for _ in 0..len {
+        for i in 0..len {
             let item_hash = try!(read_hash(read));
-            let item: Rc<T> = try!(hash_io.get(&item_hash));
+            let item: Rc<T> = try!(hash_io.get(&item_hash).map_err(|err| err.in_item(i)));
             res.push(item);
         }
         Ok(Rc::new(res))
@@ -13,6 +13,7 @@ use std::result;
 use std::rc::Rc;
 use hashio::hashiofile::HashIOFile;
 use std::fs::remove_dir_all;
+use hashio::migration::{migration_report, migrate, update_refs};
 
 hashio_type! {
 	Task1 {
@@ -28,6 +29,7 @@ hashio_type! {
 		title: String,
 		category: String
 	}
+	fallback => Task1
 }
 impl From<Rc<Task1>> for Task {
 	fn from(old: Rc<Task1>) -> Task {
@@ -39,6 +41,27 @@ impl From<Rc<Task1>> for Task {
 	}
 }
 
+hashio_type! {
+	Task3 {
+		factor: f32, read_f32, write_f32,
+		done: u8, read_u8, write_u8
+	} {
+		title: String,
+		category: String
+	}
+	fallback => Task
+}
+impl From<Rc<Task>> for Task3 {
+	fn from(old: Rc<Task>) -> Task3 {
+		Task3 {
+			factor: old.factor,
+			done: 0,
+			title: old.title.clone(),
+			category: old.category.clone()
+		}
+	}
+}
+
 hashio_type! {
 	TaskStrage1 {
 	} {
@@ -64,6 +87,15 @@ impl From<Rc<TaskStrage1>> for TaskStrage {
 	}
 }
 
+hashio_type! {
+	TaskStrage2 {
+	} {
+		tasks: Vec<Rc<Task>>
+	}
+	type_hash => v2
+	legacy_fallback => v1
+}
+
 #[test]
 fn test() {
 	remove_dir_all("unittest/overalltest").ok();
@@ -81,3 +113,128 @@ fn test() {
 	assert_eq!(Rc::new("".to_string()), storage.tasks[1].category.clone());
 }
 
+
+#[test]
+fn test_migration_chain() {
+	remove_dir_all("unittest/migrationtest").ok();
+	let hash_io = HashIOFile::new("unittest/migrationtest".to_string());
+	let task1 = Task1 {title: Rc::new("Test1".to_string()), factor: 0.5};
+	let task = Task {title: Rc::new("Test2".to_string()), factor: 0.2,
+	                 category: Rc::new("Cat".to_string())};
+	let task3 = Task3 {title: Rc::new("Test3".to_string()), factor: 0.1, done: 1,
+	                   category: Rc::new("Cat".to_string())};
+	let hash1 = task1.as_hash();
+	let hash2 = task.as_hash();
+	let hash3 = task3.as_hash();
+	hash_io.put(Rc::new(task1)).unwrap();
+	hash_io.put(Rc::new(task)).unwrap();
+	hash_io.put(Rc::new(task3)).unwrap();
+
+	// Task1 is migrated to Task and then to Task3
+	let migrated: Rc<Task3> = hash_io.get(&hash1).unwrap();
+	assert_eq!(Rc::new("Test1".to_string()), migrated.title);
+	assert_eq!(Rc::new("".to_string()), migrated.category);
+	assert_eq!(0, migrated.done);
+	let migrated: Rc<Task3> = hash_io.get(&hash2).unwrap();
+	assert_eq!(Rc::new("Cat".to_string()), migrated.category);
+
+	assert_eq!(vec![("Task".to_string(), Task::type_hash()),
+	                ("Task1".to_string(), Task1::type_hash())],
+	           Task3::fallback_types());
+
+	let report = migration_report::<Task3>(&hash_io).unwrap();
+	assert_eq!(vec![hash3], report.current);
+	assert_eq!(2, report.outdated.len());
+	assert!(!report.is_up_to_date());
+	let task1_entry = report.outdated.iter().find(|x| x.hash == hash1).unwrap();
+	assert_eq!("Task1", task1_entry.type_name);
+	let task_entry = report.outdated.iter().find(|x| x.hash == hash2).unwrap();
+	assert_eq!("Task", task_entry.type_name);
+	assert!(report.unreadable.is_empty());
+
+	// A truncated object is listed instead of being skipped
+	std::fs::File::create(hash_io.filename_for_hash(&hash3)).unwrap();
+	let report = migration_report::<Task3>(&hash_io).unwrap();
+	assert!(report.current.is_empty());
+	assert_eq!(vec![hash3], report.unreadable);
+}
+
+#[test]
+fn test_migrate_store() {
+	remove_dir_all("unittest/migratestoretest").ok();
+	let hash_io = HashIOFile::new("unittest/migratestoretest".to_string());
+	let task1 = Task1 {title: Rc::new("Test1".to_string()), factor: 0.5};
+	let storage = TaskStrage1 { tasks: Rc::new(vec![Rc::new(task1)]) };
+	let old_root = storage.as_hash();
+	hash_io.put(Rc::new(storage)).unwrap();
+
+	let mapping = migrate::<TaskStrage>(&hash_io, &[old_root]).unwrap();
+	let new_root = *mapping.get(&old_root).unwrap();
+	assert!(new_root != old_root);
+
+	let report = migration_report::<TaskStrage>(&hash_io).unwrap();
+	assert_eq!(vec![new_root], report.current);
+	let storage: Rc<TaskStrage> = hash_io.get(&new_root).unwrap();
+	assert_eq!(Rc::new("Test1".to_string()), storage.tasks[0].title.clone());
+	let task_report = migration_report::<Task>(&hash_io).unwrap();
+	assert_eq!(1, task_report.current.len());
+
+	let mut refs: BTreeMap<String, Hash> = BTreeMap::new();
+	refs.insert("main".to_string(), old_root);
+	refs.insert("other".to_string(), storage.tasks[0].as_hash());
+	assert_eq!(1, update_refs(&mut refs, &mapping));
+	assert_eq!(&new_root, refs.get("main").unwrap());
+}
+
+#[test]
+fn test_migrate_same_payload() {
+	remove_dir_all("unittest/migratesamepayloadtest").ok();
+	let hash_io = HashIOFile::new("unittest/migratesamepayloadtest".to_string());
+	let task = Task {
+		title: Rc::new("Test1".to_string()),
+		category: Rc::new("Cat".to_string()),
+		factor: 0.5
+	};
+	let storage = TaskStrage { tasks: Rc::new(vec![Rc::new(task)]) };
+	let root = storage.as_hash();
+	hash_io.put(Rc::new(storage)).unwrap();
+	assert_eq!(1, migration_report::<TaskStrage2>(&hash_io).unwrap().outdated.len());
+
+	// TaskStrage has the v1 type hash of TaskStrage2.  The payload doesn't
+	// change, so only the header is rewritten.
+	let mapping = migrate::<TaskStrage2>(&hash_io, &[root]).unwrap();
+	assert_eq!(&root, mapping.get(&root).unwrap());
+	let report = migration_report::<TaskStrage2>(&hash_io).unwrap();
+	assert!(report.is_up_to_date());
+	assert_eq!(vec![root], report.current);
+	assert_eq!((1, TaskStrage2::type_hash()), hash_io.read_header(&root).unwrap());
+}
+
+#[test]
+fn test_error_context() {
+	remove_dir_all("unittest/errorcontexttest").ok();
+	let hash_io = HashIOFile::new("unittest/errorcontexttest".to_string());
+	let tasks: Vec<Rc<Task>> = (0..5).map(|i| Rc::new(Task {
+		title: Rc::new(format!("Task {}", i)),
+		factor: 1.0,
+		category: Rc::new("Cat".to_string())
+	})).collect();
+	let storage = TaskStrage { tasks: Rc::new(tasks) };
+	let hash = storage.as_hash();
+	let title_hash = storage.tasks[3].title.as_hash();
+	hash_io.put(Rc::new(storage)).unwrap();
+	std::fs::remove_file(hash_io.filename_for_hash(&title_hash)).unwrap();
+
+	match hash_io.get::<TaskStrage>(&hash) {
+		Err(HashIOError::Object(object_error)) => {
+			assert_eq!("TaskStrage.tasks[3].title", object_error.path());
+			assert_eq!("String", object_error.type_name);
+			assert_eq!(title_hash, object_error.hash);
+			match object_error.cause {
+				HashIOError::NotFound(missing) => assert_eq!(title_hash, missing),
+				ref err => panic!("Unexpected cause {}", err)
+			}
+		},
+		_ => panic!("Expected an error with context")
+	}
+}